@@ -0,0 +1,515 @@
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::Write,
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use bytes::{Buf, BufMut, Bytes};
+use parking_lot::Mutex;
+
+use crate::{
+    fs_util::{sync_dir, sync_file, sync_parent_dir},
+    iterators::StorageIterator,
+    lsm_storage::{LsmStorageInner, LsmStorageState, OpenMode, SsTableIterator},
+    rate_limiter::IoPriority,
+    sstable::read_exact_at,
+    value::{decode_value, now_ms, relocate_blob_pointer, ValueRef},
+    varint::{get_varint, put_varint},
+};
+
+/// 指向 blob 文件中一个大值的指针，代替值本身保存在 memtable、WAL 和 SST 里。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobPointer {
+    pub file_id: usize,
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl BlobPointer {
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        put_varint(buf, self.file_id as u64);
+        put_varint(buf, self.offset);
+        put_varint(buf, self.len);
+    }
+
+    pub(crate) fn decode(buf: &mut &[u8]) -> Self {
+        Self {
+            file_id: get_varint(buf) as usize,
+            offset: get_varint(buf),
+            len: get_varint(buf),
+        }
+    }
+}
+
+/// 正在追加写入的 blob 文件
+struct ActiveBlobFile {
+    id: usize,
+    file: File,
+    size: u64,
+}
+
+/// blob 文件里的一条记录，GC 时用键回查 LSM 判断它是否还有效
+pub(crate) struct BlobRecord {
+    pub(crate) cf_id: usize,
+    pub(crate) key: Bytes,
+    pub(crate) ptr: BlobPointer,
+    /// 整条记录占用的字节数
+    pub(crate) size: u64,
+}
+
+/// 超过 `large_value_threshold` 的值写在单独的 blob 文件（value log）里，只追加不修改。
+/// 每条记录：| cf_id | key_len | key | value_len | value | checksum (u32) |，长度都是 varint，
+/// 校验和只覆盖 value，指针指向 value 的起始位置。文件超过 `file_size_limit` 后换一个新文件。
+pub(crate) struct BlobStore {
+    path: PathBuf,
+    file_size_limit: u64,
+    active: Mutex<Option<ActiveBlobFile>>,
+    /// 读取用的文件句柄，按文件id缓存，用定位读取，多个读者可以同时读一个文件
+    readers: Mutex<HashMap<usize, Arc<File>>>,
+    /// 读者在拿快照之前复制一份，GC 换掉一个文件里的指针之后换成新的
+    read_pin: Mutex<Arc<()>>,
+    /// 有效的值已经重写到别的文件、等待删除的文件，和换指针之前的 `read_pin`，从旧到新
+    retired: Mutex<VecDeque<(usize, Arc<()>)>>,
+    /// 没有 WAL 时，新指针只在memtable里，等这个memtable刷新之后文件才能退役：(memtable id, 文件id)
+    unflushed: Mutex<Vec<(usize, usize)>>,
+}
+
+impl BlobStore {
+    pub(crate) fn new(path: impl Into<PathBuf>, file_size_limit: usize) -> Self {
+        Self {
+            path: path.into(),
+            file_size_limit: file_size_limit as u64,
+            active: Mutex::new(None),
+            readers: Mutex::new(HashMap::new()),
+            read_pin: Mutex::new(Arc::new(())),
+            retired: Mutex::new(VecDeque::new()),
+            unflushed: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// 目录里已有的 blob 文件的最大id，没有时返回0
+    pub(crate) fn max_file_id(path: &Path) -> Result<usize> {
+        let mut max_id = 0;
+        for entry in std::fs::read_dir(path)? {
+            let name = entry?.file_name();
+            if let Some(id) = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".blob"))
+                .and_then(|id| id.parse::<usize>().ok())
+            {
+                max_id = max_id.max(id);
+            }
+        }
+        Ok(max_id)
+    }
+
+    /// 恢复出的 memtable 和 SST 里的指针引用的 blob 文件，其他的 blob 文件是 GC 删除前崩溃
+    /// 或者指针没有持久化留下的。目录里没有 blob 文件时不用扫描
+    pub(crate) fn live_file_ids<'a>(
+        path: &Path,
+        states: impl IntoIterator<Item = &'a LsmStorageState>,
+    ) -> Result<BTreeSet<usize>> {
+        let mut ids = BTreeSet::new();
+        if Self::max_file_id(path)? == 0 {
+            return Ok(ids);
+        }
+        let mut add = |raw: &[u8]| -> Result<()> {
+            // 已经过期的指针也算，这里只关心文件是否还被引用
+            if let ValueRef::Blob(ptr) = decode_value(raw, 0)? {
+                ids.insert(ptr.file_id);
+            }
+            Ok(())
+        };
+        for state in states {
+            for memtable in std::iter::once(&state.memtable).chain(state.imm_memtables.iter()) {
+                let mut iter = memtable.scan(Bound::Unbounded, Bound::Unbounded);
+                while iter.is_valid() {
+                    add(iter.value())?;
+                    iter.next()?;
+                }
+            }
+            for table in state.sstables.values() {
+                let mut iter = SsTableIterator::create_and_seek_to_first(table.clone())?;
+                while iter.is_valid() {
+                    add(iter.value())?;
+                    iter.next()?;
+                }
+            }
+        }
+        Ok(ids)
+    }
+
+    /// 追加一个值，需要新文件时用 `new_file_id` 分配文件id。
+    pub(crate) fn append(
+        &self,
+        cf_id: usize,
+        key: &[u8],
+        value: &[u8],
+        new_file_id: impl FnOnce() -> usize,
+    ) -> Result<BlobPointer> {
+        let mut active = self.active.lock();
+        if active
+            .as_ref()
+            .is_none_or(|file| file.size >= self.file_size_limit)
+        {
+            if let Some(old) = active.take() {
+                sync_file(&old.file, &LsmStorageInner::path_of_blob_static(&self.path, old.id))?;
+            }
+            let id = new_file_id();
+            let path = LsmStorageInner::path_of_blob_static(&self.path, id);
+            let file = OpenOptions::new()
+                .create_new(true)
+                .append(true)
+                .open(&path)
+                .context("failed to create blob file")?;
+            sync_parent_dir(&path)?;
+            *active = Some(ActiveBlobFile { id, file, size: 0 });
+        }
+        let active = active.as_mut().unwrap();
+        let mut buf = Vec::with_capacity(key.len() + value.len() + 30);
+        put_varint(&mut buf, cf_id as u64);
+        put_varint(&mut buf, key.len() as u64);
+        buf.put_slice(key);
+        put_varint(&mut buf, value.len() as u64);
+        let value_offset = buf.len() as u64;
+        buf.put_slice(value);
+        buf.put_u32(crc32fast::hash(value));
+        active.file.write_all(&buf)?;
+        let ptr = BlobPointer {
+            file_id: active.id,
+            offset: active.size + value_offset,
+            len: value.len() as u64,
+        };
+        active.size += buf.len() as u64;
+        Ok(ptr)
+    }
+
+    /// 读取指针指向的值并校验
+    pub(crate) fn read(&self, ptr: BlobPointer) -> Result<Bytes> {
+        let cached = self.readers.lock().get(&ptr.file_id).cloned();
+        let file = match cached {
+            Some(file) => file,
+            None => {
+                let path = LsmStorageInner::path_of_blob_static(&self.path, ptr.file_id);
+                let file = File::open(path)
+                    .with_context(|| format!("failed to open blob file: {}", ptr.file_id))?;
+                self.readers
+                    .lock()
+                    .entry(ptr.file_id)
+                    .or_insert(Arc::new(file))
+                    .clone()
+            }
+        };
+        let mut buf = vec![0; ptr.len as usize + std::mem::size_of::<u32>()];
+        read_exact_at(&file, &mut buf, ptr.offset)?;
+        let checksum = (&buf[ptr.len as usize..]).get_u32();
+        buf.truncate(ptr.len as usize);
+        if checksum != crc32fast::hash(&buf) {
+            bail!("blob checksum mismatched");
+        }
+        Ok(buf.into())
+    }
+
+    /// 除了正在写入的文件和等待删除的文件以外的 blob 文件，从旧到新排序
+    pub(crate) fn sealed_file_ids(&self) -> Result<Vec<usize>> {
+        let mut ids = self.file_ids()?.0;
+        let retired = self.retired.lock();
+        let unflushed = self.unflushed.lock();
+        ids.retain(|id| {
+            !retired.iter().any(|(retired_id, _)| retired_id == id)
+                && !unflushed.iter().any(|(_, unflushed_id)| unflushed_id == id)
+        });
+        Ok(ids)
+    }
+
+    /// 已经写完的 blob 文件（从旧到新排序）和正在写入的文件。在同一把锁下读取，
+    /// 期间不会换新文件，正在写入的文件不会同时出现在已写完的文件里
+    pub(crate) fn file_ids(&self) -> Result<(Vec<usize>, Option<usize>)> {
+        let active = self.active.lock();
+        let active_id = active.as_ref().map(|file| file.id);
+        let mut ids = Vec::new();
+        for entry in std::fs::read_dir(&self.path)? {
+            let name = entry?.file_name();
+            if let Some(id) = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".blob"))
+                .and_then(|id| id.parse::<usize>().ok())
+            {
+                if Some(id) != active_id {
+                    ids.push(id);
+                }
+            }
+        }
+        ids.sort_unstable();
+        Ok((ids, active_id))
+    }
+
+    /// 读出一个 blob 文件里的所有记录。写到一半的尾部记录被忽略。
+    pub(crate) fn scan_file(&self, file_id: usize) -> Result<Vec<BlobRecord>> {
+        let data = std::fs::read(LsmStorageInner::path_of_blob_static(&self.path, file_id))
+            .with_context(|| format!("failed to read blob file: {}", file_id))?;
+        let mut records = Vec::new();
+        let mut rbuf = &data[..];
+        while !rbuf.is_empty() {
+            let start = data.len() - rbuf.len();
+            let Some(record) = Self::parse_record(&mut rbuf, &data, file_id) else {
+                break;
+            };
+            records.push(BlobRecord {
+                size: (data.len() - rbuf.len() - start) as u64,
+                ..record
+            });
+        }
+        Ok(records)
+    }
+
+    fn parse_record(rbuf: &mut &[u8], data: &[u8], file_id: usize) -> Option<BlobRecord> {
+        // 检查尾部是否完整，varint 本身被截断时 get_varint 会 panic
+        fn get_len(rbuf: &mut &[u8]) -> Option<u64> {
+            let len = rbuf.iter().position(|b| b & 0x80 == 0)? + 1;
+            let mut varint = &rbuf[..len];
+            *rbuf = &rbuf[len..];
+            Some(get_varint(&mut varint))
+        }
+        let cf_id = get_len(rbuf)? as usize;
+        let key_len = get_len(rbuf)? as usize;
+        if rbuf.len() < key_len {
+            return None;
+        }
+        let key = Bytes::copy_from_slice(&rbuf[..key_len]);
+        rbuf.advance(key_len);
+        let len = get_len(rbuf)?;
+        if (rbuf.len() as u64) < len + std::mem::size_of::<u32>() as u64 {
+            return None;
+        }
+        let offset = (data.len() - rbuf.len()) as u64;
+        rbuf.advance(len as usize + std::mem::size_of::<u32>());
+        Some(BlobRecord {
+            cf_id,
+            key,
+            ptr: BlobPointer {
+                file_id,
+                offset,
+                len,
+            },
+            size: 0,
+        })
+    }
+
+    /// 读者在拿快照之前调用，返回值存在期间，快照里的指针可能指向的文件都不会被删除
+    pub(crate) fn pin_files(&self) -> Arc<()> {
+        self.read_pin.lock().clone()
+    }
+
+    /// 文件里有效的值都已经换到新文件，之前开始的读者都结束后才删除它
+    pub(crate) fn retire_file(&self, file_id: usize) {
+        let pin = std::mem::replace(&mut *self.read_pin.lock(), Arc::new(()));
+        self.retired.lock().push_back((file_id, pin));
+    }
+
+    /// 新指针写在 `memtable_id` 的memtable里、还没有持久化，这个memtable刷新后再退役文件
+    pub(crate) fn retire_file_after_flush(&self, memtable_id: usize, file_id: usize) {
+        self.unflushed.lock().push((memtable_id, file_id));
+    }
+
+    /// `memtable_id` 和更旧的memtable已经刷新到 SST，退役新指针在这些memtable里的文件
+    pub(crate) fn memtable_flushed(&self, memtable_id: usize) {
+        let mut unflushed = self.unflushed.lock();
+        for (_, file_id) in unflushed.extract_if(.., |(id, _)| *id <= memtable_id) {
+            self.retire_file(file_id);
+        }
+    }
+
+    /// 按换指针的顺序删除已经没有读者的文件，遇到还有读者的文件就停下，
+    /// 更早开始的读者可能还读到后面的文件。返回删除的文件数
+    pub(crate) fn delete_retired_files(&self) -> Result<usize> {
+        let mut removed = 0;
+        loop {
+            let file_id = {
+                let mut retired = self.retired.lock();
+                match retired.front() {
+                    Some((_, pin)) if Arc::strong_count(pin) == 1 => retired.pop_front().unwrap().0,
+                    _ => break,
+                }
+            };
+            self.readers.lock().remove(&file_id);
+            std::fs::remove_file(LsmStorageInner::path_of_blob_static(&self.path, file_id))
+                .with_context(|| format!("failed to remove blob file: {}", file_id))?;
+            removed += 1;
+        }
+        if removed > 0 {
+            sync_dir(&self.path)?;
+        }
+        Ok(removed)
+    }
+
+    /// 把正在写入的 blob 文件刷到磁盘，要在引用它的 WAL 同步之前调用
+    pub(crate) fn sync(&self) -> Result<()> {
+        if let Some(active) = self.active.lock().as_ref() {
+            sync_file(
+                &active.file,
+                &LsmStorageInner::path_of_blob_static(&self.path, active.id),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl LsmStorageInner {
+    /// 回收 blob 文件：从最旧的文件开始，用记录里的键回查 LSM，指针仍指向这条记录的值还有效。
+    /// 垃圾比例不低于 `blob_gc_garbage_ratio` 的文件，把有效的值按低优先级限速重写到新的 blob 文件，
+    /// 再短暂阻塞写入，把仍然有效的指针换成新指针后删除旧文件。返回删除的文件数。
+    pub fn gc_blob_files(&self) -> Result<usize> {
+        self.check_writable()?;
+        let mut removed = 0;
+        for file_id in self.blob_store.sealed_file_ids()? {
+            let records = self.blob_store.scan_file(file_id)?;
+            let total_size = records.iter().map(|record| record.size).sum::<u64>();
+            let mut live = Vec::new();
+            let mut live_size = 0;
+            let mut pinned = false;
+            for record in records {
+                match self.blob_liveness(&record)? {
+                    BlobLiveness::Dead => {}
+                    BlobLiveness::Live(raw) => {
+                        live_size += record.size;
+                        live.push((record, raw));
+                    }
+                    BlobLiveness::Pinned => pinned = true,
+                }
+            }
+            let garbage_ratio = if total_size == 0 {
+                1.0
+            } else {
+                1.0 - live_size as f64 / total_size as f64
+            };
+            if pinned || garbage_ratio < self.options.blob_gc_garbage_ratio {
+                continue;
+            }
+            // 复制值时不阻塞写入
+            let mut copied = Vec::with_capacity(live.len());
+            for (record, _) in live {
+                let value = self.blob_store.read(record.ptr)?;
+                self.rate_limiter
+                    .request(record.size as usize, IoPriority::Low);
+                let ptr = self
+                    .blob_store
+                    .append(record.cf_id, &record.key, &value, || self.next_sst_id())?;
+                copied.push((record, ptr));
+            }
+            // 换指针期间不能有新的写入，否则重写的旧值会覆盖用户刚写入的新值。
+            // 复制期间被覆盖的值不再重写，复制出的那份成为新文件里的垃圾
+            let guard = self.blob_gc_lock.write();
+            self.check_writable()?;
+            let mut relocated = Vec::with_capacity(copied.len());
+            for (record, ptr) in &copied {
+                match self.blob_liveness(record)? {
+                    BlobLiveness::Dead => {}
+                    BlobLiveness::Live(raw) => relocated.push((
+                        record.cf_id,
+                        &record.key[..],
+                        relocate_blob_pointer(&raw, *ptr),
+                    )),
+                    BlobLiveness::Pinned => pinned = true,
+                }
+            }
+            if pinned {
+                continue;
+            }
+            let size = self.write_records(&relocated)?;
+            drop(guard);
+            // 新指针落盘之后才能删除旧文件
+            self.blob_store.sync()?;
+            if self.options.enable_wal {
+                self.state.read().memtable.sync_wal()?;
+                self.blob_store.retire_file(file_id);
+            } else {
+                // 新指针只在memtable里，崩溃就丢了，等它刷新到 SST。
+                // 写完才读id，期间冻结过也只会多等一个memtable
+                let memtable_id = self.state.read().memtable.id();
+                self.blob_store
+                    .retire_file_after_flush(memtable_id, file_id);
+            }
+            self.try_freeze(size)?;
+        }
+        // 创建检查点时不删除文件，还有读者的文件也留到下次
+        if let Some(_guard) = self.file_deletion_lock.try_read() {
+            removed += self.blob_store.delete_retired_files()?;
+        }
+        Ok(removed)
+    }
+
+    // 键的最新版本是否还指向这条记录。从新到旧查找memtable和SST，跳过上面的合并操作数
+    fn blob_liveness(&self, record: &BlobRecord) -> Result<BlobLiveness> {
+        let Some(cf) = self.column_families.read().get(&record.cf_id).cloned() else {
+            return Ok(BlobLiveness::Dead);
+        };
+        let snapshot = cf.state.read().clone();
+        let now = now_ms();
+        let mut has_operands = false;
+        let memtable_versions = std::iter::once(&snapshot.memtable)
+            .chain(snapshot.imm_memtables.iter())
+            .map(|memtable| Ok(memtable.get(&record.key)));
+        let sst_versions = snapshot
+            .l0_sstables
+            .iter()
+            .chain(snapshot.levels.iter().flat_map(|(_, files)| files))
+            .map(|id| snapshot.sstables[id].get(&record.key));
+        for raw in memtable_versions.chain(sst_versions) {
+            let Some(raw) = raw? else {
+                continue;
+            };
+            match decode_value(&raw, now)? {
+                ValueRef::Merge(_) => has_operands = true,
+                // 上面还有合并操作数时，重写指针会遮住它们，暂时不能回收
+                ValueRef::Blob(ptr) if ptr == record.ptr => {
+                    return Ok(if has_operands {
+                        BlobLiveness::Pinned
+                    } else {
+                        BlobLiveness::Live(raw)
+                    });
+                }
+                _ => return Ok(BlobLiveness::Dead),
+            }
+        }
+        // 墓碑压缩到最底层后被丢掉，键就找不到了。调用者持有 `blob_gc_lock`，期间不会有新的引用
+        Ok(BlobLiveness::Dead)
+    }
+
+    /// 启动后台 blob GC 线程，没有开启键值分离时不启动
+    pub(crate) fn spawn_blob_gc_thread(
+        self: &Arc<Self>,
+        rx: crossbeam_channel::Receiver<()>,
+    ) -> Result<Option<std::thread::JoinHandle<()>>> {
+        if self.options.large_value_threshold.is_none() || self.mode != OpenMode::ReadWrite {
+            return Ok(None);
+        }
+        let this = self.clone();
+        let handle = std::thread::spawn(move || {
+            let ticker = crossbeam_channel::tick(Duration::from_secs(1));
+            loop {
+                crossbeam_channel::select! {
+                    recv(ticker) -> _ => if let Err(e) = this.gc_blob_files() {
+                        eprintln!("blob gc failed: {}", e);
+                    },
+                    recv(rx) -> _ => return
+                }
+            }
+        });
+        Ok(Some(handle))
+    }
+}
+
+enum BlobLiveness {
+    /// 被覆盖、删除或过期
+    Dead,
+    /// 仍然有效，带着引用它的原始值
+    Live(Bytes),
+    /// 仍然有效但暂时不能重写
+    Pinned,
+}
@@ -4,25 +4,111 @@ use std::sync::Arc;
 
 use bytes::{Buf, BufMut, Bytes};
 
-use crate::key::{KeySlice, KeyVec};
+use crate::{
+    key::{KeySlice, KeyVec},
+    varint::{get_varint, put_varint, varint_len},
+};
 
 
-pub(crate) const SIZEOF_U16: usize = std::mem::size_of::<u16>();
+pub(crate) const SIZEOF_U32: usize = std::mem::size_of::<u32>();
+
+/// 默认每隔多少个条目放一个重启点
+pub(crate) const DEFAULT_RESTART_INTERVAL: usize = 16;
 
 ///块是LSM树中最小的读取和缓存单元。它是一个排序的集合
 ///键值对。
-/// //数据和偏移量，向量数组
+/// 每个条目：| shared (varint) | unshared_len (varint) | unshared key | value_len (varint) | value |
+/// 键和前一个键共享前 shared 个字节；重启点上的条目 shared 为 0，保存完整的键。
 pub struct Block {
-    pub(crate) data: Vec<u8>,
-    pub(crate) offsets: Vec<u16>,
+    /// 使用 mmap 读取时直接引用映射的文件，不拷贝
+    pub(crate) data: Bytes,
+    /// 重启点条目在 data 中的偏移量
+    pub(crate) restarts: Vec<u32>,
 }
 impl Block {
-    fn get_first_key(&self) -> KeyVec {
-        let mut buf = &self.data[..];
-        buf.get_u16();
-        let key_len = buf.get_u16();
-        let key = &buf[..key_len as usize];
-        KeyVec::from_vec(key.to_vec())
+    /// 第 idx 个重启点的完整键，直接引用块数据，不分配内存
+    fn restart_key(&self, idx: usize) -> &[u8] {
+        let mut entry = &self.data[self.restarts[idx] as usize..];
+        let shared = get_varint(&mut entry);
+        debug_assert_eq!(shared, 0, "restart entry must hold the full key");
+        let key_len = get_varint(&mut entry) as usize;
+        &entry[..key_len]
+    }
+}
+
+/// 构建一个块，每隔 `restart_interval` 个条目写一个完整的键，其余条目只写和前一个键不同的部分。
+pub struct BlockBuilder {
+    data: Vec<u8>,
+    restarts: Vec<u32>,
+    /// 块的期望大小
+    block_size: usize,
+    restart_interval: usize,
+    /// 已经添加的条目数
+    counter: usize,
+    /// 上一个键，用于前缀压缩
+    last_key: KeyVec,
+}
+
+impl BlockBuilder {
+    pub fn new(block_size: usize, restart_interval: usize) -> Self {
+        Self {
+            data: Vec::new(),
+            restarts: Vec::new(),
+            block_size,
+            restart_interval: restart_interval.max(1),
+            counter: 0,
+            last_key: KeyVec::new(),
+        }
+    }
+
+    fn estimated_size(&self) -> usize {
+        SIZEOF_U32 + self.restarts.len() * SIZEOF_U32 + self.data.len()
+    }
+
+    /// 添加一个键值对。块满了返回 false，但第一个键值对总能添加。
+    #[must_use]
+    pub fn add(&mut self, key: KeySlice, value: &[u8]) -> bool {
+        assert!(!key.is_empty(), "key must not be empty");
+        let entry_size = varint_len(key.len() as u64) * 2
+            + key.len()
+            + varint_len(value.len() as u64)
+            + value.len()
+            + SIZEOF_U32;
+        if !self.is_empty() && self.estimated_size() + entry_size > self.block_size {
+            return false;
+        }
+        let shared = if self.counter.is_multiple_of(self.restart_interval) {
+            self.restarts.push(self.data.len() as u32);
+            0
+        } else {
+            self.last_key
+                .raw_ref()
+                .iter()
+                .zip(key.raw_ref())
+                .take_while(|(a, b)| a == b)
+                .count()
+        };
+        let unshared = &key.raw_ref()[shared..];
+        put_varint(&mut self.data, shared as u64);
+        put_varint(&mut self.data, unshared.len() as u64);
+        self.data.put_slice(unshared);
+        put_varint(&mut self.data, value.len() as u64);
+        self.data.put_slice(value);
+        self.last_key.set_from_slice(key);
+        self.counter += 1;
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.restarts.is_empty()
+    }
+
+    pub fn build(self) -> Block {
+        assert!(!self.is_empty(), "block should not be empty");
+        Block {
+            data: self.data.into(),
+            restarts: self.restarts,
+        }
     }
 }
 
@@ -40,30 +126,35 @@ impl Block {
         //在Rust中，Vec<u8> 是一个动态数组，其中每个元素都是一个无符号8位整数（即字节，u8）。clone 是一个方法，用于创建该数据结构的深拷贝。
         //当你对一个 Vec<u8> 调用 clone 方法时，你会得到一个与原 Vec<u8> 内容完全相同的新的 Vec<u8> 实例，
         // 但是这两个实例在内存中是分开的，互不干扰。
-        let mut buf = self.data.clone();
-        let offsets_len = self.offsets.len();
-        for offset in &self.offsets {
-            buf.put_u16(*offset);
+        let mut buf = self.data.to_vec();
+        let restarts_len = self.restarts.len();
+        for offset in &self.restarts {
+            buf.put_u32(*offset);
         }
-        // 在块的末尾添加元素的个数
-        buf.put_u16(offsets_len as u16);
+        // 在块的末尾添加重启点的个数
+        buf.put_u32(restarts_len as u32);
         buf.into()
     }
 
     //解码 把
     pub fn decode(data: &[u8]) -> Self {
-        // 获取块中元素的个数
-        let entry_offsets_len = (&data[data.len() - SIZEOF_U16..]).get_u16() as usize;
-        let data_end = data.len() - SIZEOF_U16 - entry_offsets_len * SIZEOF_U16;
-        let offsets_raw = &data[data_end..data.len() - SIZEOF_U16];
-        // 获取偏移量数组
-        let offsets = offsets_raw
-            .chunks(SIZEOF_U16)
-            .map(|mut x| x.get_u16())
+        Self::decode_bytes(Bytes::copy_from_slice(data))
+    }
+
+    /// 和 `decode` 相同，但数据部分直接引用 `data`，不拷贝
+    pub fn decode_bytes(data: Bytes) -> Self {
+        // 获取块中重启点的个数
+        let restarts_len = (&data[data.len() - SIZEOF_U32..]).get_u32() as usize;
+        let data_end = data.len() - SIZEOF_U32 - restarts_len * SIZEOF_U32;
+        let restarts_raw = &data[data_end..data.len() - SIZEOF_U32];
+        // 获取重启点数组
+        let restarts = restarts_raw
+            .chunks(SIZEOF_U32)
+            .map(|mut x| x.get_u32())
             .collect();
         //检索数据
-        let data = data[0..data_end].to_vec();
-        Self { data, offsets }
+        let data = data.slice(0..data_end);
+        Self { data, restarts }
     }
 }
 
@@ -75,21 +166,21 @@ pub struct BlockIterator {
     key: KeyVec,
     /// 块中的当前值范围。数据，对应当前键
     value_range: (usize, usize),
-    /// 迭代器所在位置的当前索引
-    idx: usize,
-    /// 块中的第一个键
-    first_key: KeyVec,
+    /// 当前条目在 data 中的偏移量
+    offset: usize,
+    /// 下一个条目在 data 中的偏移量
+    next_offset: usize,
 }
 
 //实现迭代器
 impl BlockIterator {
     fn new(block: Arc<Block>) -> Self {
         Self {
-            first_key: Block::get_first_key(&block),
             block,
             key: KeyVec::new(),
             value_range: (0, 0),
-            idx: 0,
+            offset: 0,
+            next_offset: 0,
         }
     }
 
@@ -107,8 +198,22 @@ impl BlockIterator {
         iter
     }
 
+    /// Creates a block iterator and seek to the last entry.
+    pub fn create_and_seek_to_last(block: Arc<Block>) -> Self {
+        let mut iter = Self::new(block);
+        iter.seek_to_last();
+        iter
+    }
+
+    /// Creates a block iterator and seek to the last key that <= `key`.
+    pub fn create_and_seek_for_prev(block: Arc<Block>, key: KeySlice) -> Self {
+        let mut iter = Self::new(block);
+        iter.seek_for_prev(key);
+        iter
+    }
+
     /// Returns the key of the current entry.
-    pub fn key(&self) -> KeySlice {
+    pub fn key(&self) -> KeySlice<'_> {
         debug_assert!(!self.key.is_empty(), "invalid iterator");
         self.key.as_key_slice()
     }
@@ -126,60 +231,181 @@ impl BlockIterator {
 
     /// Seeks to the first key in the block.
     pub fn seek_to_first(&mut self) {
-        self.seek_to(0);
+        self.seek_to_restart(0);
     }
 
-    /// Seeks to the idx-th key in the block.
-    fn seek_to(&mut self, idx: usize) {
-        if idx >= self.block.offsets.len() {
+    /// Seeks to the idx-th restart point in the block.
+    fn seek_to_restart(&mut self, idx: usize) {
+        if idx >= self.block.restarts.len() {
             self.key.clear();
             self.value_range = (0, 0);
             return;
         }
-        let offset = self.block.offsets[idx] as usize;
-        self.seek_to_offset(offset);
-        self.idx = idx;
+        self.key.clear();
+        self.next_offset = self.block.restarts[idx] as usize;
+        self.parse_next_entry();
     }
-    /// Seek to the specified position and update the current `key` and `value`
-    /// Index update will be handled by caller
-    fn seek_to_offset(&mut self, offset: usize) {
+
+    /// Decode the entry at `next_offset` against the current key and advance `next_offset`.
+    fn parse_next_entry(&mut self) {
+        let offset = self.next_offset;
+        if offset >= self.block.data.len() {
+            self.key.clear();
+            self.value_range = (0, 0);
+            return;
+        }
+        self.offset = offset;
         let mut entry = &self.block.data[offset..];
-        // Since `get_u16()` will automatically move the ptr 2 bytes ahead here,
-        // we don't need to manually advance it
-        let overlap_len = entry.get_u16() as usize;
-        let key_len = entry.get_u16() as usize;
-        let key = &entry[..key_len];
-        self.key.clear();
-        self.key.append(&self.first_key.raw_ref()[..overlap_len]);
-        self.key.append(key);
-        entry.advance(key_len);
-        let value_len = entry.get_u16() as usize;
-        let value_offset_begin = offset + SIZEOF_U16 + SIZEOF_U16 + key_len + SIZEOF_U16;
+        // `get_varint()` moves the ptr ahead by the encoded length,
+        // so the value offset is derived from what is left
+        let shared = get_varint(&mut entry) as usize;
+        let unshared_len = get_varint(&mut entry) as usize;
+        self.key.truncate(shared);
+        self.key.append(&entry[..unshared_len]);
+        entry.advance(unshared_len);
+        let value_len = get_varint(&mut entry) as usize;
+        let value_offset_begin = self.block.data.len() - entry.len();
         let value_offset_end = value_offset_begin + value_len;
         self.value_range = (value_offset_begin, value_offset_end);
-        entry.advance(value_len);
+        self.next_offset = value_offset_end;
     }
 
     /// Move to the next key in the block.
     pub fn next(&mut self) {
-        self.idx += 1;
-        self.seek_to(self.idx);
+        self.parse_next_entry();
+    }
+
+    /// Move to the previous key in the block.
+    /// Entries can only be decoded forward, so scan from the last restart point before the
+    /// current entry.
+    pub fn prev(&mut self) {
+        let target = self.offset;
+        if !self.is_valid() || target == 0 {
+            self.key.clear();
+            self.value_range = (0, 0);
+            return;
+        }
+        let idx = self
+            .block
+            .restarts
+            .partition_point(|&restart| (restart as usize) < target);
+        self.seek_to_restart(idx - 1);
+        while self.next_offset < target {
+            self.parse_next_entry();
+        }
+    }
+
+    /// Seeks to the last key in the block.
+    pub fn seek_to_last(&mut self) {
+        self.seek_to_restart(self.block.restarts.len().saturating_sub(1));
+        while self.is_valid() && self.next_offset < self.block.data.len() {
+            self.parse_next_entry();
+        }
+    }
+
+    /// Seek to the last key that is <= `key`.
+    pub fn seek_for_prev(&mut self, key: KeySlice) {
+        self.seek_to_key(key);
+        if !self.is_valid() {
+            self.seek_to_last();
+        } else if self.key() > key {
+            self.prev();
+        }
     }
 
     /// Seek to the first key that is >= `key`.
+    /// Binary search over the restart points, then scan linearly from the last restart point
+    /// whose key is <= `key`.
     pub fn seek_to_key(&mut self, key: KeySlice) {
         let mut low = 0;
-        let mut high = self.block.offsets.len();
+        let mut high = self.block.restarts.len();
         while low < high {
             let mid = low + (high - low) / 2;
-            self.seek_to(mid);
-            assert!(self.is_valid());
-            match self.key().cmp(&key) {
-                std::cmp::Ordering::Less => low = mid + 1,
-                std::cmp::Ordering::Greater => high = mid,
-                std::cmp::Ordering::Equal => return,
+            if self.block.restart_key(mid) <= key.raw_ref() {
+                low = mid + 1;
+            } else {
+                high = mid;
             }
         }
-        self.seek_to(low);
+        self.seek_to_restart(low.saturating_sub(1));
+        while self.is_valid() && self.key() < key {
+            self.next();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{Block, BlockBuilder, BlockIterator};
+    use crate::key::KeySlice;
+
+    #[test]
+    fn test_block_restart_points() {
+        let mut builder = BlockBuilder::new(65536, 4);
+        for i in 0..100 {
+            let key = format!("tenant/table/{:05}", i * 2);
+            let value = format!("value_{}", i);
+            assert!(builder.add(KeySlice::from_slice(key.as_bytes()), value.as_bytes()));
+        }
+        let block = Arc::new(Block::decode(&builder.build().encode()));
+        assert_eq!(block.restarts.len(), 25);
+
+        let mut iter = BlockIterator::create_and_seek_to_first(block.clone());
+        for i in 0..100 {
+            assert_eq!(iter.key().raw_ref(), format!("tenant/table/{:05}", i * 2).as_bytes());
+            assert_eq!(iter.value(), format!("value_{}", i).as_bytes());
+            iter.next();
+        }
+        assert!(!iter.is_valid());
+
+        for i in 0..200usize {
+            let key = format!("tenant/table/{:05}", i);
+            let iter = BlockIterator::create_and_seek_to_key(
+                block.clone(),
+                KeySlice::from_slice(key.as_bytes()),
+            );
+            if i >= 199 {
+                assert!(!iter.is_valid());
+                continue;
+            }
+            let expected = i.div_ceil(2) * 2;
+            assert_eq!(
+                iter.key().raw_ref(),
+                format!("tenant/table/{:05}", expected).as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn test_block_reverse_iteration() {
+        let mut builder = BlockBuilder::new(65536, 4);
+        for i in 0..10 {
+            let key = format!("key_{:03}", i * 2);
+            assert!(builder.add(KeySlice::from_slice(key.as_bytes()), key.as_bytes()));
+        }
+        let block = Arc::new(Block::decode(&builder.build().encode()));
+
+        let mut iter = BlockIterator::create_and_seek_to_last(block.clone());
+        for i in (0..10).rev() {
+            let key = format!("key_{:03}", i * 2);
+            assert_eq!(iter.key().raw_ref(), key.as_bytes());
+            assert_eq!(iter.value(), key.as_bytes());
+            iter.prev();
+        }
+        assert!(!iter.is_valid());
+
+        for i in 0..21usize {
+            let key = format!("key_{:03}", i);
+            let iter = BlockIterator::create_and_seek_for_prev(
+                block.clone(),
+                KeySlice::from_slice(key.as_bytes()),
+            );
+            let expected = format!("key_{:03}", (i / 2 * 2).min(18));
+            assert_eq!(iter.key().raw_ref(), expected.as_bytes());
+        }
+        let iter = BlockIterator::create_and_seek_for_prev(block, KeySlice::from_slice(b"a"));
+        assert!(!iter.is_valid());
     }
 }
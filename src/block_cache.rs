@@ -0,0 +1,273 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{block::Block, bloom::Bloom, sstable::BlockMeta};
+
+/// SST 里的哪一个块
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum BlockId {
+    Data(usize),
+    /// 索引块，分区索引时是分区序号，否则总是 0
+    Index(usize),
+    Filter,
+}
+
+/// 缓存里的块，数据块、索引块和过滤块共用一个容量
+#[derive(Clone)]
+enum CachedBlock {
+    Data(Arc<Block>),
+    Index(Arc<Vec<BlockMeta>>),
+    Filter(Arc<Bloom>),
+}
+
+impl CachedBlock {
+    fn weight(&self) -> usize {
+        match self {
+            CachedBlock::Data(block) => block.data.len(),
+            CachedBlock::Index(block_meta) => block_meta
+                .iter()
+                .map(|meta| {
+                    std::mem::size_of::<BlockMeta>() + meta.first_key.len() + meta.last_key.len()
+                })
+                .sum(),
+            CachedBlock::Filter(bloom) => bloom.filter.len(),
+        }
+    }
+}
+
+/// 按字节计算容量的块缓存，可以在多个引擎之间共享，共享时用各自的命名空间区分 SST id。
+/// 打开 `cache_index_and_filter_blocks` 时，SST 的索引块和过滤块也放在这里。
+pub struct BlockCache {
+    /// 键是 (命名空间, SST id, 块)
+    cache: moka::sync::Cache<(usize, usize, BlockId), CachedBlock>,
+    next_namespace: AtomicUsize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: Arc<AtomicU64>,
+}
+
+/// 块缓存的统计信息
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// 因为容量不足被淘汰的块数
+    pub evictions: u64,
+    /// 缓存中所有块的总字节数（近似值）
+    pub size_bytes: u64,
+    pub entry_count: u64,
+}
+
+impl BlockCache {
+    /// 创建一个最多缓存 `capacity_bytes` 字节块数据的缓存
+    pub fn new(capacity_bytes: u64) -> Self {
+        let evictions = Arc::new(AtomicU64::new(0));
+        let counter = evictions.clone();
+        let cache = moka::sync::Cache::builder()
+            .max_capacity(capacity_bytes)
+            .weigher(|_, block: &CachedBlock| block.weight().try_into().unwrap_or(u32::MAX))
+            .eviction_listener(move |_, _, cause| {
+                if cause.was_evicted() {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+            .build();
+        Self {
+            cache,
+            next_namespace: AtomicUsize::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions,
+        }
+    }
+
+    /// 给一个引擎分配命名空间，得到它读块时使用的句柄
+    pub fn handle(self: &Arc<Self>) -> BlockCacheHandle {
+        BlockCacheHandle {
+            cache: self.clone(),
+            namespace: self.next_namespace.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    pub fn stats(&self) -> BlockCacheStats {
+        BlockCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            size_bytes: self.cache.weighted_size(),
+            entry_count: self.cache.entry_count(),
+        }
+    }
+
+    fn try_get_with(
+        &self,
+        key: (usize, usize, BlockId),
+        init: impl FnOnce() -> Result<CachedBlock>,
+    ) -> Result<CachedBlock> {
+        let mut missed = false;
+        let block = self
+            .cache
+            .try_get_with(key, || {
+                missed = true;
+                init()
+            })
+            .map_err(|e| anyhow!("{}", e))?;
+        if missed {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(block)
+    }
+}
+
+impl fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockCache")
+            .field("capacity_bytes", &self.cache.policy().max_capacity())
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+/// 一个引擎使用共享块缓存的句柄
+#[derive(Clone)]
+pub struct BlockCacheHandle {
+    cache: Arc<BlockCache>,
+    namespace: usize,
+}
+
+impl BlockCacheHandle {
+    pub fn cache(&self) -> &Arc<BlockCache> {
+        &self.cache
+    }
+
+    /// 读取一个数据块，不在缓存里时用 `init` 从磁盘读取
+    pub(crate) fn get_data_block(
+        &self,
+        sst_id: usize,
+        block_idx: usize,
+        init: impl FnOnce() -> Result<Arc<Block>>,
+    ) -> Result<Arc<Block>> {
+        let key = (self.namespace, sst_id, BlockId::Data(block_idx));
+        match self
+            .cache
+            .try_get_with(key, || init().map(CachedBlock::Data))?
+        {
+            CachedBlock::Data(block) => Ok(block),
+            _ => unreachable!(),
+        }
+    }
+
+    /// 只在缓存里查找数据块，不从磁盘读取
+    pub(crate) fn get_cached_data_block(
+        &self,
+        sst_id: usize,
+        block_idx: usize,
+    ) -> Option<Arc<Block>> {
+        let block = self
+            .cache
+            .cache
+            .get(&(self.namespace, sst_id, BlockId::Data(block_idx)));
+        match block {
+            Some(CachedBlock::Data(block)) => {
+                self.cache.hits.fetch_add(1, Ordering::Relaxed);
+                Some(block)
+            }
+            _ => {
+                self.cache.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// 放入 `get_cached_data_block` 没有找到、从磁盘读出的数据块，未命中已经统计过
+    pub(crate) fn insert_data_block(&self, sst_id: usize, block_idx: usize, block: Arc<Block>) {
+        self.cache.cache.insert(
+            (self.namespace, sst_id, BlockId::Data(block_idx)),
+            CachedBlock::Data(block),
+        );
+    }
+
+    /// 读取一个索引块（或索引分区）
+    pub(crate) fn get_index_block(
+        &self,
+        sst_id: usize,
+        partition: usize,
+        init: impl FnOnce() -> Result<Vec<BlockMeta>>,
+    ) -> Result<Arc<Vec<BlockMeta>>> {
+        let key = (self.namespace, sst_id, BlockId::Index(partition));
+        match self.cache.try_get_with(key, || {
+            init().map(|meta| CachedBlock::Index(Arc::new(meta)))
+        })? {
+            CachedBlock::Index(block_meta) => Ok(block_meta),
+            _ => unreachable!(),
+        }
+    }
+
+    /// 读取过滤块
+    pub(crate) fn get_filter_block(
+        &self,
+        sst_id: usize,
+        init: impl FnOnce() -> Result<Bloom>,
+    ) -> Result<Arc<Bloom>> {
+        let key = (self.namespace, sst_id, BlockId::Filter);
+        match self.cache.try_get_with(key, || {
+            init().map(|bloom| CachedBlock::Filter(Arc::new(bloom)))
+        })? {
+            CachedBlock::Filter(bloom) => Ok(bloom),
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use moka::sync::ConcurrentCacheExt;
+
+    use super::*;
+
+    fn block(len: usize) -> Arc<Block> {
+        Arc::new(Block {
+            data: vec![0; len].into(),
+            restarts: vec![0],
+        })
+    }
+
+    #[test]
+    fn test_block_cache_stats() {
+        let cache = Arc::new(BlockCache::new(100));
+        let engine1 = cache.handle();
+        let engine2 = cache.handle();
+        engine1.get_data_block(1, 0, || Ok(block(40))).unwrap();
+        engine1.get_data_block(1, 0, || unreachable!()).unwrap();
+        // 另一个引擎的同名 SST 不会命中
+        engine2.get_data_block(1, 0, || Ok(block(40))).unwrap();
+        engine2.get_data_block(2, 0, || Ok(block(40))).unwrap();
+        cache.cache.sync();
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 3);
+        assert!(stats.evictions >= 1);
+        assert!(stats.size_bytes <= 100);
+    }
+
+    #[test]
+    fn test_cached_data_block_stats() {
+        let cache = Arc::new(BlockCache::new(100));
+        let engine = cache.handle();
+        assert!(engine.get_cached_data_block(1, 0).is_none());
+        engine.insert_data_block(1, 0, block(10));
+        assert!(engine.get_cached_data_block(1, 0).is_some());
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 1);
+    }
+}
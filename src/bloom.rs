@@ -0,0 +1,124 @@
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+/// SST 的布隆过滤器，位于 SST 文件的 meta 之后。
+/// 配置了 `prefix_extractor` 时，键的前缀也会和完整的键一起加入过滤器。
+pub struct Bloom {
+    /// 过滤器的位数组
+    pub(crate) filter: Bytes,
+    /// 哈希函数的个数
+    pub(crate) k: u8,
+}
+
+pub trait BitSlice {
+    fn get_bit(&self, idx: usize) -> bool;
+    fn bit_len(&self) -> usize;
+}
+
+pub trait BitSliceMut {
+    fn set_bit(&mut self, idx: usize, val: bool);
+}
+
+impl<T: AsRef<[u8]>> BitSlice for T {
+    fn get_bit(&self, idx: usize) -> bool {
+        let pos = idx / 8;
+        let offset = idx % 8;
+        (self.as_ref()[pos] & (1 << offset)) != 0
+    }
+
+    fn bit_len(&self) -> usize {
+        self.as_ref().len() * 8
+    }
+}
+
+impl<T: AsMut<[u8]>> BitSliceMut for T {
+    fn set_bit(&mut self, idx: usize, val: bool) {
+        let pos = idx / 8;
+        let offset = idx % 8;
+        if val {
+            self.as_mut()[pos] |= 1 << offset;
+        } else {
+            self.as_mut()[pos] &= !(1 << offset);
+        }
+    }
+}
+
+impl Bloom {
+    /// 从缓冲区解码布隆过滤器：| filter | k (u8) | checksum (u32) |
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 5 {
+            bail!("bloom filter too short");
+        }
+        let checksum = (&buf[buf.len() - 4..]).get_u32();
+        if checksum != crc32fast::hash(&buf[..buf.len() - 4]) {
+            bail!("checksum mismatched for bloom filters");
+        }
+        let filter = &buf[..buf.len() - 5];
+        let k = buf[buf.len() - 5];
+        Ok(Self {
+            filter: filter.to_vec().into(),
+            k,
+        })
+    }
+
+    /// 把布隆过滤器编码到缓冲区
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let offset = buf.len();
+        buf.extend(&self.filter);
+        buf.put_u8(self.k);
+        let checksum = crc32fast::hash(&buf[offset..]);
+        buf.put_u32(checksum);
+    }
+
+    /// 根据条目数和误判率计算每个键需要的位数
+    pub fn bloom_bits_per_key(entries: usize, false_positive_rate: f64) -> usize {
+        let size = -(entries as f64) * false_positive_rate.ln() / std::f64::consts::LN_2.powi(2);
+        let locs = (size / (entries as f64)).ceil();
+        locs as usize
+    }
+
+    /// 用键（和前缀）的哈希构建布隆过滤器
+    pub fn build_from_key_hashes(keys: &[u32], bits_per_key: usize) -> Self {
+        let k = (bits_per_key as f64 * 0.69) as u32;
+        let k = k.clamp(1, 30);
+        let nbits = (keys.len() * bits_per_key).max(64);
+        let nbytes = nbits.div_ceil(8);
+        let nbits = nbytes * 8;
+        let mut filter = BytesMut::with_capacity(nbytes);
+        filter.resize(nbytes, 0);
+        for h in keys {
+            let mut h = *h;
+            let delta = h.rotate_left(15);
+            for _ in 0..k {
+                let bit_pos = (h as usize) % nbits;
+                filter.set_bit(bit_pos, true);
+                h = h.wrapping_add(delta);
+            }
+        }
+        Self {
+            filter: filter.freeze(),
+            k: k as u8,
+        }
+    }
+
+    /// 检查过滤器是否可能包含这个哈希
+    pub fn may_contain(&self, mut h: u32) -> bool {
+        if self.k > 30 {
+            // 为以后的新编码保留，总是返回 true
+            return true;
+        }
+        let nbits = self.filter.bit_len();
+        if nbits == 0 {
+            return true;
+        }
+        let delta = h.rotate_left(15);
+        for _ in 0..self.k {
+            let bit_pos = h % (nbits as u32);
+            if !self.filter.get_bit(bit_pos as usize) {
+                return false;
+            }
+            h = h.wrapping_add(delta);
+        }
+        true
+    }
+}
@@ -0,0 +1,275 @@
+use std::{collections::BTreeSet, path::Path};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    column_family::{ColumnFamilyOptions, DEFAULT_CF_ID},
+    compact::{CompactionController, CompactionTask},
+    fs_util::{sync_dir, sync_file, sync_parent_dir},
+    lsm_storage::{CompactionOptions, LsmStorageInner, Manifest, ManifestRecord},
+};
+
+impl LsmStorageInner {
+    /// 在 `dir` 创建一个一致的检查点，可以直接用 `LsmStorageInner::open` 打开。
+    /// SST 和已经写完的 blob 文件用硬链接（不在同一个文件系统时复制），WAL 和正在写入的 blob 文件复制，
+    /// 再写一个只包含这些文件的 MANIFEST。期间暂停删除文件，写入可以继续，之后的写入不在检查点里。
+    /// 没有 WAL 时先把 memtable 刷新到 SST，检查点只包含已经刷新的数据。
+    pub fn create_checkpoint(&self, dir: impl AsRef<Path>) -> Result<()> {
+        self.check_writable()?;
+        let dir = dir.as_ref();
+        if dir.exists() {
+            bail!("checkpoint dir {:?} already exists", dir);
+        }
+        if !self.options.enable_wal {
+            self.flush_all_memtables()?;
+        }
+        // 等正在进行的删除结束，之后删除都被推迟
+        let _pause = self.file_deletion_lock.write();
+        // 在 `state_lock` 下取出文件列表和MANIFEST记录，复制文件时不再持有它，写入和刷新可以继续。
+        // 列表里的文件在复制完之前不会被删除
+        let (records, table_ids, wals) = {
+            let _state_lock = self.state_lock.lock();
+            self.checkpoint_records()?
+        };
+
+        std::fs::create_dir_all(dir).context("failed to create checkpoint dir")?;
+        sync_parent_dir(dir)?;
+        for id in table_ids {
+            link_or_copy(
+                &Self::path_of_sst_static(&self.path, id),
+                &Self::path_of_sst_static(dir, id),
+            )?;
+        }
+        // 先复制WAL再列出和复制blob文件：WAL里的指针指向的值总是先写进blob文件。
+        // 列表之后WAL还可能追加，多出的批次只是更晚的写入，末尾写到一半的批次恢复时被丢弃
+        if self.options.enable_wal {
+            self.sync()?;
+            for id in &wals {
+                copy_file(&self.path_of_wal(*id), &Self::path_of_wal_static(dir, *id))?;
+            }
+        }
+        // 正在写入的文件只能复制，硬链接之后再复制会截断原文件
+        let (sealed_blobs, active_blob) = self.blob_store.file_ids()?;
+        for id in sealed_blobs {
+            link_or_copy(
+                &Self::path_of_blob_static(&self.path, id),
+                &Self::path_of_blob_static(dir, id),
+            )?;
+        }
+        if let Some(id) = active_blob {
+            copy_file(
+                &Self::path_of_blob_static(&self.path, id),
+                &Self::path_of_blob_static(dir, id),
+            )?;
+        }
+
+        let manifest = Manifest::create(dir.join("MANIFEST"))?;
+        for record in records {
+            manifest.add_record_when_init(record)?;
+        }
+        sync_dir(dir)
+    }
+
+    /// 检查点的MANIFEST记录、要链接的SST和要复制的WAL。调用者持有 `state_lock`，
+    /// 期间不能冻结或刷新memtable、创建或删除列族，MANIFEST不会变化
+    fn checkpoint_records(&self) -> Result<(Vec<ManifestRecord>, Vec<usize>, BTreeSet<usize>)> {
+        let column_families = self.column_families.read().clone();
+        // 每个列族L0的 (列族id, SST id)，恢复时每个Flush记录插到最前面，从旧到新写
+        let mut tables = Vec::new();
+        // 压缩出的层用没有输入的压缩记录重建
+        let mut compactions = Vec::new();
+        for cf in column_families.values() {
+            let snapshot = cf.state.read().clone();
+            if CompactionController::new(&cf.options.compaction_options).flush_to_l0() {
+                tables.extend(snapshot.l0_sstables.iter().rev().map(|id| (cf.id, *id)));
+                let l1 = &snapshot.levels[0].1;
+                if !l1.is_empty() {
+                    let task = CompactionTask::L0ToL1 {
+                        l0_sstables: Vec::new(),
+                        l1_sstables: Vec::new(),
+                    };
+                    compactions.push((cf.id, task, l1.clone()));
+                }
+                for pair in snapshot.levels.windows(2) {
+                    let ((upper_level, _), (lower_level, files)) = (&pair[0], &pair[1]);
+                    if !files.is_empty() {
+                        let task = CompactionTask::LevelToLevel {
+                            upper_level: *upper_level,
+                            upper_sstables: Vec::new(),
+                            lower_level: *lower_level,
+                            lower_sstables: Vec::new(),
+                        };
+                        compactions.push((cf.id, task, files.clone()));
+                    }
+                }
+            } else {
+                // 每条记录把一层放在最后，从新到旧写
+                for (_, files) in &snapshot.levels {
+                    let task = CompactionTask::Tiered { tiers: Vec::new() };
+                    compactions.push((cf.id, task, files.clone()));
+                }
+            }
+        }
+        let (_, wals) = self.live_file_ids();
+
+        let table_ids = tables
+            .iter()
+            .map(|(_, id)| *id)
+            .chain(compactions.iter().flat_map(|(_, _, output)| output.clone()))
+            .collect();
+
+        let mut records = Vec::new();
+        for cf in column_families.values() {
+            if cf.id != DEFAULT_CF_ID {
+                records.push(ManifestRecord::CreateColumnFamily(
+                    cf.id,
+                    cf.name().to_string(),
+                    cf.options.clone(),
+                ));
+            }
+        }
+        // 已删除列族的id不能被重用，否则WAL里它的旧记录会被重放进新列族
+        let next_cf_id = self.next_cf_id.load(std::sync::atomic::Ordering::SeqCst);
+        let max_cf_id = column_families
+            .keys()
+            .max()
+            .copied()
+            .unwrap_or(DEFAULT_CF_ID);
+        if max_cf_id + 1 < next_cf_id {
+            let id = next_cf_id - 1;
+            records.push(ManifestRecord::CreateColumnFamily(
+                id,
+                String::new(),
+                ColumnFamilyOptions {
+                    block_size: self.options.block_size,
+                    compaction_options: CompactionOptions::NoCompaction,
+                },
+            ));
+            records.push(ManifestRecord::DropColumnFamily(id));
+        }
+        for (cf_id, task, output) in compactions {
+            records.push(ManifestRecord::Compaction(cf_id, task, output));
+        }
+        for (cf_id, id) in tables {
+            records.push(ManifestRecord::NewMemtable(id));
+            records.push(ManifestRecord::Flush(id, vec![(cf_id, id)]));
+        }
+        records.extend(wals.iter().copied().map(ManifestRecord::NewMemtable));
+        Ok((records, table_ids, wals))
+    }
+}
+
+// 硬链接只需要同步目录
+fn link_or_copy(src: &Path, dst: &Path) -> Result<()> {
+    if std::fs::hard_link(src, dst).is_ok() {
+        return Ok(());
+    }
+    copy_file(src, dst)
+}
+
+fn copy_file(src: &Path, dst: &Path) -> Result<()> {
+    std::fs::copy(src, dst).with_context(|| format!("failed to copy {:?}", src))?;
+    sync_file(&std::fs::File::open(dst)?, dst)
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    use std::os::unix::fs::MetadataExt;
+    use std::sync::Arc;
+
+    use tempfile::tempdir;
+
+    use crate::{
+        column_family::ColumnFamilyOptions,
+        iterators::StorageIterator,
+        lsm_storage::{
+            CompactionOptions, LsmStorageInner, LsmStorageOptions, LsmStorageState,
+            SsTableIterator, WriteBatchRecord,
+        },
+        sstable::{FileObject, SsTable},
+        value::encode_value,
+    };
+
+    #[test]
+    fn test_create_checkpoint() {
+        let dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.enable_wal = true;
+        options.large_value_threshold = Some(64);
+        let storage = LsmStorageInner::open(&dir, options.clone()).unwrap();
+
+        // 假装刷新出了一个SST
+        let sst_id = storage.next_sst_id();
+        let sst_path = LsmStorageInner::path_of_sst_static(&dir, sst_id);
+        let value = encode_value(b"sst", None);
+        SsTable::write_for_testing(&sst_path, &[(b"s", &value)]).unwrap();
+        let table = SsTable::open(sst_id, None, FileObject::open(&sst_path).unwrap()).unwrap();
+        {
+            let mut guard = storage.state.write();
+            let mut snapshot = LsmStorageState::clone(&guard);
+            snapshot.l0_sstables.insert(0, sst_id);
+            snapshot.sstables.insert(sst_id, Arc::new(table));
+            *guard = Arc::new(snapshot);
+        }
+        storage.live_files.lock().ssts.insert(sst_id);
+
+        storage.put(b"1", b"1").unwrap();
+        storage.put(b"large", &[b'x'; 100]).unwrap();
+        let cf_options = ColumnFamilyOptions {
+            block_size: 4096,
+            compaction_options: CompactionOptions::NoCompaction,
+        };
+        let cf = storage.create_cf("cf2", cf_options.clone()).unwrap();
+        storage
+            .write_batch_cf(&[(&*cf, &WriteBatchRecord::Put(b"3", b"3"))])
+            .unwrap();
+        // 最后创建的列族被删除了
+        let cf = storage.create_cf("cf", cf_options.clone()).unwrap();
+        storage
+            .write_batch_cf(&[(&*cf, &WriteBatchRecord::Put(b"2", b"2"))])
+            .unwrap();
+        storage.drop_cf("cf").unwrap();
+
+        let (_, active_blob) = storage.blob_store.file_ids().unwrap();
+        let active_blob = active_blob.unwrap();
+        let checkpoint = dir.path().join("checkpoint");
+        storage.create_checkpoint(&checkpoint).unwrap();
+        assert!(storage.create_checkpoint(&checkpoint).is_err());
+        storage.put(b"4", b"4").unwrap();
+
+        #[cfg(unix)]
+        {
+            let sst_copy = LsmStorageInner::path_of_sst_static(&checkpoint, sst_id);
+            assert_eq!(
+                std::fs::metadata(&sst_path).unwrap().ino(),
+                std::fs::metadata(&sst_copy).unwrap().ino()
+            );
+            // 正在写入的blob文件是复制的，之后的追加不会出现在检查点里
+            let blob = LsmStorageInner::path_of_blob_static(&dir, active_blob);
+            let blob_copy = LsmStorageInner::path_of_blob_static(&checkpoint, active_blob);
+            assert_ne!(
+                std::fs::metadata(&blob).unwrap().ino(),
+                std::fs::metadata(&blob_copy).unwrap().ino()
+            );
+        }
+        let copy = LsmStorageInner::open(&checkpoint, options.clone()).unwrap();
+        assert_eq!(copy.state.read().l0_sstables, vec![sst_id]);
+        let table = copy.state.read().sstables[&sst_id].clone();
+        let iter = SsTableIterator::create_and_seek_to_first(table).unwrap();
+        assert_eq!(iter.key().raw_ref(), b"s");
+        assert_eq!(iter.value(), &value[..]);
+        assert_eq!(&copy.get(b"1").unwrap().unwrap()[..], b"1");
+        assert_eq!(&copy.get(b"large").unwrap().unwrap()[..], &[b'x'; 100]);
+        assert!(copy.get(b"4").unwrap().is_none());
+        let cf = copy.cf_handle("cf2").unwrap();
+        assert_eq!(&copy.get_cf(&cf, b"3").unwrap().unwrap()[..], b"3");
+        // 已删除列族的id不会被重用，它在WAL里的记录不会出现在新列族里
+        assert!(copy.cf_handle("cf").is_none());
+        copy.create_cf("cf3", cf_options).unwrap();
+        drop(copy);
+        let copy = LsmStorageInner::open(&checkpoint, options).unwrap();
+        let cf = copy.cf_handle("cf3").unwrap();
+        assert!(copy.get_cf(&cf, b"2").unwrap().is_none());
+    }
+}
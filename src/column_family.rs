@@ -0,0 +1,123 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    lsm_storage::{CompactionOptions, LsmStorageInner, LsmStorageState, ManifestRecord},
+    MemTable,
+};
+
+/// 默认列族，`LsmStorageInner::put`/`get` 等不带列族的接口都作用在它上面。
+pub const DEFAULT_CF_NAME: &str = "default";
+pub(crate) const DEFAULT_CF_ID: usize = 0;
+
+/// 每个列族自己的选项，创建时写进 MANIFEST，恢复时从 MANIFEST 读回来。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ColumnFamilyOptions {
+    // 以字节为单位的块大小
+    pub block_size: usize,
+    //压缩等级
+    pub compaction_options: CompactionOptions,
+}
+
+/// 一个逻辑上独立的键空间，有自己的 memtable、L0 和各层 SST。
+/// 所有列族共用同一个 WAL 和 MANIFEST，所以跨列族的 `write_batch_cf` 是原子的。
+pub struct ColumnFamily {
+    pub(crate) id: usize,
+    name: String,
+    pub(crate) options: ColumnFamilyOptions,
+    pub(crate) state: Arc<RwLock<Arc<LsmStorageState>>>,
+}
+
+impl ColumnFamily {
+    pub(crate) fn new(
+        id: usize,
+        name: String,
+        options: ColumnFamilyOptions,
+        state: Arc<RwLock<Arc<LsmStorageState>>>,
+    ) -> Self {
+        Self {
+            id,
+            name,
+            options,
+            state,
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn options(&self) -> &ColumnFamilyOptions {
+        &self.options
+    }
+}
+
+impl LsmStorageInner {
+    /// 创建一个列族。新列族的 memtable 和当前的 memtable 共用一个 WAL。
+    pub fn create_cf(&self, name: &str, options: ColumnFamilyOptions) -> Result<Arc<ColumnFamily>> {
+        let state_lock = self.state_lock.lock();
+        self.check_writable()?;
+        if self.cf_handle(name).is_some() {
+            bail!("column family {} already exists", name);
+        }
+        let id = self.next_cf_id.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let mut state = LsmStorageState::create(&options.compaction_options);
+        {
+            let guard = self.state.read();
+            state.memtable = Arc::new(MemTable::create_sharing_wal(
+                guard.memtable.id(),
+                &guard.memtable,
+            ));
+        }
+        let cf = Arc::new(ColumnFamily::new(
+            id,
+            name.to_string(),
+            options.clone(),
+            Arc::new(RwLock::new(Arc::new(state))),
+        ));
+        self.manifest.as_ref().unwrap().add_record(
+            &state_lock,
+            ManifestRecord::CreateColumnFamily(id, name.to_string(), options),
+        )?;
+        self.column_families.write().insert(id, cf.clone());
+        Ok(cf)
+    }
+
+    /// 删除一个列族，之后 WAL 里属于它的记录在恢复时会被跳过。
+    pub fn drop_cf(&self, name: &str) -> Result<()> {
+        let state_lock = self.state_lock.lock();
+        self.check_writable()?;
+        let Some(cf) = self.cf_handle(name) else {
+            bail!("column family {} does not exist", name);
+        };
+        if cf.id == DEFAULT_CF_ID {
+            bail!("cannot drop the default column family");
+        }
+        self.manifest
+            .as_ref()
+            .unwrap()
+            .add_record(&state_lock, ManifestRecord::DropColumnFamily(cf.id))?;
+        self.column_families.write().remove(&cf.id);
+        Ok(())
+    }
+
+    /// 按名字查找列族。
+    pub fn cf_handle(&self, name: &str) -> Option<Arc<ColumnFamily>> {
+        self.column_families
+            .read()
+            .values()
+            .find(|cf| cf.name == name)
+            .cloned()
+    }
+
+    pub(crate) fn default_cf(&self) -> Arc<ColumnFamily> {
+        self.column_families.read()[&DEFAULT_CF_ID].clone()
+    }
+}
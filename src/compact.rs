@@ -1,9 +1,22 @@
-use std::{sync::Arc, time::Duration};
+use std::{borrow::Cow, sync::Arc, time::Duration};
 
-use crate::lsm_storage::{CompactionOptions, LsmStorageInner};
-use anyhow::{Context, Result};
+use crate::{
+    column_family::ColumnFamily,
+    iterators::StorageIterator,
+    key::{KeyBytes, KeySlice},
+    lsm_storage::{
+        CompactionOptions, LsmStorageInner, LsmStorageState, ManifestRecord, MergeIterator,
+        OpenMode, SsTableIterator, ValueResolver,
+    },
+    rate_limiter::IoPriority,
+    sstable::{SsTable, SsTableBuilder},
+    value::{decode_value, encode_value, now_ms, ValueRef},
+    MemTable,
+};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LeveledCompactionOptions {
     pub level_size_multiplier: usize,
     pub level0_file_num_compaction_trigger: usize,
@@ -12,19 +25,112 @@ pub struct LeveledCompactionOptions {
 }
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TieredCompactionOptions {
     pub num_tiers: usize,
     pub max_size_amplification_percent: usize,
     pub size_ratio: usize,
     pub min_merge_width: usize,
 }
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SimpleLeveledCompactionOptions {
     pub size_ratio_percent: usize,
     pub level0_file_num_compaction_trigger: usize,
     pub max_levels: usize,
 }
+
+/// 一次压缩的输入，写在 MANIFEST 的 `Compaction` 记录里，恢复时按它重放
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum CompactionTask {
+    /// 把这些 L0 SST 和 L1 的全部 SST 合并，输出替换 L1
+    L0ToL1 {
+        l0_sstables: Vec<usize>,
+        l1_sstables: Vec<usize>,
+    },
+    /// 把上一层的这些 SST 和下一层里和它们键范围重叠的 SST 合并，输出加入下一层
+    LevelToLevel {
+        upper_level: usize,
+        upper_sstables: Vec<usize>,
+        lower_level: usize,
+        lower_sstables: Vec<usize>,
+    },
+    /// 把这些相邻的层（从新到旧）合并成一层，放在它们原来的位置；没有输入时放在最后
+    Tiered { tiers: Vec<(usize, Vec<usize>)> },
+}
+
+impl CompactionTask {
+    /// 输入的 SST，从新到旧
+    pub(crate) fn input_ids(&self) -> Vec<usize> {
+        match self {
+            Self::L0ToL1 {
+                l0_sstables,
+                l1_sstables,
+            } => l0_sstables.iter().chain(l1_sstables).copied().collect(),
+            Self::LevelToLevel {
+                upper_sstables,
+                lower_sstables,
+                ..
+            } => upper_sstables
+                .iter()
+                .chain(lower_sstables)
+                .copied()
+                .collect(),
+            Self::Tiered { tiers } => tiers.iter().flat_map(|(_, files)| files).copied().collect(),
+        }
+    }
+
+    /// 输入 SST 加上输出层下面更旧的各层，在上面查找一个键就能找到输入里合并操作数的基础值
+    pub(crate) fn lookup_state(&self, snapshot: &LsmStorageState) -> LsmStorageState {
+        let (l0_sstables, levels) = match self {
+            Self::L0ToL1 {
+                l0_sstables,
+                l1_sstables,
+            } => {
+                let mut levels = vec![(1, l1_sstables.clone())];
+                levels.extend(snapshot.levels.iter().skip(1).cloned());
+                (l0_sstables.clone(), levels)
+            }
+            Self::LevelToLevel {
+                upper_level,
+                upper_sstables,
+                lower_level,
+                lower_sstables,
+            } => {
+                let mut levels = vec![
+                    (*upper_level, upper_sstables.clone()),
+                    (*lower_level, lower_sstables.clone()),
+                ];
+                levels.extend(snapshot.levels.iter().skip(*lower_level).cloned());
+                (Vec::new(), levels)
+            }
+            Self::Tiered { tiers } => {
+                let mut levels = tiers.clone();
+                if let Some(last) = tiers.last() {
+                    let below = snapshot
+                        .levels
+                        .iter()
+                        .skip_while(|tier| *tier != last)
+                        .skip(1);
+                    levels.extend(below.cloned());
+                }
+                (Vec::new(), levels)
+            }
+        };
+        let sstables = l0_sstables
+            .iter()
+            .chain(levels.iter().flat_map(|(_, files)| files))
+            .map(|id| (*id, snapshot.sstables[id].clone()))
+            .collect();
+        LsmStorageState {
+            memtable: Arc::new(MemTable::create(0)),
+            imm_memtables: Vec::new(),
+            l0_sstables,
+            levels,
+            sstables,
+        }
+    }
+}
+
 pub(crate) enum CompactionController {
     Leveled(LeveledCompactionController),
     Tiered(TieredCompactionController),
@@ -33,12 +139,107 @@ pub(crate) enum CompactionController {
 }
 
 impl CompactionController {
+    pub fn new(options: &CompactionOptions) -> Self {
+        match options {
+            CompactionOptions::Leveled(options) => {
+                Self::Leveled(LeveledCompactionController::new(options.clone()))
+            }
+            CompactionOptions::Tiered(options) => {
+                Self::Tiered(TieredCompactionController::new(options.clone()))
+            }
+            CompactionOptions::Simple(options) => {
+                Self::Simple(SimpleLeveledCompactionController::new(options.clone()))
+            }
+            CompactionOptions::NoCompaction => Self::NoCompaction,
+        }
+    }
+
     pub fn flush_to_l0(&self) -> bool {
         matches!(
             self,
             Self::Leveled(_) | Self::Simple(_) | Self::NoCompaction
         )
     }
+
+    /// 根据当前状态决定是否需要压缩，按各自配置的策略选出要合并的 SST
+    pub fn generate_compaction_task(&self, state: &LsmStorageState) -> Option<CompactionTask> {
+        match self {
+            Self::Leveled(controller) => controller.generate_compaction_task(state),
+            Self::Tiered(controller) => controller.generate_compaction_task(state),
+            Self::Simple(controller) => controller.generate_compaction_task(state),
+            Self::NoCompaction => None,
+        }
+    }
+
+    /// 把压缩的输出应用到状态上，返回新状态和被替换掉的 SST。
+    /// 压缩期间新刷新的 SST 不受影响；新状态的 `sstables` 由调用方更新。
+    /// `LevelToLevel` 的输出追加在下一层最后，调用方打开输出的 SST 后用 `sort_levels` 按键排序。
+    pub fn apply_compaction_result(
+        &self,
+        state: &LsmStorageState,
+        task: &CompactionTask,
+        output: &[usize],
+    ) -> (LsmStorageState, Vec<usize>) {
+        let mut state = state.clone();
+        let removed = task.input_ids();
+        match task {
+            CompactionTask::L0ToL1 {
+                l0_sstables,
+                l1_sstables,
+            } => {
+                state.l0_sstables.retain(|id| !l0_sstables.contains(id));
+                assert_eq!(
+                    &state.levels[0].1, l1_sstables,
+                    "L1 changed during compaction"
+                );
+                state.levels[0].1 = output.to_vec();
+            }
+            CompactionTask::LevelToLevel {
+                upper_level,
+                upper_sstables,
+                lower_level,
+                lower_sstables,
+            } => {
+                state.levels[upper_level - 1]
+                    .1
+                    .retain(|id| !upper_sstables.contains(id));
+                let lower = &mut state.levels[lower_level - 1].1;
+                lower.retain(|id| !lower_sstables.contains(id));
+                lower.extend_from_slice(output);
+            }
+            CompactionTask::Tiered { tiers } => {
+                // 压缩期间新刷新的层插在最前面，合并的层整体往后移
+                let start = match tiers.first() {
+                    Some(first) => state
+                        .levels
+                        .iter()
+                        .position(|tier| tier == first)
+                        .expect("tiers changed during compaction"),
+                    None => state.levels.len(),
+                };
+                assert!(
+                    state.levels[start..].starts_with(tiers),
+                    "tiers changed during compaction"
+                );
+                state.levels.drain(start..start + tiers.len());
+                if let Some(tier_id) = output.first() {
+                    state.levels.insert(start, (*tier_id, output.to_vec()));
+                }
+            }
+        }
+        (state, removed)
+    }
+}
+
+/// L0 的 SST 个数达到触发值时，把 L0 和整个 L1 合并
+fn l0_compaction_task(state: &LsmStorageState, trigger: usize) -> Option<CompactionTask> {
+    if state.l0_sstables.is_empty() || state.l0_sstables.len() < trigger {
+        return None;
+    }
+    Some(CompactionTask::L0ToL1 {
+        l0_sstables: state.l0_sstables.clone(),
+        l1_sstables: state.levels[0].1.clone(),
+    })
 }
 
 pub struct LeveledCompactionController {
@@ -48,6 +249,58 @@ impl LeveledCompactionController {
     pub fn new(options: LeveledCompactionOptions) -> Self {
         Self { options }
     }
+
+    /// L1 的目标大小是 `base_level_size_mb`，往下每层乘以 `level_size_multiplier`，最后一层不限
+    fn target_level_sizes(&self) -> Vec<u64> {
+        let mut target = (self.options.base_level_size_mb as u64).saturating_mul(1024 * 1024);
+        let mut sizes = Vec::with_capacity(self.options.max_levels);
+        for _ in 1..self.options.max_levels {
+            sizes.push(target);
+            target = target.saturating_mul(self.options.level_size_multiplier as u64);
+        }
+        sizes
+    }
+
+    /// L0 先按个数触发合并进 L1。否则选出大小超过目标最多的一层，
+    /// 把其中最旧的 SST 和下一层里键范围和它重叠的 SST 合并
+    fn generate_compaction_task(&self, state: &LsmStorageState) -> Option<CompactionTask> {
+        if let Some(task) =
+            l0_compaction_task(state, self.options.level0_file_num_compaction_trigger)
+        {
+            return Some(task);
+        }
+        let level_size = |files: &[usize]| -> u64 {
+            files.iter().map(|id| state.sstables[id].table_size()).sum()
+        };
+        let (index, _) = self
+            .target_level_sizes()
+            .into_iter()
+            .zip(&state.levels)
+            .enumerate()
+            .map(|(index, (target, (_, files)))| {
+                (index, level_size(files) as f64 / target.max(1) as f64)
+            })
+            .filter(|(_, ratio)| *ratio > 1.0)
+            .max_by(|a, b| a.1.total_cmp(&b.1))?;
+        let (upper_level, upper_files) = &state.levels[index];
+        let (lower_level, lower_files) = &state.levels[index + 1];
+        let oldest = *upper_files.iter().min()?;
+        let table = &state.sstables[&oldest];
+        let lower_sstables = lower_files
+            .iter()
+            .filter(|id| {
+                let other = &state.sstables[*id];
+                other.first_key() <= table.last_key() && other.last_key() >= table.first_key()
+            })
+            .copied()
+            .collect();
+        Some(CompactionTask::LevelToLevel {
+            upper_level: *upper_level,
+            upper_sstables: vec![oldest],
+            lower_level: *lower_level,
+            lower_sstables,
+        })
+    }
 }
 
 pub struct TieredCompactionController {
@@ -58,6 +311,41 @@ impl TieredCompactionController {
     pub fn new(options: TieredCompactionOptions) -> Self {
         Self { options }
     }
+
+    /// 层数达到 `num_tiers` 后依次检查：
+    /// 1. 其它层的 SST 个数超过最后一层的 `max_size_amplification_percent`，合并所有层；
+    /// 2. 从最新的层往下累加，下一层的 SST 个数超过前面这些层的 `100 + size_ratio` 百分比、
+    ///    且前面至少有 `min_merge_width` 层，合并前面这些层；
+    /// 3. 都不满足时合并最新的几层，让层数回到 `num_tiers` 以下
+    fn generate_compaction_task(&self, state: &LsmStorageState) -> Option<CompactionTask> {
+        let num_tiers = self.options.num_tiers.max(2);
+        let levels = &state.levels;
+        if levels.len() < num_tiers {
+            return None;
+        }
+        let (newer, last) = levels.split_at(levels.len() - 1);
+        let newer_size = newer.iter().map(|(_, files)| files.len()).sum::<usize>();
+        if newer_size * 100 >= last[0].1.len() * self.options.max_size_amplification_percent {
+            return Some(CompactionTask::Tiered {
+                tiers: levels.clone(),
+            });
+        }
+        let mut size = 0;
+        for i in 0..levels.len() - 1 {
+            size += levels[i].1.len();
+            let next_size = levels[i + 1].1.len();
+            if next_size * 100 > size * (100 + self.options.size_ratio)
+                && i + 1 >= self.options.min_merge_width
+            {
+                return Some(CompactionTask::Tiered {
+                    tiers: levels[..=i].to_vec(),
+                });
+            }
+        }
+        Some(CompactionTask::Tiered {
+            tiers: levels[..levels.len() - num_tiers + 2].to_vec(),
+        })
+    }
 }
 pub struct SimpleLeveledCompactionController {
     options: SimpleLeveledCompactionOptions,
@@ -67,45 +355,332 @@ impl SimpleLeveledCompactionController {
     pub fn new(options: SimpleLeveledCompactionOptions) -> Self {
         Self { options }
     }
+
+    /// L0 先按个数触发合并进 L1。否则找到第一对下一层的 SST 个数不到上一层
+    /// `size_ratio_percent` 百分比的相邻层，把上一层整层合并进下一层
+    fn generate_compaction_task(&self, state: &LsmStorageState) -> Option<CompactionTask> {
+        if let Some(task) =
+            l0_compaction_task(state, self.options.level0_file_num_compaction_trigger)
+        {
+            return Some(task);
+        }
+        state.levels.windows(2).find_map(|pair| {
+            let ((upper_level, upper), (lower_level, lower)) = (&pair[0], &pair[1]);
+            (!upper.is_empty() && lower.len() * 100 < upper.len() * self.options.size_ratio_percent)
+                .then(|| CompactionTask::LevelToLevel {
+                    upper_level: *upper_level,
+                    upper_sstables: upper.clone(),
+                    lower_level: *lower_level,
+                    lower_sstables: lower.clone(),
+                })
+        })
+    }
+}
+
+/// 子压缩负责的键范围，左闭右开，`None` 表示不限
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SubcompactionRange {
+    pub start: Option<KeyBytes>,
+    pub end: Option<KeyBytes>,
+}
+
+impl SubcompactionRange {
+    /// 键是否已经超出这个范围的右边界
+    pub fn is_past_end(&self, key: KeySlice) -> bool {
+        matches!(&self.end, Some(end) if key >= end.as_key_slice())
+    }
+
+    /// 这个范围的输入迭代器，定位到范围的左边界。调用方遇到 `is_past_end` 的键就停止。
+    pub fn create_iter(&self, tables: &[Arc<SsTable>]) -> Result<MergeIterator<SsTableIterator>> {
+        let mut iters = Vec::with_capacity(tables.len());
+        for table in tables {
+            if matches!(&self.start, Some(start) if table.last_key() < start)
+                || matches!(&self.end, Some(end) if table.first_key() >= end)
+            {
+                continue;
+            }
+            let iter = match &self.start {
+                Some(start) => {
+                    SsTableIterator::create_and_seek_to_key(table.clone(), start.as_key_slice())?
+                }
+                None => SsTableIterator::create_and_seek_to_first(table.clone())?,
+            };
+            iters.push(Box::new(iter));
+        }
+        Ok(MergeIterator::create(iters))
+    }
+}
+
+/// 按输入 SST 的数据块边界，把一次压缩切成最多 `max_subcompactions` 个互不相交的键范围，
+/// 每个范围可以在自己的线程上合并。
+pub(crate) fn plan_subcompactions(
+    tables: &[Arc<SsTable>],
+    max_subcompactions: usize,
+) -> Result<Vec<SubcompactionRange>> {
+    let mut boundaries = Vec::new();
+    for table in tables {
+        boundaries.extend(table.block_first_keys()?);
+    }
+    Ok(split_key_ranges(boundaries, max_subcompactions))
+}
+
+/// 数据块大小差不多，按块的个数平均切分，各个范围要合并的数据量也就差不多
+fn split_key_ranges(mut boundaries: Vec<KeyBytes>, max_ranges: usize) -> Vec<SubcompactionRange> {
+    boundaries.sort();
+    boundaries.dedup();
+    let num_ranges = max_ranges.min(boundaries.len()).max(1);
+    let mut ranges = Vec::with_capacity(num_ranges);
+    let mut start = None;
+    // 第一个边界是最小的键，不作为切分点
+    for i in 1..num_ranges {
+        let split = boundaries[i * boundaries.len() / num_ranges].clone();
+        ranges.push(SubcompactionRange {
+            start,
+            end: Some(split.clone()),
+        });
+        start = Some(split);
+    }
+    ranges.push(SubcompactionRange { start, end: None });
+    ranges
 }
 
 impl LsmStorageInner {
-    pub(crate) fn spawn_compaction_thread(
-        self: &Arc<Self>,
-        rx: crossbeam_channel::Receiver<()>,
-    ) -> Result<Option<std::thread::JoinHandle<()>>> {
-        if let CompactionOptions::Leveled(_)
-        | CompactionOptions::Simple(_)
-        | CompactionOptions::Tiered(_) = self.options.compaction_options
+    /// 依次检查每个列族，按它自己的压缩配置需要时压缩一次
+    pub(crate) fn trigger_compaction(&self) -> Result<()> {
+        let column_families = self
+            .column_families
+            .read()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        for cf in column_families {
+            let controller = CompactionController::new(&cf.options.compaction_options);
+            let Some(task) = controller.generate_compaction_task(&cf.state.read()) else {
+                continue;
+            };
+            self.run_compaction_task(&cf, &controller, task)?;
+        }
+        Ok(())
+    }
+
+    /// 执行压缩任务，所有输出用一条 MANIFEST 记录同时生效
+    fn run_compaction_task(
+        &self,
+        cf: &ColumnFamily,
+        controller: &CompactionController,
+        task: CompactionTask,
+    ) -> Result<()> {
+        self.check_writable()?;
+        let resolver = self.value_resolver();
+        let snapshot = cf.state.read().clone();
+        let output = self.compact(cf, &resolver, &snapshot, &task)?;
+        let output_ids = output
+            .iter()
+            .map(|table| table.sst_id())
+            .collect::<Vec<_>>();
+
+        let state_lock = self.state_lock.lock();
+        // 压缩期间被删除的列族不用记录，输出的SST下次打开时作为孤儿文件删除
+        if !self.column_families.read().contains_key(&cf.id) {
+            return Ok(());
+        }
+        self.manifest.as_ref().unwrap().add_record(
+            &state_lock,
+            ManifestRecord::Compaction(cf.id, task.clone(), output_ids.clone()),
+        )?;
+        let mut retired = Vec::new();
         {
-            let this = self.clone();
-            let handle = std::thread::spawn(move || {
-                let ticker = crossbeam_channel::tick(Duration::from_millis(50));
-                loop {
-                    crossbeam_channel::select! {
-                        // recv(ticker) -> _ => if let Err(e) = this.trigger_compaction() {
-                        //     eprintln!("compaction failed: {}", e);
-                        // },
-                        recv(rx) -> _ => return
+            let mut guard = cf.state.write();
+            let (mut new_state, removed) =
+                controller.apply_compaction_result(&guard, &task, &output_ids);
+            for id in removed {
+                retired.extend(new_state.sstables.remove(&id));
+            }
+            for table in output {
+                new_state.sstables.insert(table.sst_id(), table);
+            }
+            new_state.sort_levels();
+            *guard = Arc::new(new_state);
+        }
+        self.live_files.lock().ssts.extend(output_ids);
+        // 还在读旧快照的迭代器释放之后，输入的文件才会被删除
+        for table in retired {
+            self.retire_sst(table);
+        }
+        Ok(())
+    }
+
+    /// 按数据块边界把输入切成互不相交的键范围，每个范围在自己的线程上合并，返回按键排好序的输出
+    fn compact(
+        &self,
+        cf: &ColumnFamily,
+        resolver: &ValueResolver,
+        snapshot: &LsmStorageState,
+        task: &CompactionTask,
+    ) -> Result<Vec<Arc<SsTable>>> {
+        // 从新到旧，MergeIterator 里同一个键取最新的版本
+        let tables = task
+            .input_ids()
+            .into_iter()
+            .map(|id| snapshot.sstables[&id].clone())
+            .collect::<Vec<_>>();
+        let lookup = task.lookup_state(snapshot);
+        // 输出下面没有更旧的数据时，墓碑和过期的值不用再遮住什么
+        let bottommost = match task {
+            CompactionTask::L0ToL1 { .. } => snapshot.levels[1..]
+                .iter()
+                .all(|(_, files)| files.is_empty()),
+            CompactionTask::LevelToLevel { lower_level, .. } => snapshot.levels[*lower_level..]
+                .iter()
+                .all(|(_, files)| files.is_empty()),
+            CompactionTask::Tiered { tiers } => snapshot.levels.ends_with(tiers),
+        };
+        let ranges = plan_subcompactions(&tables, self.options.max_subcompactions)?;
+        let outputs = std::thread::scope(|scope| {
+            let handles = ranges
+                .iter()
+                .map(|range| {
+                    let (tables, lookup) = (&tables, &lookup);
+                    scope.spawn(move || {
+                        self.run_subcompaction(cf, resolver, lookup, tables, range, bottommost)
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .map_err(|_| anyhow!("subcompaction thread panicked"))?
+                })
+                .collect::<Result<Vec<_>>>()
+        })?;
+        Ok(outputs.into_iter().flatten().collect())
+    }
+
+    /// 合并一个键范围，输出达到 `target_sst_size` 时切换到下一个 SST。
+    /// 输出到最底层时丢掉墓碑和已经过期的值。
+    fn run_subcompaction(
+        &self,
+        cf: &ColumnFamily,
+        resolver: &ValueResolver,
+        lookup: &LsmStorageState,
+        tables: &[Arc<SsTable>],
+        range: &SubcompactionRange,
+        bottommost: bool,
+    ) -> Result<Vec<Arc<SsTable>>> {
+        let now = now_ms();
+        let mut iter = range.create_iter(tables)?;
+        let mut builder: Option<SsTableBuilder> = None;
+        let mut output = Vec::new();
+        while iter.is_valid() && !range.is_past_end(iter.key()) {
+            let value = match decode_value(iter.value(), now)? {
+                ValueRef::Deleted if bottommost => {
+                    iter.next()?;
+                    continue;
+                }
+                // 还要遮住更旧的版本，过期的值只留下墓碑
+                ValueRef::Deleted => Cow::Borrowed(&[][..]),
+                // 输入里更旧的版本压缩后就没有了，先把操作数和基础值合并；
+                // 下面没有基础值时只有一个操作数就原样写出，让它以后再和更深层的值合并
+                ValueRef::Merge(_) => {
+                    let key = iter.key().raw_ref();
+                    let merged = resolver.merge_versions(lookup, key, now)?;
+                    match merged.value {
+                        _ if !merged.has_base && !bottommost && merged.num_operands == 1 => {
+                            Cow::Borrowed(iter.value())
+                        }
+                        Some(value) => Cow::Owned(encode_value(&value, merged.expire_at)),
+                        None => Cow::Borrowed(&[][..]),
                     }
                 }
+                _ => Cow::Borrowed(iter.value()),
+            };
+            let current = builder.get_or_insert_with(|| {
+                SsTableBuilder::new(
+                    cf.options.block_size,
+                    self.options.block_compression,
+                    self.options.prefix_extractor.clone(),
+                )
             });
-            return Ok(Some(handle));
+            current.add(iter.key(), &value);
+            if current.estimated_size() >= self.options.target_sst_size {
+                let current = builder.take().unwrap();
+                output.push(self.write_sst(self.next_sst_id(), current, false, IoPriority::Low)?);
+            }
+            iter.next()?;
+        }
+        if let Some(builder) = builder {
+            output.push(self.write_sst(self.next_sst_id(), builder, false, IoPriority::Low)?);
         }
-        Ok(None)
+        Ok(output)
+    }
+
+    /// 不可变memtable的个数达到 `num_memtable_limit` 时刷新最旧的一个
+    fn trigger_flush(&self) -> Result<()> {
+        let num_imm_memtables = self
+            .column_families
+            .read()
+            .values()
+            .map(|cf| cf.state.read().imm_memtables.len())
+            .max()
+            .unwrap_or_default();
+        if num_imm_memtables >= self.options.num_memtable_limit {
+            self.force_flush_next_imm_memtable()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn spawn_compaction_thread(
+        self: &Arc<Self>,
+        rx: crossbeam_channel::Receiver<()>,
+    ) -> Result<Option<std::thread::JoinHandle<()>>> {
+        // 只读实例和从实例不刷新也不压缩
+        if self.mode != OpenMode::ReadWrite {
+            return Ok(None);
+        }
+        // 每个列族有自己的压缩配置，默认列族不压缩时其它列族也可能要压缩
+        self.write_controller.set_has_compaction_thread();
+        let this = self.clone();
+        let handle = std::thread::spawn(move || {
+            let ticker = crossbeam_channel::tick(Duration::from_millis(50));
+            loop {
+                crossbeam_channel::select! {
+                    // 压缩完成后唤醒被阻塞的写入者重新检查
+                    recv(ticker) -> _ => {
+                        if let Err(e) = this.trigger_compaction() {
+                            eprintln!("compaction failed: {}", e);
+                        }
+                        this.write_controller.wake_stalled_writers();
+                    },
+                    recv(rx) -> _ => return
+                }
+            }
+        });
+        Ok(Some(handle))
     }
     pub(crate) fn spawn_flush_thread(
         self: &Arc<Self>,
         rx: crossbeam_channel::Receiver<()>,
     ) -> Result<Option<std::thread::JoinHandle<()>>> {
+        if self.mode != OpenMode::ReadWrite {
+            return Ok(None);
+        }
         let this = self.clone();
         let handle = std::thread::spawn(move || {
             let ticker = crossbeam_channel::tick(Duration::from_millis(50));
             loop {
                 crossbeam_channel::select! {
-                    // recv(ticker) -> _ => if let Err(e) = this.trigger_flush() {
-                    //     eprintln!("flush failed: {}", e);
-                    // },
+                    // 刷新完成后唤醒被阻塞的写入者重新检查，顺便删除不再被引用的文件
+                    recv(ticker) -> _ => {
+                        if let Err(e) = this.trigger_flush() {
+                            eprintln!("flush failed: {}", e);
+                        }
+                        this.write_controller.wake_stalled_writers();
+                        if let Err(e) = this.delete_obsolete_files() {
+                            eprintln!("delete obsolete files failed: {}", e);
+                        }
+                    },
                     recv(rx) -> _ => return
                 }
             }
@@ -113,3 +688,595 @@ impl LsmStorageInner {
         Ok(Some(handle))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use anyhow::Result;
+    use bytes::Bytes;
+    use tempfile::tempdir;
+
+    use super::{
+        split_key_ranges, CompactionController, CompactionTask, LeveledCompactionOptions,
+        SimpleLeveledCompactionOptions, SubcompactionRange, TieredCompactionOptions,
+    };
+    use crate::{
+        iterators::StorageIterator,
+        key::{KeyBytes, KeySlice},
+        lsm_storage::{
+            CompactionOptions, LsmStorageInner, LsmStorageOptions, LsmStorageState,
+            SsTableIterator, WriteBatchRecord,
+        },
+        merge_operator::MergeOperator,
+        value::{decode_value, now_ms, value_expire_at, ValueRef},
+    };
+
+    /// 用逗号把操作数追加到旧值后面
+    #[derive(Debug)]
+    struct AppendOperator;
+
+    impl MergeOperator for AppendOperator {
+        fn full_merge(
+            &self,
+            _key: &[u8],
+            existing: Option<&[u8]>,
+            operands: &[&[u8]],
+        ) -> Result<Vec<u8>> {
+            let mut parts = existing.into_iter().collect::<Vec<_>>();
+            parts.extend_from_slice(operands);
+            Ok(parts.join(&b","[..]))
+        }
+    }
+
+    fn compaction_test_options(compaction_options: CompactionOptions) -> LsmStorageOptions {
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.compaction_options = compaction_options;
+        options.merge_operator = Some(Arc::new(AppendOperator));
+        // 块和SST都很小，输入能切成多个子压缩，输出也不止一个SST
+        options.block_size = 64;
+        options.target_sst_size = 1024;
+        options.max_subcompactions = 4;
+        options
+    }
+
+    /// 写两个memtable并刷新：先写100个键，再覆盖偶数键、删除最后10个键、给前10个键追加操作数
+    fn write_two_flushes(storage: &LsmStorageInner) {
+        let keys = (0..100).map(|i| format!("key{:03}", i)).collect::<Vec<_>>();
+        let batch = keys
+            .iter()
+            .map(|key| WriteBatchRecord::Put(key.clone(), format!("v-{}", key)))
+            .collect::<Vec<_>>();
+        storage.write_batch(&batch).unwrap();
+        storage.flush_all_memtables().unwrap();
+        let mut batch = Vec::new();
+        for (i, key) in keys.iter().enumerate() {
+            if i >= 90 {
+                batch.push(WriteBatchRecord::Del(key.clone()));
+            } else if i < 10 {
+                batch.push(WriteBatchRecord::Merge(key.clone(), "m".to_string()));
+            } else if i % 2 == 0 {
+                batch.push(WriteBatchRecord::Put(key.clone(), format!("w-{}", key)));
+            }
+        }
+        storage.write_batch(&batch).unwrap();
+        storage.flush_all_memtables().unwrap();
+    }
+
+    fn check_values(storage: &LsmStorageInner) {
+        for i in 0..100 {
+            let key = format!("key{:03}", i);
+            let expected = if i >= 90 {
+                None
+            } else if i < 10 {
+                Some(format!("v-{},m", key))
+            } else if i % 2 == 0 {
+                Some(format!("w-{}", key))
+            } else {
+                Some(format!("v-{}", key))
+            };
+            let value = storage.get(key.as_bytes()).unwrap();
+            assert_eq!(
+                value.as_deref(),
+                expected.as_ref().map(|x| x.as_bytes()),
+                "{}",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn test_leveled_compaction() {
+        let dir = tempdir().unwrap();
+        let options =
+            compaction_test_options(CompactionOptions::Leveled(LeveledCompactionOptions {
+                level_size_multiplier: 10,
+                level0_file_num_compaction_trigger: 2,
+                max_levels: 3,
+                base_level_size_mb: 1,
+            }));
+        let storage = LsmStorageInner::open(&dir, options.clone()).unwrap();
+        write_two_flushes(&storage);
+        assert_eq!(storage.state.read().l0_sstables.len(), 2);
+        let inputs = storage.state.read().l0_sstables.clone();
+        let reader = storage.state.read().clone();
+        storage.trigger_compaction().unwrap();
+        // 读旧快照的读者释放之后，压缩的输入才被删除
+        assert_eq!(storage.delete_obsolete_files().unwrap(), 0);
+        drop(reader);
+        assert_eq!(storage.delete_obsolete_files().unwrap(), inputs.len());
+        for id in inputs {
+            assert!(!LsmStorageInner::path_of_sst_static(&dir, id).exists());
+        }
+
+        let snapshot = storage.state.read().clone();
+        assert!(snapshot.l0_sstables.is_empty());
+        let l1 = &snapshot.levels[0].1;
+        assert!(l1.len() > 1);
+        // L1的SST按键排序，互不重叠
+        for pair in l1.windows(2) {
+            assert!(
+                snapshot.sstables[&pair[0]].last_key() < snapshot.sstables[&pair[1]].first_key()
+            );
+        }
+        assert_eq!(snapshot.sstables.len(), l1.len());
+        check_values(&storage);
+        // L0不够多时不压缩
+        storage.trigger_compaction().unwrap();
+        assert_eq!(&storage.state.read().levels[0].1, l1);
+
+        let checkpoint = dir.path().join("checkpoint");
+        storage.create_checkpoint(&checkpoint).unwrap();
+        drop(storage);
+        for path in [dir.path().to_path_buf(), checkpoint] {
+            let storage = LsmStorageInner::open(&path, options.clone()).unwrap();
+            assert!(storage.state.read().l0_sstables.is_empty());
+            assert_eq!(&storage.state.read().levels[0].1, l1);
+            check_values(&storage);
+        }
+    }
+
+    #[test]
+    fn test_tiered_compaction() {
+        let dir = tempdir().unwrap();
+        let options = compaction_test_options(CompactionOptions::Tiered(TieredCompactionOptions {
+            num_tiers: 2,
+            max_size_amplification_percent: 200,
+            size_ratio: 1,
+            min_merge_width: 2,
+        }));
+        let storage = LsmStorageInner::open(&dir, options.clone()).unwrap();
+        write_two_flushes(&storage);
+        assert_eq!(storage.state.read().levels.len(), 2);
+        storage.trigger_compaction().unwrap();
+
+        let levels = storage.state.read().levels.clone();
+        assert_eq!(levels.len(), 1);
+        assert_eq!(levels[0].0, levels[0].1[0]);
+        check_values(&storage);
+        // 之后刷新的层在前面
+        storage.put(b"key100", b"x").unwrap();
+        storage.flush_all_memtables().unwrap();
+        assert_eq!(storage.state.read().levels[1], levels[0]);
+        drop(storage);
+        let storage = LsmStorageInner::open(&dir, options).unwrap();
+        assert_eq!(storage.state.read().levels[1], levels[0]);
+        check_values(&storage);
+        assert_eq!(&storage.get(b"key100").unwrap().unwrap()[..], b"x");
+    }
+
+    #[test]
+    fn test_leveled_compaction_to_lower_levels() {
+        let dir = tempdir().unwrap();
+        let mut options =
+            compaction_test_options(CompactionOptions::Leveled(LeveledCompactionOptions {
+                level_size_multiplier: 10,
+                level0_file_num_compaction_trigger: 2,
+                max_levels: 3,
+                base_level_size_mb: 1,
+            }));
+        options.block_size = 4096;
+        options.target_sst_size = 64 * 1024;
+        let storage = LsmStorageInner::open(&dir, options.clone()).unwrap();
+        let value = |i: usize| format!("{:04}", i).repeat(256);
+        for keys in [(0..1500).step_by(2), (1..1500).step_by(2)] {
+            let batch = keys
+                .map(|i| WriteBatchRecord::Put(format!("key{:04}", i), value(i)))
+                .collect::<Vec<_>>();
+            storage.write_batch(&batch).unwrap();
+            storage.flush_all_memtables().unwrap();
+        }
+        storage.trigger_compaction().unwrap();
+        let level_size = |files: &[usize]| {
+            let snapshot = storage.state.read();
+            files
+                .iter()
+                .map(|id| snapshot.sstables[id].table_size())
+                .sum::<u64>()
+        };
+        let l1 = storage.state.read().levels[0].1.clone();
+        assert!(level_size(&l1) > 1024 * 1024);
+
+        // L1超过1MB，每次把它最旧的一个SST和L2里重叠的SST合并，直到不超过目标大小
+        storage.trigger_compaction().unwrap();
+        let oldest = l1.iter().min().unwrap();
+        assert!(!storage.state.read().levels[0].1.contains(oldest));
+        loop {
+            let levels = storage.state.read().levels.clone();
+            storage.trigger_compaction().unwrap();
+            if storage.state.read().levels == levels {
+                break;
+            }
+        }
+        let snapshot = storage.state.read().clone();
+        assert!(level_size(&snapshot.levels[0].1) <= 1024 * 1024);
+        assert!(!snapshot.levels[1].1.is_empty());
+        assert!(snapshot.levels[2].1.is_empty());
+        // 每层的SST按键排序，互不重叠
+        for (_, files) in &snapshot.levels {
+            for pair in files.windows(2) {
+                assert!(
+                    snapshot.sstables[&pair[0]].last_key()
+                        < snapshot.sstables[&pair[1]].first_key()
+                );
+            }
+        }
+        let check = |storage: &LsmStorageInner| {
+            for i in (0..1500).step_by(7) {
+                let key = format!("key{:04}", i);
+                assert_eq!(
+                    &storage.get(key.as_bytes()).unwrap().unwrap()[..],
+                    value(i).as_bytes()
+                );
+            }
+        };
+        check(&storage);
+
+        let checkpoint = dir.path().join("checkpoint");
+        storage.create_checkpoint(&checkpoint).unwrap();
+        drop(storage);
+        for path in [dir.path().to_path_buf(), checkpoint] {
+            let storage = LsmStorageInner::open(&path, options.clone()).unwrap();
+            assert_eq!(storage.state.read().levels, snapshot.levels);
+            check(&storage);
+        }
+    }
+
+    #[test]
+    fn test_simple_leveled_compaction_task() {
+        let options = CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+        });
+        let controller = CompactionController::new(&options);
+        let mut state = LsmStorageState::create(&options);
+        state.levels = vec![(1, vec![1, 2]), (2, vec![3]), (3, vec![])];
+        // L2只有L1的一半，不到200%，把L1整层合并进L2
+        assert!(matches!(
+            controller.generate_compaction_task(&state),
+            Some(CompactionTask::LevelToLevel {
+                upper_level: 1,
+                lower_level: 2,
+                upper_sstables,
+                lower_sstables,
+            }) if upper_sstables == [1, 2] && lower_sstables == [3]
+        ));
+        state.levels = vec![(1, vec![1, 2]), (2, vec![3, 4, 5, 6]), (3, vec![])];
+        assert!(matches!(
+            controller.generate_compaction_task(&state),
+            Some(CompactionTask::LevelToLevel {
+                upper_level: 2,
+                lower_level: 3,
+                ..
+            })
+        ));
+        state.levels[2].1 = (7..15).collect();
+        assert!(controller.generate_compaction_task(&state).is_none());
+        // L0的SST个数达到触发值时先合并进L1
+        state.l0_sstables = vec![20, 21];
+        assert!(matches!(
+            controller.generate_compaction_task(&state),
+            Some(CompactionTask::L0ToL1 { .. })
+        ));
+    }
+
+    #[test]
+    fn test_tiered_compaction_task() {
+        let options = CompactionOptions::Tiered(TieredCompactionOptions {
+            num_tiers: 3,
+            max_size_amplification_percent: 200,
+            size_ratio: 1,
+            min_merge_width: 2,
+        });
+        let controller = CompactionController::new(&options);
+        let mut state = LsmStorageState::create(&options);
+        let tiers = |sizes: &[usize]| -> Vec<(usize, Vec<usize>)> {
+            let mut next_id = 100;
+            sizes
+                .iter()
+                .map(|size| {
+                    let files = (next_id..next_id + size).collect::<Vec<_>>();
+                    next_id += size;
+                    (files[0], files)
+                })
+                .collect()
+        };
+        let task = |state: &LsmStorageState| match controller.generate_compaction_task(state) {
+            Some(CompactionTask::Tiered { tiers }) => Some(tiers),
+            _ => None,
+        };
+        state.levels = tiers(&[1, 1]);
+        assert_eq!(task(&state), None);
+        // 其它层加起来达到最后一层的200%，合并所有层
+        state.levels = tiers(&[1, 1, 1]);
+        assert_eq!(task(&state), Some(state.levels.clone()));
+        // 第三层比前两层大得多，合并前两层
+        state.levels = tiers(&[1, 1, 5]);
+        assert_eq!(task(&state), Some(state.levels[..2].to_vec()));
+        // 只有一层时不满足 `min_merge_width`
+        state.levels = tiers(&[1, 5, 10]);
+        assert_eq!(task(&state), Some(state.levels[..2].to_vec()));
+        // 都不满足时合并最新的几层，让层数回到 `num_tiers` 以下
+        state.levels = tiers(&[1, 1, 1, 2]);
+        assert_eq!(task(&state), Some(state.levels[..3].to_vec()));
+
+        // 压缩期间新刷新的层插在最前面，输出放在合并的层原来的位置
+        let task = CompactionTask::Tiered {
+            tiers: state.levels[..3].to_vec(),
+        };
+        let last = state.levels[3].clone();
+        state.add_flushed_sst(1, false);
+        let (new_state, removed) = controller.apply_compaction_result(&state, &task, &[50, 51]);
+        assert_eq!(removed, vec![100, 101, 102]);
+        assert_eq!(
+            new_state.levels,
+            vec![(1, vec![1]), (50, vec![50, 51]), last]
+        );
+    }
+
+    fn key(key: &str) -> KeyBytes {
+        KeyBytes::from_bytes(Bytes::copy_from_slice(key.as_bytes()))
+    }
+
+    #[test]
+    fn test_compaction_drops_deleted_and_expired() {
+        let dir = tempdir().unwrap();
+        let options =
+            compaction_test_options(CompactionOptions::Leveled(LeveledCompactionOptions {
+                level_size_multiplier: 10,
+                level0_file_num_compaction_trigger: 2,
+                max_levels: 3,
+                base_level_size_mb: 1,
+            }));
+        let storage = LsmStorageInner::open(&dir, options).unwrap();
+        storage
+            .write_batch(&[
+                WriteBatchRecord::Put("a", "1"),
+                WriteBatchRecord::PutWithTtl("b", "2", Duration::from_millis(50)),
+                WriteBatchRecord::Put("c", "3"),
+                WriteBatchRecord::PutWithTtl("d", "4", Duration::from_secs(3600)),
+            ])
+            .unwrap();
+        storage.flush_all_memtables().unwrap();
+        storage.write_batch(&[WriteBatchRecord::Del("a")]).unwrap();
+        storage.flush_all_memtables().unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        storage.trigger_compaction().unwrap();
+
+        // L1下面没有更旧的数据，墓碑和过期的值都不再写出
+        let snapshot = storage.state.read().clone();
+        let mut keys = Vec::new();
+        for id in &snapshot.levels[0].1 {
+            let mut iter =
+                SsTableIterator::create_and_seek_to_first(snapshot.sstables[id].clone()).unwrap();
+            while iter.is_valid() {
+                keys.push(iter.key().raw_ref().to_vec());
+                iter.next().unwrap();
+            }
+        }
+        assert_eq!(keys, vec![b"c".to_vec(), b"d".to_vec()]);
+        assert!(storage.get(b"a").unwrap().is_none());
+        assert!(storage.get(b"b").unwrap().is_none());
+        assert_eq!(&storage.get(b"d").unwrap().unwrap()[..], b"4");
+    }
+
+    #[test]
+    fn test_compaction_collapses_merge_operands() {
+        let dir = tempdir().unwrap();
+        let options =
+            compaction_test_options(CompactionOptions::Leveled(LeveledCompactionOptions {
+                level_size_multiplier: 10,
+                level0_file_num_compaction_trigger: 3,
+                max_levels: 3,
+                base_level_size_mb: 1,
+            }));
+        let storage = LsmStorageInner::open(&dir, options).unwrap();
+        storage
+            .write_batch(&[
+                WriteBatchRecord::Put("a", "1"),
+                WriteBatchRecord::Put("b", "1"),
+            ])
+            .unwrap();
+        storage.flush_all_memtables().unwrap();
+        storage
+            .write_batch(&[
+                WriteBatchRecord::Merge("a", "2"),
+                WriteBatchRecord::Del("b"),
+                WriteBatchRecord::Merge("c", "1"),
+            ])
+            .unwrap();
+        storage.flush_all_memtables().unwrap();
+        storage
+            .write_batch(&[
+                WriteBatchRecord::Merge("a", "3"),
+                WriteBatchRecord::Merge("b", "2"),
+                WriteBatchRecord::Merge("c", "2"),
+            ])
+            .unwrap();
+        storage.flush_all_memtables().unwrap();
+        storage.trigger_compaction().unwrap();
+
+        // 每个键只剩一个合并好的值，不再有操作数
+        let snapshot = storage.state.read().clone();
+        let mut entries = Vec::new();
+        for id in &snapshot.levels[0].1 {
+            let mut iter =
+                SsTableIterator::create_and_seek_to_first(snapshot.sstables[id].clone()).unwrap();
+            while iter.is_valid() {
+                let value = match decode_value(iter.value(), now_ms()).unwrap() {
+                    ValueRef::Put(value) => value.to_vec(),
+                    value => panic!("unexpected value {:?}", value),
+                };
+                entries.push((iter.key().raw_ref().to_vec(), value));
+                iter.next().unwrap();
+            }
+        }
+        assert_eq!(
+            entries,
+            vec![
+                (b"a".to_vec(), b"1,2,3".to_vec()),
+                (b"b".to_vec(), b"2".to_vec()),
+                (b"c".to_vec(), b"1,2".to_vec()),
+            ]
+        );
+        // 压缩之后的操作数合并到压缩出的值上
+        storage.merge(b"a", b"4").unwrap();
+        assert_eq!(&storage.get(b"a").unwrap().unwrap()[..], b"1,2,3,4");
+    }
+
+    #[test]
+    fn test_compaction_keeps_base_in_deeper_level() {
+        let dir = tempdir().unwrap();
+        let options =
+            compaction_test_options(CompactionOptions::Leveled(LeveledCompactionOptions {
+                level_size_multiplier: 10,
+                level0_file_num_compaction_trigger: 2,
+                max_levels: 3,
+                base_level_size_mb: 1,
+            }));
+        let storage = LsmStorageInner::open(&dir, options).unwrap();
+        storage.put(b"a", b"1").unwrap();
+        storage.flush_all_memtables().unwrap();
+        // 把基础值挪到 L2，L0 压缩到 L1 时它不在输入里
+        {
+            let mut guard = storage.state.write();
+            let mut snapshot = guard.as_ref().clone();
+            let l0 = std::mem::take(&mut snapshot.l0_sstables);
+            snapshot.levels[1].1 = l0;
+            *guard = Arc::new(snapshot);
+        }
+        storage
+            .write_batch(&[
+                WriteBatchRecord::Merge("a", "2"),
+                WriteBatchRecord::Merge("b", "1"),
+            ])
+            .unwrap();
+        storage.flush_all_memtables().unwrap();
+        storage.merge(b"a", b"3").unwrap();
+        storage.flush_all_memtables().unwrap();
+        storage.trigger_compaction().unwrap();
+
+        let snapshot = storage.state.read().clone();
+        assert!(snapshot.l0_sstables.is_empty());
+        let mut entries = Vec::new();
+        for id in &snapshot.levels[0].1 {
+            let mut iter =
+                SsTableIterator::create_and_seek_to_first(snapshot.sstables[id].clone()).unwrap();
+            while iter.is_valid() {
+                let value = match decode_value(iter.value(), now_ms()).unwrap() {
+                    ValueRef::Put(value) => format!("put:{}", String::from_utf8_lossy(value)),
+                    ValueRef::Merge(value) => format!("merge:{}", String::from_utf8_lossy(value)),
+                    value => panic!("unexpected value {:?}", value),
+                };
+                entries.push((iter.key().raw_ref().to_vec(), value));
+                iter.next().unwrap();
+            }
+        }
+        // a 和 L2 的基础值合并，b 下面没有基础值，唯一的操作数原样写出
+        assert_eq!(
+            entries,
+            vec![
+                (b"a".to_vec(), "put:1,2,3".to_string()),
+                (b"b".to_vec(), "merge:1".to_string()),
+            ]
+        );
+        assert_eq!(&storage.get(b"a").unwrap().unwrap()[..], b"1,2,3");
+        assert_eq!(&storage.get(b"b").unwrap().unwrap()[..], b"1");
+    }
+
+    #[test]
+    fn test_compaction_keeps_ttl_of_merge_base() {
+        let dir = tempdir().unwrap();
+        let options =
+            compaction_test_options(CompactionOptions::Leveled(LeveledCompactionOptions {
+                level_size_multiplier: 10,
+                level0_file_num_compaction_trigger: 2,
+                max_levels: 3,
+                base_level_size_mb: 1,
+            }));
+        let storage = LsmStorageInner::open(&dir, options).unwrap();
+        storage
+            .put_with_ttl(b"a", b"1", Duration::from_secs(3600))
+            .unwrap();
+        storage.flush_all_memtables().unwrap();
+        let snapshot = storage.state.read().clone();
+        let table = snapshot.sstables[&snapshot.l0_sstables[0]].clone();
+        let expire_at = value_expire_at(&table.get(b"a").unwrap().unwrap()).unwrap();
+        storage.merge(b"a", b"2").unwrap();
+        storage.flush_all_memtables().unwrap();
+        storage.trigger_compaction().unwrap();
+
+        // 合并后的值和基础值同时过期
+        let snapshot = storage.state.read().clone();
+        assert!(snapshot.l0_sstables.is_empty());
+        let table = snapshot.sstables[&snapshot.levels[0].1[0]].clone();
+        let value = table.get(b"a").unwrap().unwrap();
+        assert_eq!(value_expire_at(&value), Some(expire_at));
+        assert_eq!(&storage.get(b"a").unwrap().unwrap()[..], b"1,2");
+    }
+
+    #[test]
+    fn test_split_key_ranges() {
+        // 两个 SST 的块边界交错，还有重复的
+        let boundaries = ["a", "c", "e", "g", "b", "d", "f", "h", "c"]
+            .into_iter()
+            .map(key)
+            .collect::<Vec<_>>();
+        let ranges = split_key_ranges(boundaries.clone(), 4);
+        assert_eq!(
+            ranges,
+            vec![
+                SubcompactionRange {
+                    start: None,
+                    end: Some(key("c")),
+                },
+                SubcompactionRange {
+                    start: Some(key("c")),
+                    end: Some(key("e")),
+                },
+                SubcompactionRange {
+                    start: Some(key("e")),
+                    end: Some(key("g")),
+                },
+                SubcompactionRange {
+                    start: Some(key("g")),
+                    end: None,
+                },
+            ]
+        );
+        assert!(!ranges[0].is_past_end(KeySlice::from_slice(b"bz")));
+        assert!(ranges[0].is_past_end(KeySlice::from_slice(b"c")));
+        assert!(!ranges[3].is_past_end(KeySlice::from_slice(b"zzz")));
+
+        // 块比范围少时每个块一个范围
+        assert_eq!(split_key_ranges(boundaries[..2].to_vec(), 8).len(), 2);
+        assert_eq!(
+            split_key_ranges(Vec::new(), 8),
+            vec![SubcompactionRange {
+                start: None,
+                end: None,
+            }]
+        );
+    }
+}
@@ -0,0 +1,47 @@
+use anyhow::{anyhow, bail, Result};
+
+/// SST 数据块的压缩算法。每个块在校验和之前带一个字节的 codec id，
+/// 所以同一个数据库里可以混用不同压缩算法写出的 SST，读取时不需要知道当前的配置。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlockCompression {
+    /// 不压缩
+    #[default]
+    None,
+    /// LZ4，纯 Rust 实现（lz4_flex），不依赖系统库
+    Lz4,
+}
+
+impl BlockCompression {
+    /// 写入块末尾的 codec id
+    pub fn codec_id(self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Lz4 => 1,
+        }
+    }
+
+    pub fn from_codec_id(id: u8) -> Result<Self> {
+        match id {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Lz4),
+            _ => bail!("unknown block codec: {}", id),
+        }
+    }
+
+    /// 压缩 `Block::encode` 的输出
+    pub fn compress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::None => data.to_vec(),
+            Self::Lz4 => lz4_flex::compress_prepend_size(data),
+        }
+    }
+
+    /// 解压出 `Block::decode` 需要的数据
+    pub fn decompress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::None => Ok(data.to_vec()),
+            Self::Lz4 => lz4_flex::decompress_size_prepended(data)
+                .map_err(|e| anyhow!("failed to decompress block: {}", e)),
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+/// 数据库目录已经被另一个进程（或者本进程里的另一个实例）打开
+#[derive(Debug)]
+pub struct DatabaseInUseError {
+    pub path: PathBuf,
+}
+
+impl std::fmt::Display for DatabaseInUseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "database already in use: {:?}", self.path)
+    }
+}
+
+impl std::error::Error for DatabaseInUseError {}
+
+/// 数据库目录下 `LOCK` 文件上的排他锁，drop 时释放。
+/// 用的是 flock，进程退出（包括崩溃）时系统会自动释放。
+pub(crate) struct DbLock {
+    file: File,
+}
+
+impl DbLock {
+    /// 拿不到锁时返回可以用 `downcast_ref::<DatabaseInUseError>()` 识别的错误
+    pub fn acquire(dir: &Path) -> Result<Self> {
+        let path = dir.join("LOCK");
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("failed to open lock file {:?}", path))?;
+        match file.try_lock() {
+            Ok(()) => Ok(Self { file }),
+            Err(TryLockError::WouldBlock) => Err(DatabaseInUseError {
+                path: dir.to_path_buf(),
+            }
+            .into()),
+            Err(TryLockError::Error(e)) => {
+                Err(e).with_context(|| format!("failed to lock {:?}", path))
+            }
+        }
+    }
+}
+
+impl Drop for DbLock {
+    fn drop(&mut self) {
+        self.file.unlock().ok();
+    }
+}
@@ -0,0 +1,248 @@
+//! Linux 上绕过页缓存的读取：`O_DIRECT` 打开文件，用对齐的缓冲区读取，
+//! 多个块的读取一次性提交给 io_uring 并行执行。块只缓存在 `BlockCache` 里，不会在页缓存里再存一份。
+
+use std::{
+    alloc::{self, Layout},
+    cell::RefCell,
+    fs::File,
+    os::{fd::AsRawFd, unix::fs::FileExt, unix::fs::OpenOptionsExt},
+    path::Path,
+    ptr::NonNull,
+};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use io_uring::{opcode, types, IoUring};
+
+/// `O_DIRECT` 要求偏移量、长度和缓冲区地址都按逻辑块大小对齐，这里统一按 4 KiB 对齐
+const ALIGN: u64 = 4096;
+
+/// 一次提交给 io_uring 的最多请求数
+const RING_ENTRIES: u32 = 64;
+
+/// 按 `ALIGN` 对齐的缓冲区
+struct AlignedBuf {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// SAFETY: 缓冲区独占它的内存，和 Vec<u8> 一样可以在线程间转移
+unsafe impl Send for AlignedBuf {}
+
+impl AlignedBuf {
+    fn new(len: usize) -> Self {
+        let layout = Self::layout(len);
+        // SAFETY: layout 的大小不为 0
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        Self { ptr, len }
+    }
+
+    fn layout(len: usize) -> Layout {
+        Layout::from_size_align(len.max(ALIGN as usize), ALIGN as usize).unwrap()
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: ptr 指向 len 字节已初始化的内存
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl AsRef<[u8]> for AlignedBuf {
+    fn as_ref(&self) -> &[u8] {
+        // SAFETY: ptr 指向 len 字节已初始化的内存
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        // SAFETY: 用同样的 layout 分配
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), Self::layout(self.len)) }
+    }
+}
+
+/// 用 `O_DIRECT` 打开文件。文件系统不支持（例如 tmpfs）时返回 `None`。
+pub(crate) fn open_direct(path: &Path) -> Result<Option<File>> {
+    match File::options()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+    {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("failed to open {:?} with O_DIRECT", path)),
+    }
+}
+
+/// 把 [offset, offset + len) 扩展成对齐的区间，返回对齐后的起点、长度和原区间在其中的偏移
+fn align_range(offset: u64, len: u64) -> (u64, u64, usize) {
+    let start = offset / ALIGN * ALIGN;
+    let end = (offset + len).div_ceil(ALIGN) * ALIGN;
+    (start, end - start, (offset - start) as usize)
+}
+
+/// 从 `O_DIRECT` 打开的文件读取一段数据，返回的 `Bytes` 引用对齐的缓冲区，不再拷贝
+pub(crate) fn pread_direct(file: &File, offset: u64, len: u64) -> Result<Bytes> {
+    let (start, aligned_len, skip) = align_range(offset, len);
+    let mut buf = AlignedBuf::new(aligned_len as usize);
+    let mut filled = 0;
+    // 文件末尾不是对齐的，最后一次读取会比对齐长度短
+    while filled < skip + len as usize {
+        let n = file.read_at(&mut buf.as_mut_slice()[filled..], start + filled as u64)?;
+        if n == 0 {
+            bail!("failed to fill whole buffer");
+        }
+        filled += n;
+    }
+    Ok(Bytes::from_owner(buf).slice(skip..skip + len as usize))
+}
+
+thread_local! {
+    /// 每个线程复用一个 io_uring，创建失败（例如内核不支持或被禁用）时为 `None`
+    static RING: RefCell<Option<IoUring>> = RefCell::new(IoUring::new(RING_ENTRIES).ok());
+}
+
+/// 并行读取多段数据。`direct` 表示文件是用 `O_DIRECT` 打开的，需要对齐。
+/// 没有可用的 io_uring 时退回逐个 pread。
+pub(crate) fn read_many(file: &File, ranges: &[(u64, u64)], direct: bool) -> Result<Vec<Bytes>> {
+    let result = RING.with(|ring| {
+        let mut ring = ring.borrow_mut();
+        ring.is_some()
+            .then(|| read_many_uring(&mut ring, file, ranges, direct))
+    });
+    match result {
+        Some(result) => result,
+        None => ranges
+            .iter()
+            .map(|&(offset, len)| {
+                if direct {
+                    pread_direct(file, offset, len)
+                } else {
+                    let mut data = vec![0; len as usize];
+                    file.read_exact_at(&mut data, offset)?;
+                    Ok(data.into())
+                }
+            })
+            .collect(),
+    }
+}
+
+/// 用 io_uring 读取，`ring` 一定是 `Some`。出错时也要等所有已经提交的请求完成，内核才不会再写缓冲区，
+/// 完成队列里也不会留下属于这次调用的结果；连等待都失败时泄漏缓冲区并丢掉这个 ring，之后退回 pread
+fn read_many_uring(
+    ring: &mut Option<IoUring>,
+    file: &File,
+    ranges: &[(u64, u64)],
+    direct: bool,
+) -> Result<Vec<Bytes>> {
+    let mut result = Vec::with_capacity(ranges.len());
+    for chunk in ranges.chunks(RING_ENTRIES as usize) {
+        let uring = ring.as_mut().unwrap();
+        let mut bufs = chunk
+            .iter()
+            .map(|&(offset, len)| {
+                let (start, aligned_len, skip) = if direct {
+                    align_range(offset, len)
+                } else {
+                    (offset, len, 0)
+                };
+                (
+                    start,
+                    skip,
+                    len as usize,
+                    AlignedBuf::new(aligned_len as usize),
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut error = None;
+        let mut pushed = 0;
+        for (idx, (start, _, _, buf)) in bufs.iter_mut().enumerate() {
+            let buf = buf.as_mut_slice();
+            let entry = opcode::Read::new(
+                types::Fd(file.as_raw_fd()),
+                buf.as_mut_ptr(),
+                buf.len() as u32,
+            )
+            .offset(*start)
+            .build()
+            .user_data(idx as u64);
+            // SAFETY: 缓冲区在所有已提交的请求完成之前一直有效，见下面的等待
+            if let Err(e) = unsafe { uring.submission().push(&entry) } {
+                error = Some(anyhow::Error::from(e));
+                break;
+            }
+            pushed += 1;
+        }
+        let mut results = vec![None; bufs.len()];
+        let mut completed = 0;
+        while completed < pushed {
+            if let Err(e) = uring.submit_and_wait(pushed - completed) {
+                if e.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                // 不知道还有哪些请求没完成，内核可能还会写这些缓冲区
+                std::mem::forget(bufs);
+                *ring = None;
+                return Err(e.into());
+            }
+            for cqe in uring.completion() {
+                results[cqe.user_data() as usize] = Some(cqe.result());
+                completed += 1;
+            }
+        }
+        if let Some(e) = error {
+            return Err(e);
+        }
+        for ((_, skip, len, buf), read) in bufs.into_iter().zip(results) {
+            let read = read.unwrap();
+            if read < 0 {
+                return Err(std::io::Error::from_raw_os_error(-read).into());
+            }
+            // 读到文件末尾时会比请求的短，但必须覆盖原区间
+            if (read as usize) < skip + len {
+                bail!("short read from io_uring");
+            }
+            result.push(Bytes::from_owner(buf).slice(skip..skip + len));
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_direct_and_uring_reads() {
+        // tmpfs 不支持 O_DIRECT，这时只检查普通文件的并行读取
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("00001.sst");
+        let data = (0..20000u32).map(|i| i as u8).collect::<Vec<_>>();
+        std::fs::write(&path, &data).unwrap();
+        let (file, direct) = match open_direct(&path).unwrap() {
+            Some(file) => (file, true),
+            None => (File::open(&path).unwrap(), false),
+        };
+        let ranges = [(0, 100), (4000, 300), (8191, 2), (19990, 10)];
+        for (bytes, (offset, len)) in read_many(&file, &ranges, direct)
+            .unwrap()
+            .iter()
+            .zip(ranges)
+        {
+            assert_eq!(&bytes[..], &data[offset as usize..(offset + len) as usize]);
+        }
+        // 读到文件末尾之外时报错，所有请求的结果都被取走，不影响下一次读取
+        assert!(read_many(&file, &[(0, 100), (30000, 10)], direct).is_err());
+        assert_eq!(
+            &read_many(&file, &ranges[1..2], direct).unwrap()[0][..],
+            &data[4000..4300]
+        );
+        if direct {
+            assert_eq!(
+                &pread_direct(&file, 4095, 3).unwrap()[..],
+                &data[4095..4098]
+            );
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! 测试用的掉电模拟。记录每个文件最后一次 fsync 时的内容，以及目录最后一次 fsync 时有哪些文件；
+//! `crash` 把目录恢复成掉电后磁盘上可能剩下的样子。
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
+
+use parking_lot::Mutex;
+
+#[derive(Default)]
+struct DirState {
+    /// 目录最后一次 fsync 时存在的文件
+    durable_entries: HashSet<OsString>,
+    /// 文件最后一次 fsync 时的内容
+    synced_contents: HashMap<OsString, Vec<u8>>,
+}
+
+/// 开启了掉电模拟的目录
+static DIRS: LazyLock<Mutex<HashMap<PathBuf, DirState>>> = LazyLock::new(Default::default);
+
+fn list_dir(dir: &Path) -> HashSet<OsString> {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect()
+}
+
+pub(crate) fn on_file_synced(path: &Path) {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return;
+    };
+    let Ok(dir) = dir.canonicalize() else {
+        return;
+    };
+    if let Some(state) = DIRS.lock().get_mut(&dir) {
+        let content = std::fs::read(path).unwrap();
+        state.synced_contents.insert(name.to_owned(), content);
+    }
+}
+
+pub(crate) fn on_dir_synced(dir: &Path) {
+    let Ok(dir) = dir.canonicalize() else {
+        return;
+    };
+    if let Some(state) = DIRS.lock().get_mut(&dir) {
+        state.durable_entries = list_dir(&dir);
+        let durable_entries = &state.durable_entries;
+        state
+            .synced_contents
+            .retain(|name, _| durable_entries.contains(name));
+    }
+}
+
+/// 在一个目录上开启掉电模拟，开启时目录里已有的文件都视为已经落盘
+pub(crate) struct FaultInjectionDir {
+    dir: PathBuf,
+}
+
+impl FaultInjectionDir {
+    pub fn new(dir: &Path) -> Self {
+        let dir = dir.canonicalize().unwrap();
+        let durable_entries = list_dir(&dir);
+        let synced_contents = durable_entries
+            .iter()
+            .map(|name| (name.clone(), std::fs::read(dir.join(name)).unwrap()))
+            .collect();
+        DIRS.lock().insert(
+            dir.clone(),
+            DirState {
+                durable_entries,
+                synced_contents,
+            },
+        );
+        Self { dir }
+    }
+
+    /// 模拟掉电：目录项没有落盘的新文件消失，已经删除但目录项没有落盘的文件回来，
+    /// 文件内容回到最后一次 fsync 时的样子（从没 fsync 过的文件为空）。调用前要关闭引擎。
+    pub fn crash(&self) {
+        let dirs = DIRS.lock();
+        let state = &dirs[&self.dir];
+        for name in list_dir(&self.dir) {
+            if !state.durable_entries.contains(&name) {
+                std::fs::remove_file(self.dir.join(name)).unwrap();
+            }
+        }
+        for name in &state.durable_entries {
+            let content = state
+                .synced_contents
+                .get(name)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            std::fs::write(self.dir.join(name), content).unwrap();
+        }
+    }
+}
+
+impl Drop for FaultInjectionDir {
+    fn drop(&mut self) {
+        DIRS.lock().remove(&self.dir);
+    }
+}
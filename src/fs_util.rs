@@ -0,0 +1,32 @@
+use std::{fs::File, path::Path};
+
+use anyhow::{Context, Result};
+
+/// 把文件内容刷到磁盘
+pub(crate) fn sync_file(file: &File, path: &Path) -> Result<()> {
+    file.sync_all()
+        .with_context(|| format!("failed to sync {:?}", path))?;
+    #[cfg(test)]
+    crate::fault_injection::on_file_synced(path);
+    Ok(())
+}
+
+/// 把目录项刷到磁盘。新建或删除文件之后要调用，否则掉电后新文件可能不见了，删掉的文件可能又回来。
+pub(crate) fn sync_dir(dir: &Path) -> Result<()> {
+    // 只有 Unix 能打开目录并 fsync
+    #[cfg(unix)]
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("failed to sync dir {:?}", dir))?;
+    #[cfg(test)]
+    crate::fault_injection::on_dir_synced(dir);
+    Ok(())
+}
+
+/// 新建或删除 `path` 之后，把它所在的目录刷到磁盘
+pub(crate) fn sync_parent_dir(path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => sync_dir(dir),
+        _ => sync_dir(Path::new(".")),
+    }
+}
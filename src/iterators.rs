@@ -12,7 +12,7 @@ pub struct SstConcatIterator {
 
 
 pub trait StorageIterator {
-    type KeyType<'a>: PartialEq + Eq + PartialOrd + Ord
+    type KeyType<'a>: PartialEq + Eq + PartialOrd + Ord + Copy
     where
         Self: 'a;
 
@@ -28,6 +28,18 @@ pub trait StorageIterator {
     /// Move to the next position.
     fn next(&mut self) -> anyhow::Result<()>;
 
+    /// Move to the previous position.
+    fn prev(&mut self) -> anyhow::Result<()>;
+
+    /// Seek to the first key that is >= `key`.
+    fn seek(&mut self, key: Self::KeyType<'_>) -> anyhow::Result<()>;
+
+    /// Seek to the last key.
+    fn seek_to_last(&mut self) -> anyhow::Result<()>;
+
+    /// Seek to the last key that is <= `key`.
+    fn seek_for_prev(&mut self, key: Self::KeyType<'_>) -> anyhow::Result<()>;
+
     /// Number of underlying active iterators for this iterator.
     fn num_active_iterators(&self) -> usize {
         1
@@ -44,14 +56,31 @@ impl SstConcatIterator {
             if self.next_sst_idx >= self.sstables.len() {
                 self.current = None;
             } else {
-                // self.current = Some(SsTableIterator::create_and_seek_to_first(
-                //     self.sstables[self.next_sst_idx].clone(),
-                // )?);
+                self.current = Some(SsTableIterator::create_and_seek_to_first(
+                    self.sstables[self.next_sst_idx].clone(),
+                )?);
                 self.next_sst_idx += 1;
             }
         }
         Ok(())
     }
+    /// 反向迭代时 current 指向 sstables[next_sst_idx - 1]，用完后换成前一个 SST 的最后一个键
+    fn move_back_until_valid(&mut self) -> Result<()> {
+        while let Some(iter) = self.current.as_mut() {
+            if iter.is_valid() {
+                break;
+            }
+            if self.next_sst_idx <= 1 {
+                self.current = None;
+            } else {
+                self.next_sst_idx -= 1;
+                self.current = Some(SsTableIterator::create_and_seek_to_last(
+                    self.sstables[self.next_sst_idx - 1].clone(),
+                )?);
+            }
+        }
+        Ok(())
+    }
     fn check_sst_valid(sstables: &[Arc<SsTable>]) {
         for sst in sstables {
             assert!(sst.first_key() <= sst.last_key());
@@ -65,25 +94,12 @@ impl SstConcatIterator {
     
     pub fn create_and_seek_to_key(sstables: Vec<Arc<SsTable>>, key: KeySlice) -> Result<Self> {
         Self::check_sst_valid(&sstables);
-        let idx: usize = sstables
-            .partition_point(|table| table.first_key().as_key_slice() <= key)
-            .saturating_sub(1);
-        if idx >= sstables.len() {
-            return Ok(Self {
-                current: None,
-                next_sst_idx: sstables.len(),
-                sstables,
-            });
-        }
         let mut iter = Self {
-            current: Some(SsTableIterator::create_and_seek_to_key(
-                sstables[idx].clone(),
-                key,
-            )?),
-            next_sst_idx: idx + 1,
+            current: None,
+            next_sst_idx: 0,
             sstables,
         };
-        iter.move_until_valid()?;
+        iter.seek(key)?;
         Ok(iter)
     }
 }
@@ -114,6 +130,53 @@ impl StorageIterator for SstConcatIterator {
         Ok(())
     }
 
+    fn prev(&mut self) -> Result<()> {
+        self.current.as_mut().unwrap().prev()?;
+        self.move_back_until_valid()
+    }
+
+    fn seek(&mut self, key: KeySlice) -> Result<()> {
+        let idx = self
+            .sstables
+            .partition_point(|table| table.first_key().as_key_slice() <= key)
+            .saturating_sub(1);
+        if idx >= self.sstables.len() {
+            self.current = None;
+            self.next_sst_idx = self.sstables.len();
+            return Ok(());
+        }
+        self.current = Some(SsTableIterator::create_and_seek_to_key(
+            self.sstables[idx].clone(),
+            key,
+        )?);
+        self.next_sst_idx = idx + 1;
+        self.move_until_valid()
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.next_sst_idx = self.sstables.len();
+        self.current = match self.sstables.last() {
+            Some(table) => Some(SsTableIterator::create_and_seek_to_last(table.clone())?),
+            None => None,
+        };
+        self.move_back_until_valid()
+    }
+
+    fn seek_for_prev(&mut self, key: KeySlice) -> Result<()> {
+        let idx = self
+            .sstables
+            .partition_point(|table| table.first_key().as_key_slice() <= key);
+        self.next_sst_idx = idx;
+        self.current = match idx {
+            0 => None,
+            _ => Some(SsTableIterator::create_and_seek_for_prev(
+                self.sstables[idx - 1].clone(),
+                key,
+            )?),
+        };
+        self.move_back_until_valid()
+    }
+
     fn num_active_iterators(&self) -> usize {
         1
     }
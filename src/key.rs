@@ -43,6 +43,11 @@ impl Key<Vec<u8>> {
         self.0.clear()
     }
 
+    /// 只保留键的前 `len` 个字节
+    pub fn truncate(&mut self, len: usize) {
+        self.0.truncate(len)
+    }
+
     /// 将切片附加到键的末尾
     pub fn append(&mut self, data: &[u8]) {
         self.0.extend(data)
@@ -0,0 +1,242 @@
+use std::{collections::BTreeSet, path::Path, sync::Arc};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    fs_util::sync_dir,
+    lsm_storage::{LsmStorageInner, OpenMode},
+    sstable::SsTable,
+};
+
+/// MANIFEST 里还引用着的文件，以及已经不再引用、等待删除的文件。
+/// 和 MANIFEST 的记录保持一致：`NewMemtable` 加入一个 WAL，`Flush` 把 WAL 换成同 id 的 SST。
+#[derive(Default)]
+pub(crate) struct LiveFiles {
+    /// 还在某一层里的 SST
+    pub(crate) ssts: BTreeSet<usize>,
+    /// 对应的 memtable 还没刷新的 WAL
+    pub(crate) wals: BTreeSet<usize>,
+    /// 被压缩替换掉的 SST，迭代器和快照都不再引用它们之后才删除
+    obsolete_tables: Vec<Arc<SsTable>>,
+    /// memtable 已经刷新到 SST 的 WAL
+    obsolete_wals: Vec<usize>,
+}
+
+impl LiveFiles {
+    pub(crate) fn new(ssts: BTreeSet<usize>, wals: BTreeSet<usize>) -> Self {
+        Self {
+            ssts,
+            wals,
+            ..Default::default()
+        }
+    }
+
+    /// 删除目录里不被 MANIFEST 引用的 SST 和 WAL，比如刷新或压缩写到一半崩溃留下的文件，
+    /// 以及不在 `live_blobs` 里的 blob 文件。其他文件（MANIFEST、LOCK 等）不动。返回删除的文件数。
+    pub(crate) fn purge_orphans(&self, dir: &Path, live_blobs: &BTreeSet<usize>) -> Result<usize> {
+        let mut removed = 0;
+        for entry in std::fs::read_dir(dir).context("failed to list DB dir")? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let orphan = match name.split_once('.') {
+                Some((id, "sst")) => id.parse().is_ok_and(|id| !self.ssts.contains(&id)),
+                Some((id, "wal")) => id.parse().is_ok_and(|id| !self.wals.contains(&id)),
+                Some((id, "blob")) => id.parse().is_ok_and(|id| !live_blobs.contains(&id)),
+                _ => false,
+            };
+            if orphan {
+                std::fs::remove_file(&path)
+                    .with_context(|| format!("failed to remove orphan file {:?}", path))?;
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            sync_dir(dir)?;
+        }
+        Ok(removed)
+    }
+}
+
+impl LsmStorageInner {
+    /// 压缩把 `table` 从各层移除、写进 MANIFEST 之后调用，最后一个引用释放后文件才被删除
+    pub(crate) fn retire_sst(&self, table: Arc<SsTable>) {
+        let mut live_files = self.live_files.lock();
+        live_files.ssts.remove(&table.sst_id());
+        live_files.obsolete_tables.push(table);
+    }
+
+    /// memtable 刷新到 SST、写进 MANIFEST 之后调用，它的 WAL 不再需要
+    pub(crate) fn retire_wal(&self, id: usize) {
+        let mut live_files = self.live_files.lock();
+        live_files.wals.remove(&id);
+        // 没有WAL时没有文件要删除
+        if self.options.enable_wal {
+            live_files.obsolete_wals.push(id);
+        }
+    }
+
+    /// MANIFEST 当前引用的 SST 和 WAL 的 id
+    pub(crate) fn live_file_ids(&self) -> (BTreeSet<usize>, BTreeSet<usize>) {
+        let live_files = self.live_files.lock();
+        (live_files.ssts.clone(), live_files.wals.clone())
+    }
+
+    /// 删除已经过时的 WAL，只剩这里一个引用的过时 SST，以及 GC 换掉指针后不再有读者的 blob 文件。
+    /// 还被迭代器或快照引用的文件留到下次再检查。返回删除的文件数。
+    pub fn delete_obsolete_files(&self) -> Result<usize> {
+        if self.mode != OpenMode::ReadWrite {
+            return Ok(0);
+        }
+        // 正在创建检查点，留到下次再删除
+        let Some(_guard) = self.file_deletion_lock.try_read() else {
+            return Ok(0);
+        };
+        let (tables, wals) = {
+            let mut live_files = self.live_files.lock();
+            let (unreferenced, referenced) = std::mem::take(&mut live_files.obsolete_tables)
+                .into_iter()
+                .partition::<Vec<_>, _>(|table| Arc::strong_count(table) == 1);
+            live_files.obsolete_tables = referenced;
+            (unreferenced, std::mem::take(&mut live_files.obsolete_wals))
+        };
+        let mut removed = 0;
+        for table in tables {
+            let id = table.sst_id();
+            // 先关闭文件句柄再删除
+            drop(table);
+            if let Some(table_cache) = &self.table_cache {
+                table_cache.evict(id);
+            }
+            std::fs::remove_file(Self::path_of_sst_static(&self.path, id))
+                .with_context(|| format!("failed to remove obsolete SST {}", id))?;
+            removed += 1;
+        }
+        for id in wals {
+            std::fs::remove_file(self.path_of_wal(id))
+                .with_context(|| format!("failed to remove obsolete WAL {}", id))?;
+            removed += 1;
+        }
+        removed += self.blob_store.delete_retired_files()?;
+        if removed > 0 {
+            self.sync_dir()?;
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tempfile::tempdir;
+
+    use crate::{
+        lsm_storage::{LsmStorageInner, LsmStorageOptions, LsmStorageState},
+        sstable::{FileObject, SsTable},
+    };
+
+    #[test]
+    fn test_purge_orphan_files() {
+        let dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.enable_wal = true;
+        options.large_value_threshold = Some(64);
+        let storage = LsmStorageInner::open(&dir, options.clone()).unwrap();
+        storage.put(b"1", b"1").unwrap();
+        storage.put(b"large", &[b'x'; 100]).unwrap();
+        let (_, wals) = storage.live_file_ids();
+        let (_, live_blob) = storage.blob_store.file_ids().unwrap();
+        let live_blob = LsmStorageInner::path_of_blob_static(&dir, live_blob.unwrap());
+        drop(storage);
+
+        // 刷新写到一半崩溃留下的SST，以及不在MANIFEST里的WAL
+        let orphan_sst = LsmStorageInner::path_of_sst_static(&dir, 99);
+        SsTable::write_for_testing(&orphan_sst, &[(b"1", b"1")]).unwrap();
+        let orphan_wal = LsmStorageInner::path_of_wal_static(&dir, 98);
+        std::fs::write(&orphan_wal, b"").unwrap();
+        // GC重写了有效的值之后、删除旧文件之前崩溃留下的blob文件
+        let orphan_blob = LsmStorageInner::path_of_blob_static(&dir, 90);
+        std::fs::write(&orphan_blob, b"").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), b"").unwrap();
+
+        let storage = LsmStorageInner::open(&dir, options).unwrap();
+        assert!(!orphan_sst.exists());
+        assert!(!orphan_wal.exists());
+        assert!(!orphan_blob.exists());
+        assert!(live_blob.exists());
+        assert!(dir.path().join("notes.txt").exists());
+        for id in wals {
+            assert!(LsmStorageInner::path_of_wal_static(&dir, id).exists());
+        }
+        assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"1");
+        assert_eq!(&storage.get(b"large").unwrap().unwrap()[..], &[b'x'; 100]);
+    }
+
+    #[test]
+    fn test_delete_obsolete_files() {
+        let dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.enable_wal = true;
+        let storage = LsmStorageInner::open(&dir, options).unwrap();
+
+        // 假装刷新出了一个SST
+        let id = storage.next_sst_id();
+        let path = LsmStorageInner::path_of_sst_static(&dir, id);
+        SsTable::write_for_testing(&path, &[(b"1", b"1")]).unwrap();
+        let table = Arc::new(SsTable::open(id, None, FileObject::open(&path).unwrap()).unwrap());
+        {
+            let mut guard = storage.state.write();
+            let mut snapshot = LsmStorageState::clone(&guard);
+            snapshot.l0_sstables.insert(0, id);
+            snapshot.sstables.insert(id, table.clone());
+            *guard = Arc::new(snapshot);
+        }
+        storage.live_files.lock().ssts.insert(id);
+        let reader = storage.state.read().clone();
+
+        // 假装压缩把它移除了，读者还拿着旧快照
+        {
+            let mut guard = storage.state.write();
+            let mut snapshot = LsmStorageState::clone(&guard);
+            snapshot.l0_sstables.clear();
+            snapshot.sstables.clear();
+            *guard = Arc::new(snapshot);
+        }
+        storage.retire_sst(table);
+        assert_eq!(storage.delete_obsolete_files().unwrap(), 0);
+        assert!(path.exists());
+        drop(reader);
+        assert_eq!(storage.delete_obsolete_files().unwrap(), 1);
+        assert!(!path.exists());
+        assert!(!storage.live_file_ids().0.contains(&id));
+
+        // 写入后memtable被冻结，假装它刷新了
+        storage.put(b"1", b"1").unwrap();
+        let wal_id = storage.state.read().imm_memtables[0].id();
+        let wal = storage.path_of_wal(wal_id);
+        assert!(wal.exists());
+        storage.retire_wal(wal_id);
+        assert_eq!(storage.delete_obsolete_files().unwrap(), 1);
+        assert!(!wal.exists());
+    }
+
+    #[test]
+    fn test_flush_retires_wal() {
+        let dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.enable_wal = true;
+        let storage = LsmStorageInner::open(&dir, options.clone()).unwrap();
+        storage.put(b"1", b"1").unwrap();
+        let wal_id = storage.state.read().imm_memtables[0].id();
+        let wal = storage.path_of_wal(wal_id);
+        storage.flush_all_memtables().unwrap();
+        assert!(!storage.live_file_ids().1.contains(&wal_id));
+        assert!(storage.delete_obsolete_files().unwrap() >= 1);
+        assert!(!wal.exists());
+        drop(storage);
+        let storage = LsmStorageInner::open(&dir, options).unwrap();
+        assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"1");
+    }
+}
@@ -0,0 +1,203 @@
+use std::{ops::Bound, sync::Arc};
+
+use anyhow::Result;
+use bytes::Bytes;
+
+use crate::{
+    iterators::{SstConcatIterator, StorageIterator},
+    lsm_storage::{LsmStorageState, MergeIterator, SsTableIterator, ValueResolver},
+    memtable::MemTableIterator,
+    two_merge_iterator::TwoMergeIterator,
+    key::KeySlice,
+    value::{decode_value, now_ms, ValueRef},
+};
+
+/// memtable、L0 和各层 SST 合并后的迭代器
+type LsmIteratorInner = TwoMergeIterator<
+    TwoMergeIterator<MergeIterator<MemTableIterator>, MergeIterator<SsTableIterator>>,
+    MergeIterator<SstConcatIterator>,
+>;
+
+/// 面向用户的迭代器：跳过墓碑和过期的值，去掉值头部，读出 blob 里的大值，
+/// 把合并操作数和更旧的版本合并，只返回 `lower` 和 `upper` 之间的键。
+pub struct LsmIterator {
+    inner: LsmIteratorInner,
+    lower: Bound<Bytes>,
+    upper: Bound<Bytes>,
+    /// 创建迭代器时的快照，遇到合并操作数时在上面回查更旧的版本
+    snapshot: Arc<LsmStorageState>,
+    resolver: ValueResolver,
+    /// 当前值去掉头部后在 `inner.value()` 中的起始位置
+    value_offset: usize,
+    /// 当前值保存在 blob 文件里或者由合并操作数合并而来时，解析出的值
+    resolved_value: Option<Bytes>,
+    now: u64,
+    is_valid: bool,
+}
+
+impl LsmIterator {
+    pub(crate) fn new(
+        iter: LsmIteratorInner,
+        lower: Bound<Bytes>,
+        upper: Bound<Bytes>,
+        snapshot: Arc<LsmStorageState>,
+        resolver: ValueResolver,
+    ) -> Result<Self> {
+        let mut iter = Self {
+            inner: iter,
+            lower,
+            upper,
+            snapshot,
+            resolver,
+            value_offset: 0,
+            resolved_value: None,
+            now: now_ms(),
+            is_valid: false,
+        };
+        iter.skip_excluded_lower()?;
+        iter.move_to_live(false)?;
+        Ok(iter)
+    }
+
+    fn below_lower(&self, key: &[u8]) -> bool {
+        match &self.lower {
+            Bound::Included(lower) => key < &lower[..],
+            Bound::Excluded(lower) => key <= &lower[..],
+            Bound::Unbounded => false,
+        }
+    }
+
+    fn above_upper(&self, key: &[u8]) -> bool {
+        match &self.upper {
+            Bound::Included(upper) => key > &upper[..],
+            Bound::Excluded(upper) => key >= &upper[..],
+            Bound::Unbounded => false,
+        }
+    }
+
+    //SST迭代器定位在不包含的下界上时往后走一步
+    fn skip_excluded_lower(&mut self) -> Result<()> {
+        if let Bound::Excluded(lower) = &self.lower {
+            if self.inner.is_valid() && self.inner.key().raw_ref() == &lower[..] {
+                self.inner.next()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 跳过墓碑直到一个活着的键，`backward` 时往前跳
+    fn move_to_live(&mut self, backward: bool) -> Result<()> {
+        self.resolved_value = None;
+        loop {
+            if !self.inner.is_valid()
+                || self.below_lower(self.inner.key().raw_ref())
+                || self.above_upper(self.inner.key().raw_ref())
+            {
+                self.is_valid = false;
+                return Ok(());
+            }
+            let value = self.inner.value();
+            match decode_value(value, self.now)? {
+                ValueRef::Deleted => {}
+                ValueRef::Put(data) => {
+                    self.value_offset = value.len() - data.len();
+                    self.is_valid = true;
+                    return Ok(());
+                }
+                ValueRef::Blob(ptr) => {
+                    self.resolved_value = Some(self.resolver.read_blob(ptr)?);
+                    self.is_valid = true;
+                    return Ok(());
+                }
+                // 合并迭代器只给出最新的版本，在快照上回查这个键，和更旧的版本合并
+                ValueRef::Merge(_) => {
+                    let key = self.inner.key().raw_ref();
+                    self.resolved_value = self.resolver.get(&self.snapshot, key, self.now)?;
+                    if self.resolved_value.is_some() {
+                        self.is_valid = true;
+                        return Ok(());
+                    }
+                }
+            }
+            if backward {
+                self.inner.prev()?;
+            } else {
+                self.inner.next()?;
+            }
+        }
+    }
+}
+
+impl StorageIterator for LsmIterator {
+    type KeyType<'a> = &'a [u8];
+
+    fn is_valid(&self) -> bool {
+        self.is_valid
+    }
+
+    fn key(&self) -> &[u8] {
+        self.inner.key().raw_ref()
+    }
+
+    fn value(&self) -> &[u8] {
+        match &self.resolved_value {
+            Some(value) => value,
+            None => &self.inner.value()[self.value_offset..],
+        }
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.inner.next()?;
+        self.move_to_live(false)
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        self.inner.prev()?;
+        self.move_to_live(true)
+    }
+
+    /// 定位到范围里 >= `key` 的第一个键
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        match &self.lower {
+            Bound::Included(lower) | Bound::Excluded(lower) if key <= &lower[..] => {
+                let lower = lower.clone();
+                self.inner.seek(KeySlice::from_slice(&lower))?;
+                self.skip_excluded_lower()?;
+            }
+            _ => self.inner.seek(KeySlice::from_slice(key))?,
+        }
+        self.move_to_live(false)
+    }
+
+    /// 定位到范围里的最后一个键
+    fn seek_to_last(&mut self) -> Result<()> {
+        match &self.upper {
+            Bound::Included(upper) => {
+                let upper = upper.clone();
+                self.inner.seek_for_prev(KeySlice::from_slice(&upper))?;
+            }
+            Bound::Excluded(upper) => {
+                let upper = upper.clone();
+                self.inner.seek_for_prev(KeySlice::from_slice(&upper))?;
+                if self.inner.is_valid() && self.inner.key().raw_ref() == &upper[..] {
+                    self.inner.prev()?;
+                }
+            }
+            Bound::Unbounded => self.inner.seek_to_last()?,
+        }
+        self.move_to_live(true)
+    }
+
+    /// 定位到范围里 <= `key` 的最后一个键
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        if self.above_upper(key) {
+            return self.seek_to_last();
+        }
+        self.inner.seek_for_prev(KeySlice::from_slice(key))?;
+        self.move_to_live(true)
+    }
+
+    fn num_active_iterators(&self) -> usize {
+        self.inner.num_active_iterators()
+    }
+}
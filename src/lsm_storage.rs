@@ -1,25 +1,30 @@
 use std::{
     cmp,
-    collections::{BTreeSet, BinaryHeap, HashMap},
+    collections::{binary_heap::PeekMut, BTreeMap, BTreeSet, BinaryHeap, HashMap, VecDeque},
     fs::{File, OpenOptions},
-    io::{Read, Write},
+    io::{Read, Seek, SeekFrom, Write},
+    ops::Bound,
     path::{Path, PathBuf},
-    sync::{atomic::AtomicUsize, Arc},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize},
+        Arc,
+    },
+    time::Duration,
 };
 
 use bytes::{Buf, BufMut, Bytes};
 use parking_lot::{Mutex, MutexGuard, RwLock};
 
-pub type BlockCache = moka::sync::Cache<(usize, usize), Arc<Block>>;
 use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    block::{Block, BlockIterator, SIZEOF_U16}, compact::{
-        CompactionController, LeveledCompactionController, LeveledCompactionOptions,
-        SimpleLeveledCompactionController, SimpleLeveledCompactionOptions,
-        TieredCompactionController, TieredCompactionOptions,
-    }, iterators::{SstConcatIterator, StorageIterator}, key::{KeySlice, KeyVec}, sstable::{FileObject, SsTable}, two_merge_iterator::TwoMergeIterator, MemTable
+    blob::{BlobPointer, BlobStore}, block::{Block, BlockIterator}, block_cache::{BlockCache, BlockCacheHandle}, compression::BlockCompression, db_lock::DbLock, live_files::LiveFiles, fs_util::{sync_dir, sync_file, sync_parent_dir}, column_family::{
+        ColumnFamily, ColumnFamilyOptions, DEFAULT_CF_ID, DEFAULT_CF_NAME,
+    }, compact::{
+        CompactionController, CompactionTask, LeveledCompactionOptions, SimpleLeveledCompactionOptions,
+        TieredCompactionOptions,
+    }, iterators::{SstConcatIterator, StorageIterator}, key::{KeySlice, KeyVec}, sstable::{FileObject, SsTable, SsTableBuilder, TableOpenOptions}, table_cache::TableCache, two_merge_iterator::TwoMergeIterator, write_stall::WriteController, rate_limiter::{IoPriority, RateLimiter}, value::{decode_value, encode_blob_pointer, encode_merge_operand, encode_value, expire_at_ms, now_ms, value_expire_at, ValueRef}, merge_operator::MergeOperator, lsm_iterator::LsmIterator, prefix_extractor::PrefixExtractor, MemTable
 };
 
 /// LSM树的存储接口。
@@ -27,14 +32,39 @@ use crate::{
 pub(crate) struct LsmStorageInner {
     // parking_lot里的RwLock更好用
     pub(crate) state: Arc<RwLock<Arc<LsmStorageState>>>,
+    /// 所有列族（包括默认列族，它的状态就是 `state`），按id排序
+    pub(crate) column_families: RwLock<BTreeMap<usize, Arc<ColumnFamily>>>,
+    pub(crate) next_cf_id: AtomicUsize,
     pub(crate) state_lock: Mutex<()>,
-    path: PathBuf,
-    //先不锁定缓存
-    pub(crate) block_cache: Arc<BlockCache>,
+    /// 同一时间只有一个刷新
+    flush_lock: Mutex<()>,
+    pub(crate) path: PathBuf,
+    //块缓存，可能和其他引擎共享
+    pub(crate) block_cache: BlockCacheHandle,
+    /// 超过 `large_value_threshold` 的值所在的 blob 文件
+    pub(crate) blob_store: Arc<BlobStore>,
+    /// 写入时加读锁，blob GC 检查并重写有效值时加写锁
+    pub(crate) blob_gc_lock: RwLock<()>,
+    /// 设置了 `max_open_files` 时，SST 的文件句柄通过它按需打开
+    pub(crate) table_cache: Option<Arc<TableCache>>,
     next_sst_id: AtomicUsize,
     pub(crate) options: Arc<LsmStorageOptions>,
-    pub(crate) compaction_controller: CompactionController,
     pub(crate) manifest: Option<Manifest>,
+    /// 数据库目录上的排他锁，关闭时释放
+    pub(crate) db_lock: Mutex<Option<DbLock>>,
+    pub(crate) mode: OpenMode,
+    /// 从实例跟踪主实例MANIFEST的位置，其他方式打开时为空
+    pub(crate) manifest_tail: Option<Mutex<ManifestTail>>,
+    /// MANIFEST引用的文件和等待删除的文件
+    pub(crate) live_files: Mutex<LiveFiles>,
+    /// 创建检查点时加写锁暂停删除文件，删除文件时用 `try_read`，拿不到就留到下次
+    pub(crate) file_deletion_lock: RwLock<()>,
+    /// `MiniLsm::close` 释放目录锁之后为 true，之后所有写入都返回错误
+    pub(crate) closed: AtomicBool,
+    /// 刷新或压缩落后时让写入者减速或阻塞
+    pub(crate) write_controller: WriteController,
+    /// 刷新、压缩和 blob GC 写文件时共用的限速器
+    pub(crate) rate_limiter: Arc<RateLimiter>,
     // #[allow(dead_code)]
     // // pub(crate) mvcc: Option<LsmMvccInner>,
     // #[allow(dead_code)]
@@ -44,28 +74,35 @@ pub(crate) struct LsmStorageInner {
 
 pub struct Manifest {
     file: Arc<Mutex<File>>,
+    pub(crate) path: PathBuf,
 }
 
 //源文件
 #[derive(Serialize, Deserialize)]
 pub enum ManifestRecord {
-    Flush(usize),
+    /// 刷新了一个memtable（即一个WAL）：memtable的id，各列族刷新出的 (列族id, SST id)
+    Flush(usize, Vec<(usize, usize)>),
     NewMemtable(usize),
-    // Compaction(CompactionTask, Vec<usize>),
+    CreateColumnFamily(usize, String, ColumnFamilyOptions),
+    DropColumnFamily(usize),
+    /// 列族的一次压缩：列族id，压缩任务，输出的SST
+    Compaction(usize, CompactionTask, Vec<usize>),
 }
 //创建文件
 impl Manifest {
     //创建并写入
     pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .read(true)
+            .create_new(true)
+            .write(true)
+            .open(path)
+            .context("failed to create manifest")?;
+        sync_parent_dir(path)?;
         Ok(Self {
-            file: Arc::new(Mutex::new(
-                OpenOptions::new()
-                    .read(true)
-                    .create_new(true)
-                    .write(true)
-                    .open(path)
-                    .context("failed to create manifest")?,
-            )),
+            file: Arc::new(Mutex::new(file)),
+            path: path.to_path_buf(),
         })
     }
 
@@ -77,12 +114,12 @@ impl Manifest {
         file.write_all(&(buf.len() as u64).to_be_bytes())?;
         buf.put_u32(hash);
         file.write_all(&buf)?;
-        file.sync_all()?;
-        Ok(())
+        sync_file(&file, &self.path)
     }
     //
     pub fn recover(path: impl AsRef<Path>) -> Result<(Self, Vec<ManifestRecord>)> {
         tracing::info!("recover方法 入参{:?}", path.as_ref());
+        let path = path.as_ref();
         let mut file = OpenOptions::new()
             .read(true)
             .append(true)
@@ -106,6 +143,7 @@ impl Manifest {
         Ok((
             Self {
                 file: Arc::new(Mutex::new(file)),
+                path: path.to_path_buf(),
             },
             records,
         ))
@@ -119,6 +157,64 @@ impl Manifest {
         self.add_record_when_init(record)
     }
 
+    /// 只读地读取 `offset` 之后完整的记录，返回这些记录和下次开始读取的位置。
+    /// 主实例可能正在追加，末尾不完整的记录留到下次再读。
+    pub fn read_records_from(
+        path: impl AsRef<Path>,
+        offset: u64,
+    ) -> Result<(Vec<ManifestRecord>, u64)> {
+        let mut file = File::open(path.as_ref()).context("failed to read manifest")?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        let mut buf_ptr = buf.as_slice();
+        let mut records = Vec::new();
+        while buf_ptr.remaining() >= 8 {
+            let len = (&buf_ptr[..8]).get_u64() as usize;
+            if buf_ptr.remaining() < 8 + len + 4 {
+                break;
+            }
+            buf_ptr.advance(8);
+            let slice = &buf_ptr[..len];
+            let json = serde_json::from_slice::<ManifestRecord>(slice)?;
+            buf_ptr.advance(len);
+            let checksum = buf_ptr.get_u32();
+            if checksum != crc32fast::hash(slice) {
+                bail!("checksum mismatched!");
+            }
+            records.push(json);
+        }
+        let consumed = buf.len() - buf_ptr.remaining();
+        Ok((records, offset + consumed as u64))
+    }
+}
+
+/// 打开数据库的方式
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum OpenMode {
+    /// 唯一的写入者，持有数据库目录的LOCK
+    ReadWrite,
+    /// 只读，不创建也不修改任何文件，不加锁，可以和写入者同时打开
+    ReadOnly,
+    /// 只读，并定期跟上主实例的MANIFEST和WAL。参数是从实例自己的目录，里面只有它的LOCK
+    Secondary(PathBuf),
+}
+
+/// 从实例读到的主实例MANIFEST位置
+pub(crate) struct ManifestTail {
+    /// 下次从这里继续读
+    pub(crate) offset: u64,
+    /// 还没刷新的memtable，它们的WAL按id增量重放
+    pub(crate) wals: BTreeMap<usize, ReplayedWal>,
+}
+
+/// 从实例重放到一个WAL的什么位置
+#[derive(Clone, Default)]
+pub(crate) struct ReplayedWal {
+    /// 下次从这里继续重放
+    pub(crate) offset: u64,
+    /// 各列族从这个WAL重放出的memtable，按列族id
+    pub(crate) memtables: BTreeMap<usize, Arc<MemTable>>,
 }
 impl LsmStorageInner {
     pub(crate) fn next_sst_id(&self) -> usize {
@@ -127,34 +223,60 @@ impl LsmStorageInner {
     }
        // 启动存储引擎，要么加载一个现有目录，要么创建一个新目录,目录为数据加载区
     pub(crate) fn open(path: impl AsRef<Path>, options: LsmStorageOptions) -> Result<Self> {
+        Self::open_with_mode(path, options, OpenMode::ReadWrite)
+    }
+    /// 按 `mode` 打开。只读和从实例要求数据库已经存在，恢复时不写MANIFEST、不创建WAL
+    pub(crate) fn open_with_mode(
+        path: impl AsRef<Path>,
+        options: LsmStorageOptions,
+        mode: OpenMode,
+    ) -> Result<Self> {
+        let read_only = mode != OpenMode::ReadWrite;
         tracing::info!("options数据为 {:?}", options);
         tracing::info!("path数据为{:?}", path.as_ref());
         //先创建一个资源
-        let mut state = LsmStorageState::create(&options);
+        let mut state = LsmStorageState::create(&options.compaction_options);
+        //默认列族以外的列族，按id排序
+        let mut cf_states = BTreeMap::<usize, (String, ColumnFamilyOptions, LsmStorageState)>::new();
+        let mut next_cf_id = DEFAULT_CF_ID + 1;
         let path = path.as_ref();
         let mut next_sst_id = 1;
-        // 4GB block cache,
-        let block_cache = Arc::new(BlockCache::new(1 << 20)); 
-        let manifest;
-        let compaction_controller = match &options.compaction_options {
-            CompactionOptions::Leveled(options) => {
-                CompactionController::Leveled(LeveledCompactionController::new(options.clone()))
-            }
-            CompactionOptions::Tiered(options) => {
-                CompactionController::Tiered(TieredCompactionController::new(options.clone()))
-            }
-            CompactionOptions::Simple(options) => CompactionController::Simple(
-                SimpleLeveledCompactionController::new(options.clone()),
-            ),
-            CompactionOptions::NoCompaction => CompactionController::NoCompaction,
+        let block_cache = match &options.block_cache {
+            Some(block_cache) => block_cache.handle(),
+            None => Arc::new(BlockCache::new(options.block_cache_capacity_bytes)).handle(),
         };
+        let table_cache = options
+            .max_open_files
+            .map(|max_open_files| Arc::new(TableCache::new(max_open_files)));
+        let manifest;
+        let mut manifest_tail = None;
+        let mut manifest_offset = 0;
+        // 和MANIFEST一致的未刷新memtable（即WAL）的id
+        let mut live_wals = BTreeSet::new();
         if !path.exists() {
+            if read_only {
+                bail!("database {:?} does not exist", path);
+            }
             std::fs::create_dir_all(path).context("failed to create DB dir")?;
+            sync_parent_dir(path)?;
         }
+        // 先拿到锁，再读写MANIFEST和WAL
+        let db_lock = match &mode {
+            OpenMode::ReadWrite => Some(DbLock::acquire(path)?),
+            OpenMode::ReadOnly => None,
+            OpenMode::Secondary(secondary_path) => {
+                std::fs::create_dir_all(secondary_path)
+                    .context("failed to create secondary dir")?;
+                Some(DbLock::acquire(secondary_path)?)
+            }
+        };
         let manifest_path = path.join("MANIFEST");
         tracing::info!("manifest_path 数据为 {:?}", manifest_path);
 
         if !manifest_path.exists() {
+            if read_only {
+                bail!("MANIFEST not found in {:?}", path);
+            }
             tracing::info!("test001");
             if options.enable_wal {
                 state.memtable = Arc::new(MemTable::create_with_wal(
@@ -163,73 +285,119 @@ impl LsmStorageInner {
                 )?);
             }
             tracing::info!("test0011,{:?}", manifest_path);
-            manifest = Manifest::create(&manifest_path).context("failed to create manifest")?;
-            manifest.add_record_when_init(ManifestRecord::NewMemtable(state.memtable.id()))?;
+            let m = Manifest::create(&manifest_path).context("failed to create manifest")?;
+            m.add_record_when_init(ManifestRecord::NewMemtable(state.memtable.id()))?;
+            live_wals.insert(state.memtable.id());
+            manifest = Some(m);
             tracing::info!("test002 manifest数据为");
         } else {
-            let (m, records) = Manifest::recover(&manifest_path)?;
-            tracing::info!("recover返回的数据为{:?}", m.file);
+            let (m, records) = if read_only {
+                let (records, offset) = Manifest::read_records_from(&manifest_path, 0)?;
+                manifest_offset = offset;
+                (None, records)
+            } else {
+                let (m, records) = Manifest::recover(&manifest_path)?;
+                tracing::info!("recover返回的数据为{:?}", m.file);
+                (Some(m), records)
+            };
 
             let mut memtables = BTreeSet::new();
             for record in records {
                 match record {
-                    ManifestRecord::Flush(sst_id) => {
-                        let res = memtables.remove(&sst_id);
+                    ManifestRecord::Flush(memtable_id, tables) => {
+                        let res = memtables.remove(&memtable_id);
                         assert!(res, "memtable not exist?");
-                        if compaction_controller.flush_to_l0() {
-                            state.l0_sstables.insert(0, sst_id);
-                        } else {
-                            state.levels.insert(0, (sst_id, vec![sst_id]));
+                        for (cf_id, sst_id) in tables {
+                            if let Some((compaction_options, cf_state)) =
+                                Self::recovering_cf(&options, &mut state, &mut cf_states, cf_id)
+                            {
+                                let controller = CompactionController::new(compaction_options);
+                                cf_state.add_flushed_sst(sst_id, controller.flush_to_l0());
+                            }
+                            next_sst_id = next_sst_id.max(sst_id);
                         }
-                        next_sst_id = next_sst_id.max(sst_id);
                     }
                     ManifestRecord::NewMemtable(x) => {
                         next_sst_id = next_sst_id.max(x);
                         memtables.insert(x);
-                    } //     ManifestRecord::Compaction(task, output) => {
-                      //         let (new_state, _) =
-                      //             compaction_controller.apply_compaction_result(&state, &task, &output);
-                      //         // TODO: apply remove again
-                      //         state = new_state;
-                      //         next_sst_id =
-                      //             next_sst_id.max(output.iter().max().copied().unwrap_or_default());
-                      //     }
+                    }
+                    ManifestRecord::CreateColumnFamily(id, name, cf_options) => {
+                        let cf_state = LsmStorageState::create(&cf_options.compaction_options);
+                        cf_states.insert(id, (name, cf_options, cf_state));
+                        next_cf_id = next_cf_id.max(id + 1);
+                    }
+                    ManifestRecord::DropColumnFamily(id) => {
+                        cf_states.remove(&id);
+                    }
+                    ManifestRecord::Compaction(cf_id, task, output) => {
+                        if let Some((compaction_options, cf_state)) =
+                            Self::recovering_cf(&options, &mut state, &mut cf_states, cf_id)
+                        {
+                            let (new_state, _) = CompactionController::new(compaction_options)
+                                .apply_compaction_result(cf_state, &task, &output);
+                            *cf_state = new_state;
+                        }
+                        next_sst_id =
+                            next_sst_id.max(output.iter().max().copied().unwrap_or_default());
+                    }
                 }
             }
 
             let mut sst_cnt = 0;
-            // recover SSTs
-            for table_id in state
-                .l0_sstables
-                .iter()
-                .chain(state.levels.iter().flat_map(|(_, files)| files))
-            {
-                let table_id = *table_id;
-                let sst = SsTable::open(
-                    table_id,
-                    Some(block_cache.clone()),
-                    FileObject::open(&Self::path_of_sst_static(path, table_id))
-                        .with_context(|| format!("failed to open SST: {}", table_id))?,
-                )?;
-                state.sstables.insert(table_id, Arc::new(sst));
-                sst_cnt += 1;
+            // recover SSTs，每个列族的都要打开。从实例读完MANIFEST之后主实例可能已经删掉了其中的SST，
+            // 打开后跟上主实例时再打开，那里会读出换掉它们的记录
+            let all_states = std::iter::once(&mut state)
+                .chain(cf_states.values_mut().map(|(_, _, cf_state)| cf_state))
+                .filter(|_| !matches!(mode, OpenMode::Secondary(_)));
+            for cf_state in all_states {
+                for table_id in cf_state
+                    .l0_sstables
+                    .iter()
+                    .chain(cf_state.levels.iter().flat_map(|(_, files)| files))
+                {
+                    let table_id = *table_id;
+                    let sst = SsTable::open_with_options(
+                        table_id,
+                        Some(block_cache.clone()),
+                        Self::open_sst_file(path, table_id, &options, table_cache.as_ref())
+                            .with_context(|| format!("failed to open SST: {}", table_id))?,
+                        options.table_open_options(cf_state.l0_sstables.contains(&table_id)),
+                    )?;
+                    cf_state.sstables.insert(table_id, Arc::new(sst));
+                    sst_cnt += 1;
+                }
+                cf_state.sort_levels();
             }
             println!("{} SSTs opened", sst_cnt);
 
+            // blob文件的id不记在MANIFEST里，要跳过目录里已有的blob文件
+            next_sst_id = next_sst_id.max(BlobStore::max_file_id(path)?);
             next_sst_id += 1;
 
-            // recover memtables
-            if options.enable_wal {
+            // recover memtables。从实例打开后和跟上主实例时一样增量重放
+            if options.enable_wal && !matches!(mode, OpenMode::Secondary(_)) {
                 let mut wal_cnt = 0;
+                let cf_ids = std::iter::once(DEFAULT_CF_ID)
+                    .chain(cf_states.keys().copied())
+                    .collect::<Vec<_>>();
                 for id in memtables.iter() {
-                    let memtable =
-                        MemTable::recover_from_wal(*id, Self::path_of_wal_static(path, *id))?;
-                    if !memtable.is_empty() {
-                        state.imm_memtables.insert(0, Arc::new(memtable));
-                        wal_cnt += 1;
+                    let wal_path = Self::path_of_wal_static(path, *id);
+                    let recovered = if read_only {
+                        MemTable::replay_wal(*id, wal_path, &cf_ids)?
+                    } else {
+                        MemTable::recover_from_wal(*id, wal_path, &cf_ids)?
+                    };
+                    let cf_states = std::iter::once(&mut state)
+                        .chain(cf_states.values_mut().map(|(_, _, cf_state)| cf_state));
+                    // 空的memtable也保留，刷新时才能在MANIFEST里记下这个WAL不再需要
+                    for (cf_state, memtable) in cf_states.zip(recovered) {
+                        cf_state.imm_memtables.insert(0, Arc::new(memtable));
                     }
+                    wal_cnt += 1;
                 }
                 println!("{} WALs recovered", wal_cnt);
+            }
+            if options.enable_wal && !read_only {
                 state.memtable = Arc::new(MemTable::create_with_wal(
                     next_sst_id,
                     Self::path_of_wal_static(path, next_sst_id),
@@ -237,85 +405,250 @@ impl LsmStorageInner {
             } else {
                 state.memtable = Arc::new(MemTable::create(next_sst_id));
             }
-            m.add_record_when_init(ManifestRecord::NewMemtable(state.memtable.id()))?;
+            for (_, _, cf_state) in cf_states.values_mut() {
+                cf_state.memtable =
+                    Arc::new(MemTable::create_sharing_wal(next_sst_id, &state.memtable));
+            }
+            live_wals.clone_from(&memtables);
+            if let Some(m) = &m {
+                m.add_record_when_init(ManifestRecord::NewMemtable(state.memtable.id()))?;
+                live_wals.insert(state.memtable.id());
+            }
             next_sst_id += 1;
+            if matches!(mode, OpenMode::Secondary(_)) {
+                manifest_tail = Some(Mutex::new(ManifestTail {
+                    offset: manifest_offset,
+                    wals: memtables
+                        .iter()
+                        .map(|id| (*id, ReplayedWal::default()))
+                        .collect(),
+                }));
+            }
             manifest = m;
         };
         tracing::info!("test003 manifest数据为");
+        let all_states = || {
+            std::iter::once(&state).chain(cf_states.values().map(|(_, _, cf_state)| cf_state))
+        };
+        let live_ssts = all_states()
+            .flat_map(|cf_state| cf_state.sstables.keys().copied())
+            .collect();
+        let live_files = LiveFiles::new(live_ssts, live_wals);
+        // 刷新或压缩写到一半崩溃留下的文件
+        if mode == OpenMode::ReadWrite {
+            let live_blobs = BlobStore::live_file_ids(path, all_states())?;
+            let removed = live_files.purge_orphans(path, &live_blobs)?;
+            if removed > 0 {
+                tracing::info!("{} orphan files deleted", removed);
+            }
+        }
+        let state = Arc::new(RwLock::new(Arc::new(state)));
+        let mut column_families = BTreeMap::new();
+        column_families.insert(
+            DEFAULT_CF_ID,
+            Arc::new(ColumnFamily::new(
+                DEFAULT_CF_ID,
+                DEFAULT_CF_NAME.to_string(),
+                ColumnFamilyOptions {
+                    block_size: options.block_size,
+                    compaction_options: options.compaction_options.clone(),
+                },
+                state.clone(),
+            )),
+        );
+        for (id, (name, cf_options, cf_state)) in cf_states {
+            column_families.insert(
+                id,
+                Arc::new(ColumnFamily::new(
+                    id,
+                    name,
+                    cf_options,
+                    Arc::new(RwLock::new(Arc::new(cf_state))),
+                )),
+            );
+        }
         let storage = Self {
-            state: Arc::new(RwLock::new(Arc::new(state))),
+            state,
+            column_families: RwLock::new(column_families),
+            next_cf_id: AtomicUsize::new(next_cf_id),
             state_lock: Mutex::new(()),
+            flush_lock: Mutex::new(()),
             path: path.to_path_buf(),
             block_cache,
+            blob_store: Arc::new(BlobStore::new(path, options.target_sst_size)),
+            blob_gc_lock: RwLock::new(()),
+            table_cache,
             next_sst_id: AtomicUsize::new(next_sst_id),
-            //定义等级
-            compaction_controller,
-            manifest: Some(manifest),
+            write_controller: WriteController::default(),
+            rate_limiter: Arc::new(RateLimiter::new(options.background_io_bytes_per_sec)),
+            manifest,
+            db_lock: Mutex::new(db_lock),
+            mode,
+            manifest_tail,
+            live_files: Mutex::new(live_files),
+            file_deletion_lock: RwLock::new(()),
+            closed: AtomicBool::new(false),
             options: options.into(),
             // mvcc: None,
             // compaction_filters: Arc::new(Mutex::new(Vec::new())),
         };
         tracing::info!("test004 storage数据为{:?}", storage.path);
-        // storage.sync_dir()?;
         tracing::info!("test004 manifest数据为");
+        if storage.manifest_tail.is_some() {
+            storage.try_catch_up_with_primary()?;
+        }
 
         Ok(storage)
     }
+
+    /// 恢复时按id找到列族的压缩配置和状态，已经删除的列族返回 None
+    fn recovering_cf<'a>(
+        options: &'a LsmStorageOptions,
+        state: &'a mut LsmStorageState,
+        cf_states: &'a mut BTreeMap<usize, (String, ColumnFamilyOptions, LsmStorageState)>,
+        cf_id: usize,
+    ) -> Option<(&'a CompactionOptions, &'a mut LsmStorageState)> {
+        if cf_id == DEFAULT_CF_ID {
+            return Some((&options.compaction_options, state));
+        }
+        cf_states
+            .get_mut(&cf_id)
+            .map(|(_, cf_options, cf_state)| (&cf_options.compaction_options, cf_state))
+    }
     /// 通过写入当前memtable，将键值对放入存储器。
     pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
         self.write_batch(&[WriteBatchRecord::Put(key, value)])
     }
 
+    /// 写入一个带过期时间的键值对，过期后读取时视为已删除。
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.write_batch(&[WriteBatchRecord::PutWithTtl(key, value, ttl)])
+    }
+
+    /// 写入一个合并操作数，读取时由配置的 `MergeOperator` 和旧值合并。
+    pub fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        self.write_batch(&[WriteBatchRecord::Merge(key, operand)])
+    }
+
       ///通过写入空值从存储中删除键。
        pub fn delete(&self, key: &[u8]) -> Result<()> {
         self.write_batch(&[WriteBatchRecord::Del(key)])
     }
     //批量写入接口，循环
     pub fn write_batch<T: AsRef<[u8]>>(&self, batch: &[WriteBatchRecord<T>]) -> Result<()> {
-        for record in batch {
-            match record {
-                WriteBatchRecord::Del(key) => {
-                    let key = key.as_ref();
-                    assert!(!key.is_empty(), "key cannot be empty");
-                    let size;
-                    {
-                        let guard = self.state.read();
-                        guard.memtable.put(key, b"")?;
-                        size = guard.memtable.approximate_size();
-                    }
-                    self.try_freeze(size)?;
-                }
+        let default_cf = self.default_cf();
+        let batch = batch
+            .iter()
+            .map(|record| (&*default_cf, record))
+            .collect::<Vec<_>>();
+        self.write_batch_cf(&batch)
+    }
+    /// 跨列族的批量写入。整个批次作为WAL里的一条记录写入，恢复时要么全部生效要么全部丢弃。
+    pub fn write_batch_cf<T: AsRef<[u8]>>(
+        &self,
+        batch: &[(&ColumnFamily, &WriteBatchRecord<T>)],
+    ) -> Result<()> {
+        // 先等刷新和压缩赶上来，再写入，停写时 memtable 不会继续变大
+        self.maybe_stall_writes()?;
+        let guard = self.blob_gc_lock.read();
+        // 拿到锁之后再检查，`close` 拿写锁等正在进行的写入结束
+        self.check_writable()?;
+        let mut records = Vec::with_capacity(batch.len());
+        for (cf, record) in batch {
+            let (key, value) = match record {
+                WriteBatchRecord::Del(key) => (key.as_ref(), Vec::new()),
                 WriteBatchRecord::Put(key, value) => {
                     tracing::info!("key为数据为{:?}", key.as_ref());
                     tracing::info!("value为数据为{:?}", value.as_ref());
-                    let key = key.as_ref();
-                    let value = value.as_ref();
-                    let size;
-                    {
-                        let guard = self.state.read();
-                        // tracing::info!("guard数据为{:?}", guard);
-                        guard.memtable.put(key, value)?;
-                        size = guard.memtable.approximate_size();
+                    (key.as_ref(), self.encode_put(cf.id, key.as_ref(), value.as_ref(), None)?)
+                }
+                WriteBatchRecord::PutWithTtl(key, value, ttl) => {
+                    let expire_at = expire_at_ms(*ttl);
+                    (
+                        key.as_ref(),
+                        self.encode_put(cf.id, key.as_ref(), value.as_ref(), Some(expire_at))?,
+                    )
+                }
+                WriteBatchRecord::Merge(key, operand) => {
+                    if self.options.merge_operator.is_none() {
+                        bail!("merge operator is not configured");
                     }
-                    tracing::info!("size数据为{:?}", &size);
-                    self.try_freeze(size)?;
+                    (key.as_ref(), encode_merge_operand(operand.as_ref()))
                 }
+            };
+            assert!(!key.is_empty(), "key cannot be empty");
+            records.push((cf.id, key, value));
+        }
+        let size = self.write_records(&records)?;
+        drop(guard);
+        tracing::info!("size数据为{:?}", &size);
+        self.try_freeze(size)
+    }
+    /// 把编码好的记录作为WAL里的一条记录写入，再写进各列族的memtable，返回memtable的最大大小
+    pub(crate) fn write_records(&self, records: &[(usize, &[u8], Vec<u8>)]) -> Result<usize> {
+        let mut size = 0;
+        {
+            let column_families = self.column_families.read();
+            let mut cf_ids = records.iter().map(|(cf_id, _, _)| *cf_id).collect::<Vec<_>>();
+            cf_ids.sort_unstable();
+            cf_ids.dedup();
+            // 按id顺序加读锁，和冻结时加写锁的顺序一致
+            let mut guards = HashMap::new();
+            for cf_id in cf_ids {
+                let Some(cf) = column_families.get(&cf_id) else {
+                    bail!("column family {} has been dropped", cf_id);
+                };
+                guards.insert(cf_id, cf.state.read());
+            }
+            // 所有列族的memtable共用同一个WAL
+            if let Some(wal) = guards.values().next().and_then(|guard| guard.memtable.wal()) {
+                let wal_records = records
+                    .iter()
+                    .map(|(cf_id, key, value)| (*cf_id, *key, &value[..]))
+                    .collect::<Vec<_>>();
+                wal.put_batch(&wal_records)?;
+            }
+            for (cf_id, key, value) in records {
+                let memtable = &guards[cf_id].memtable;
+                memtable.put_without_wal(key, value);
+                size = size.max(memtable.approximate_size());
             }
         }
-        Ok(())
+        Ok(size)
+    }
+    //超过阈值的值写进blob文件，memtable和WAL里只保存指针
+    fn encode_put(
+        &self,
+        cf_id: usize,
+        key: &[u8],
+        value: &[u8],
+        expire_at: Option<u64>,
+    ) -> Result<Vec<u8>> {
+        match self.options.large_value_threshold {
+            Some(threshold) if value.len() >= threshold => {
+                let ptr = self.blob_store.append(cf_id, key, value, || self.next_sst_id())?;
+                Ok(encode_blob_pointer(ptr, expire_at))
+            }
+            _ => Ok(encode_value(value, expire_at)),
+        }
     }
         //持久化操作
-    fn try_freeze(&self, estimated_size: usize) -> Result<()> {
+    pub(crate) fn try_freeze(&self, estimated_size: usize) -> Result<()> {
         tracing::info!("sestimated_size数据为{:?}", estimated_size);
         tracing::info!("本源数据为{:?}", self.options.target_sst_size );
         if estimated_size >= 1{
             tracing::info!("需要持久化的数据byte为{:?}", self.state_lock.lock());
             tracing::info!("需要持久化的数据路径为{:?}", self.path);
             let state_lock = self.state_lock.lock();
-            let guard = self.state.read();
+            let size = self
+                .column_families
+                .read()
+                .values()
+                .map(|cf| cf.state.read().memtable.approximate_size())
+                .max()
+                .unwrap_or_default();
             // the memtable could have already been frozen, check again to ensure we really need to freeze
-            if guard.memtable.approximate_size() >= 1{
-                drop(guard);
+            if size >= 1{
                 self.force_freeze_memtable(&state_lock)?;
             }
         }
@@ -323,6 +656,7 @@ impl LsmStorageInner {
     }
     //刷新源库数据
     pub fn force_freeze_memtable(&self, state_lock_observer: &MutexGuard<'_, ()>) -> Result<()> {
+        self.check_writable()?;
         tracing::info!("刷新源库数据数据为{:?}", &self.state_lock);
         let memtable_id = self.next_sst_id();
         tracing::info!("刷新源库数据数memtable_id为{:?}", memtable_id);
@@ -344,7 +678,7 @@ impl LsmStorageInner {
             state_lock_observer,
             ManifestRecord::NewMemtable(memtable_id),
         )?;
-        // self.sync_dir()?;
+        self.live_files.lock().wals.insert(memtable_id);
         Ok(())
     }
     pub(crate) fn path_of_wal(&self, id: usize) -> PathBuf {
@@ -352,76 +686,282 @@ impl LsmStorageInner {
     }
     //刷新写入
     fn freeze_memtable_with_memtable(&self, memtable: Arc<MemTable>) -> Result<()> {
-        let mut guard = self.state.write();
-        // 用新的memtable替换当前的memtable。
-        let mut snapshot = guard.as_ref().clone();
-        tracing::info!("进入005");
-        let old_memtable = std::mem::replace(&mut snapshot.memtable, memtable);
-        //将memtable添加到不可变memtable中。
-        snapshot.imm_memtables.insert(0, old_memtable.clone());
-        //更新快照。
-        *guard = Arc::new(snapshot);
+        let column_families = self.column_families.read();
+        // 按id顺序给所有列族加写锁，所有列族一起切换到共用新WAL的memtable
+        let mut guards = column_families
+            .values()
+            .map(|cf| (cf.id, cf.state.write()))
+            .collect::<Vec<_>>();
+        let mut old_memtable = None;
+        for (cf_id, guard) in guards.iter_mut() {
+            let new_memtable = if *cf_id == DEFAULT_CF_ID {
+                memtable.clone()
+            } else {
+                Arc::new(MemTable::create_sharing_wal(memtable.id(), &memtable))
+            };
+            // 用新的memtable替换当前的memtable。
+            let mut snapshot = guard.as_ref().clone();
+            tracing::info!("进入005");
+            let old = std::mem::replace(&mut snapshot.memtable, new_memtable);
+            //将memtable添加到不可变memtable中。
+            snapshot.imm_memtables.insert(0, old.clone());
+            //更新快照。
+            **guard = Arc::new(snapshot);
+            old_memtable.get_or_insert(old);
+        }
 
-        drop(guard);
-        old_memtable.sync_wal()?;
+        drop(guards);
+        // 旧的memtable共用一个WAL，同步一次即可
+        if let Some(old_memtable) = old_memtable {
+            // WAL里的指针指向blob文件，blob文件要先落盘
+            self.blob_store.sync()?;
+            old_memtable.sync_wal()?;
+        }
 
         Ok(())
     }
 
+    /// 把最旧的不可变memtable刷新到SST。所有列族同时冻结，最旧的不可变memtable共用一个WAL，
+    /// 所以一起刷新：每个非空的列族写一个SST，再用一条MANIFEST记录让它们同时生效，之后这个WAL等待删除。
+    pub fn force_flush_next_imm_memtable(&self) -> Result<()> {
+        let _flush_lock = self.flush_lock.lock();
+        self.check_writable()?;
+        let column_families = self
+            .column_families
+            .read()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        // 冻结之后才创建的列族没有这么旧的memtable
+        let Some(memtable_id) = column_families
+            .iter()
+            .filter_map(|cf| cf.state.read().imm_memtables.last().map(|x| x.id()))
+            .min()
+        else {
+            return Ok(());
+        };
+        let mut flushed = Vec::new();
+        for cf in column_families {
+            let memtable = match cf.state.read().imm_memtables.last() {
+                Some(memtable) if memtable.id() == memtable_id => memtable.clone(),
+                _ => continue,
+            };
+            let flush_to_l0 =
+                CompactionController::new(&cf.options.compaction_options).flush_to_l0();
+            let table = if memtable.is_empty() {
+                None
+            } else {
+                let mut builder = SsTableBuilder::new(
+                    cf.options.block_size,
+                    self.options.block_compression,
+                    self.options.prefix_extractor.clone(),
+                );
+                memtable.flush(&mut builder)?;
+                let sst_id = self.next_sst_id();
+                Some(self.write_sst(sst_id, builder, flush_to_l0, IoPriority::High)?)
+            };
+            flushed.push((cf, table, flush_to_l0));
+        }
+
+        let state_lock = self.state_lock.lock();
+        // 刷新期间被删除的列族不用记录，它的SST下次打开时作为孤儿文件删除
+        {
+            let column_families = self.column_families.read();
+            flushed.retain(|(cf, _, _)| column_families.contains_key(&cf.id));
+        }
+        let tables = flushed
+            .iter()
+            .filter_map(|(cf, table, _)| table.as_ref().map(|table| (cf.id, table.sst_id())))
+            .collect::<Vec<_>>();
+        self.manifest.as_ref().unwrap().add_record(
+            &state_lock,
+            ManifestRecord::Flush(memtable_id, tables.clone()),
+        )?;
+        for (cf, table, flush_to_l0) in flushed {
+            let mut guard = cf.state.write();
+            let mut snapshot = guard.as_ref().clone();
+            let memtable = snapshot.imm_memtables.pop().unwrap();
+            assert_eq!(memtable.id(), memtable_id);
+            if let Some(table) = table {
+                snapshot.add_flushed_sst(table.sst_id(), flush_to_l0);
+                snapshot.sstables.insert(table.sst_id(), table);
+            }
+            *guard = Arc::new(snapshot);
+        }
+        self.live_files
+            .lock()
+            .ssts
+            .extend(tables.into_iter().map(|(_, sst_id)| sst_id));
+        self.retire_wal(memtable_id);
+        self.blob_store.memtable_flushed(memtable_id);
+        Ok(())
+    }
+
+    /// 冻结当前memtable，再把所有不可变memtable刷新到SST。没有WAL时，关闭和创建检查点之前用它保存数据
+    pub(crate) fn flush_all_memtables(&self) -> Result<()> {
+        {
+            let state_lock = self.state_lock.lock();
+            let memtable_empty = self
+                .column_families
+                .read()
+                .values()
+                .all(|cf| cf.state.read().memtable.is_empty());
+            if !memtable_empty {
+                self.force_freeze_memtable(&state_lock)?;
+            }
+        }
+        while self
+            .column_families
+            .read()
+            .values()
+            .any(|cf| !cf.state.read().imm_memtables.is_empty())
+        {
+            self.force_flush_next_imm_memtable()?;
+        }
+        Ok(())
+    }
+
     ///获取元数据
     pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.get_cf(&self.default_cf(), key)
+    }
+
+    ///从指定列族获取数据
+    pub fn get_cf(&self, cf: &ColumnFamily, key: &[u8]) -> Result<Option<Bytes>> {
+        let resolver = self.value_resolver();
         let snapshot = {
-            let guard = self.state.read();
+            let guard = cf.state.read();
             Arc::clone(&guard)
         }; 
+        resolver.get(&snapshot, key, now_ms())
+    }
 
-        //查找当前内存表。
-        if let Some(value) = snapshot.memtable.get(key) {
-            tracing::info!("4444441");
-            if value.is_empty() {
-                 //发现，返回键不存在
-                return Ok(None);
-            }
-            tracing::info!("4444442");
-            return Ok(Some(value));
+    /// 读取时解析值用的 blob 文件和合并操作符。要在拿快照之前创建，
+    /// 它存在期间快照里的指针指向的 blob 文件不会被 GC 删除
+    pub(crate) fn value_resolver(&self) -> ValueResolver {
+        ValueResolver {
+            blob_store: self.blob_store.clone(),
+            merge_operator: self.options.merge_operator.clone(),
+            _blob_files_pin: self.blob_store.pin_files(),
         }
+    }
 
-        // 在不可变的记忆表上搜索。
-        for memtable in snapshot.imm_memtables.iter() {
-            if let Some(value) = memtable.get(key) {
-                if value.is_empty() {
-                    //发现，返回键不存在
-                    return Ok(None);
-                }
-                return Ok(Some(value));
-            }
-        }
+    /// 从默认列族批量读取
+    pub fn multi_get(&self, keys: &[&[u8]]) -> Result<Vec<Option<Bytes>>> {
+        self.multi_get_cf(&self.default_cf(), keys)
+    }
 
-        let mut l0_iters = Vec::with_capacity(snapshot.l0_sstables.len());
+    /// 批量读取，所有键在同一个快照上读取。键排序去重后每个memtable只遍历一次，
+    /// 剩下的键按SST和数据块分组，每个块只读取和解码一次。返回的结果和 `keys` 一一对应。
+    pub fn multi_get_cf(&self, cf: &ColumnFamily, keys: &[&[u8]]) -> Result<Vec<Option<Bytes>>> {
+        let resolver = self.value_resolver();
+        let snapshot = {
+            let guard = cf.state.read();
+            Arc::clone(&guard)
+        };
+        let mut sorted_keys = keys.to_vec();
+        sorted_keys.sort_unstable();
+        sorted_keys.dedup();
+        let values = resolver.multi_get(&snapshot, &sorted_keys, now_ms())?;
+        Ok(keys
+            .iter()
+            .map(|key| {
+                let idx = sorted_keys.binary_search(key).unwrap();
+                values[idx].clone()
+            })
+            .collect())
+    }
 
-        let keep_table = |key: &[u8], table: &SsTable| {
-            if key_within(
-                key,
-                table.first_key().as_key_slice(),
-                table.last_key().as_key_slice(),
-            ) {
-                // if let Some(bloom) = &table.bloom {
-                //     if bloom.may_contain(farmhash::fingerprint32(key)) {
-                //         return true;
-                //     }
-                // } else {
-                return true;
-                // }
-            }
-            false
+    /// 返回默认列族里 `lower` 和 `upper` 之间的键值对
+    pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<LsmIterator> {
+        self.scan_cf(&self.default_cf(), lower, upper)
+    }
+
+    /// 按键从大到小返回默认列族里 `lower` 和 `upper` 之间的键值对，用 `prev` 往前走
+    pub fn scan_rev(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<LsmIterator> {
+        self.scan_rev_cf(&self.default_cf(), lower, upper)
+    }
+
+    /// 范围扫描。和范围不重叠的 SST 在创建 `SsTableIterator` 之前就被跳过。
+    pub fn scan_cf(
+        &self,
+        cf: &ColumnFamily,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<LsmIterator> {
+        self.create_iterator(cf, lower, upper, None)
+    }
+
+    /// `scan_cf` 的反向版本，迭代器定位在范围里的最后一个键上
+    pub fn scan_rev_cf(
+        &self,
+        cf: &ColumnFamily,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<LsmIterator> {
+        let mut iter = self.scan_cf(cf, lower, upper)?;
+        iter.seek_to_last()?;
+        Ok(iter)
+    }
+
+    /// 返回默认列族里以 `prefix` 开头的键值对
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<LsmIterator> {
+        self.scan_prefix_cf(&self.default_cf(), prefix)
+    }
+
+    /// 按键从大到小返回默认列族里以 `prefix` 开头的键值对，用 `prev` 往前走
+    pub fn scan_prefix_rev(&self, prefix: &[u8]) -> Result<LsmIterator> {
+        self.scan_prefix_rev_cf(&self.default_cf(), prefix)
+    }
+
+    /// `scan_prefix_cf` 的反向版本，迭代器定位在最后一个带前缀的键上
+    pub fn scan_prefix_rev_cf(&self, cf: &ColumnFamily, prefix: &[u8]) -> Result<LsmIterator> {
+        let mut iter = self.scan_prefix_cf(cf, prefix)?;
+        iter.seek_to_last()?;
+        Ok(iter)
+    }
+
+    /// 前缀扫描。布隆过滤器里没有这个前缀的 SST 在创建 `SsTableIterator` 之前就被跳过。
+    pub fn scan_prefix_cf(&self, cf: &ColumnFamily, prefix: &[u8]) -> Result<LsmIterator> {
+        let upper = Self::prefix_upper_bound(prefix);
+        self.create_iterator(cf, Bound::Included(prefix), upper.as_ref().map(|upper| &upper[..]), Some(prefix))
+    }
+
+    //在memtable、L0和各层SST上创建 `lower` 到 `upper` 之间的迭代器，前缀扫描时再用布隆过滤器跳过SST
+    fn create_iterator(
+        &self,
+        cf: &ColumnFamily,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        prefix: Option<&[u8]>,
+    ) -> Result<LsmIterator> {
+        let resolver = self.value_resolver();
+        let snapshot = {
+            let guard = cf.state.read();
+            Arc::clone(&guard)
         };
+        let mut memtable_iters = Vec::with_capacity(snapshot.imm_memtables.len() + 1);
+        memtable_iters.push(Box::new(snapshot.memtable.scan(lower, upper)));
+        for memtable in snapshot.imm_memtables.iter() {
+            memtable_iters.push(Box::new(memtable.scan(lower, upper)));
+        }
+        let memtable_iter = MergeIterator::create(memtable_iters);
 
+        // SST迭代器从下界开始，不包含的下界由 `LsmIterator` 跳过
+        let seek_key = match lower {
+            Bound::Included(key) | Bound::Excluded(key) => KeySlice::from_slice(key),
+            Bound::Unbounded => KeySlice::from_slice(&[]),
+        };
+        let may_match = |table: &SsTable| match prefix {
+            Some(prefix) => self.prefix_may_match(table, prefix),
+            None => Ok(Self::range_may_match(table, lower, upper)),
+        };
+        let mut l0_iters = Vec::with_capacity(snapshot.l0_sstables.len());
         for table in snapshot.l0_sstables.iter() {
             let table = snapshot.sstables[table].clone();
-            if keep_table(key, &table) {
+            if may_match(&table)? {
                 l0_iters.push(Box::new(SsTableIterator::create_and_seek_to_key(
-                    table,
-                    KeySlice::from_slice(key),
+                    table, seek_key,
                 )?));
             }
         }
@@ -431,39 +971,388 @@ impl LsmStorageInner {
             let mut level_ssts = Vec::with_capacity(level_sst_ids.len());
             for table in level_sst_ids {
                 let table = snapshot.sstables[table].clone();
-                if keep_table(key, &table) {
+                if may_match(&table)? {
                     level_ssts.push(table);
                 }
             }
-            let level_iter =
-                SstConcatIterator::create_and_seek_to_key(level_ssts, KeySlice::from_slice(key))?;
+            let level_iter = SstConcatIterator::create_and_seek_to_key(level_ssts, seek_key)?;
             level_iters.push(Box::new(level_iter));
         }
+        let iter = TwoMergeIterator::create(
+            TwoMergeIterator::create(memtable_iter, l0_iter)?,
+            MergeIterator::create(level_iters),
+        )?;
+        LsmIterator::new(
+            iter,
+            lower.map(Bytes::copy_from_slice),
+            upper.map(Bytes::copy_from_slice),
+            snapshot,
+            resolver,
+        )
+    }
 
-        // let iter = TwoMergeIterator::create(l0_iter, MergeIterator::create(level_iters))?;
+    //SST里是否可能有以prefix开头的键
+    fn prefix_may_match(&self, table: &SsTable, prefix: &[u8]) -> Result<bool> {
+        let upper = Self::prefix_upper_bound(prefix);
+        let upper = upper.as_ref().map(|upper| &upper[..]);
+        if !Self::range_may_match(table, Bound::Included(prefix), upper) {
+            return Ok(false);
+        }
+        // 只有prefix正好是提取出来的前缀时，它才在布隆过滤器里
+        if let Some(extractor) = &self.options.prefix_extractor {
+            if extractor.prefix(prefix) == Some(prefix) {
+                if let Some(bloom) = table.bloom()? {
+                    return Ok(bloom.may_contain(farmhash::fingerprint32(prefix)));
+                }
+            }
+        }
+        Ok(true)
+    }
+    /// 把还没落盘的 blob 文件和当前 memtable 的 WAL 刷到磁盘
+    pub fn sync(&self) -> Result<()> {
+        // WAL里的指针指向blob文件，blob文件要先落盘
+        self.blob_store.sync()?;
+        self.state.read().memtable.sync_wal()
+    }
 
-        // if iter.is_valid() && iter.key().raw_ref() == key && !iter.value().is_empty() {
-        //     return Ok(Some(Bytes::copy_from_slice(iter.value())));
-        // }
-        tracing::info!("444444");
-        Ok(None)
+    /// 没有 WAL 保护、关闭后会丢失的 memtable 个数
+    pub(crate) fn unpersisted_memtables(&self) -> usize {
+        if self.options.enable_wal {
+            return 0;
+        }
+        self.column_families
+            .read()
+            .values()
+            .map(|cf| {
+                let state = cf.state.read();
+                std::iter::once(&state.memtable)
+                    .chain(state.imm_memtables.iter())
+                    .filter(|memtable| !memtable.is_empty())
+                    .count()
+            })
+            .sum()
     }
-    pub(super) fn sync_dir(&self) -> Result<()> {
-        tracing::info!("test003 manifest path 数据为{:?}", self.path);
-        let text: &str = "a.txt";
-        File::open(&self.path)?.sync_all()?;
-        // File::open(text).unwrap();
+
+    /// 只读实例、从实例和已经关闭的实例不能写入，也不能修改列族
+    pub(crate) fn check_writable(&self) -> Result<()> {
+        if self.mode != OpenMode::ReadWrite {
+            bail!("database {:?} is opened read-only", self.path);
+        }
+        if self.closed.load(std::sync::atomic::Ordering::SeqCst) {
+            bail!("database {:?} is closed", self.path);
+        }
         Ok(())
     }
+
+    pub(super) fn sync_dir(&self) -> Result<()> {
+        sync_dir(&self.path)
+    }
+    /// 引擎使用的块缓存，可以传给其他引擎的 `LsmStorageOptions::block_cache` 共享
+    pub fn block_cache(&self) -> &Arc<BlockCache> {
+        self.block_cache.cache()
+    }
+    /// 打开SST文件。使用mmap时映射整个文件（不占用文件句柄），使用direct io时用O_DIRECT打开并一直保持打开，
+    /// 否则有表缓存时只记录路径，读取时才打开
+    pub(crate) fn open_sst_file(
+        path: &Path,
+        id: usize,
+        options: &LsmStorageOptions,
+        table_cache: Option<&Arc<TableCache>>,
+    ) -> Result<FileObject> {
+        let path = Self::path_of_sst_static(path, id);
+        if options.use_mmap_reads {
+            return FileObject::open_mmap(&path);
+        }
+        if options.use_direct_io_reads {
+            return FileObject::open_direct(&path);
+        }
+        match table_cache {
+            Some(table_cache) => FileObject::open_cached(id, &path, table_cache.clone()),
+            None => FileObject::open(&path),
+        }
+    }
+    /// 把 `builder` 的内容按 `priority` 限速写成id为 `id` 的SST文件，再和恢复时一样按配置打开
+    pub(crate) fn write_sst(
+        &self,
+        id: usize,
+        builder: SsTableBuilder,
+        is_l0: bool,
+        priority: IoPriority,
+    ) -> Result<Arc<SsTable>> {
+        FileObject::create_with_rate_limiter(
+            &Self::path_of_sst_static(&self.path, id),
+            builder.finish(),
+            &self.rate_limiter,
+            priority,
+        )?;
+        let table = SsTable::open_with_options(
+            id,
+            Some(self.block_cache.clone()),
+            Self::open_sst_file(&self.path, id, &self.options, self.table_cache.as_ref())
+                .with_context(|| format!("failed to open SST: {}", id))?,
+            self.options.table_open_options(is_l0),
+        )?;
+        Ok(Arc::new(table))
+    }
     pub(crate) fn path_of_wal_static(path: impl AsRef<Path>, id: usize) -> PathBuf {
         path.as_ref().join(format!("{:05}.wal", id))
     }
     pub(crate) fn path_of_sst_static(path: impl AsRef<Path>, id: usize) -> PathBuf {
         path.as_ref().join(format!("{:05}.sst", id))
     }
+    pub(crate) fn path_of_blob_static(path: impl AsRef<Path>, id: usize) -> PathBuf {
+        path.as_ref().join(format!("{:05}.blob", id))
+    }
+
+    //SST的键范围是否和 `lower` 到 `upper` 重叠
+    fn range_may_match(table: &SsTable, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> bool {
+        let first_key = table.first_key().raw_ref();
+        let last_key = table.last_key().raw_ref();
+        let below_lower = match lower {
+            Bound::Included(lower) => last_key < lower,
+            Bound::Excluded(lower) => last_key <= lower,
+            Bound::Unbounded => false,
+        };
+        let above_upper = match upper {
+            Bound::Included(upper) => first_key > upper,
+            Bound::Excluded(upper) => first_key >= upper,
+            Bound::Unbounded => false,
+        };
+        !below_lower && !above_upper
+    }
+
+    /// 以 `prefix` 开头的键的上界：去掉末尾的 0xff 后最后一个字节加一，全是 0xff 时没有上界
+    fn prefix_upper_bound(prefix: &[u8]) -> Bound<Bytes> {
+        let mut end = prefix.to_vec();
+        while end.last() == Some(&0xff) {
+            end.pop();
+        }
+        match end.last_mut() {
+            Some(last) => {
+                *last += 1;
+                Bound::Excluded(end.into())
+            }
+            None => Bound::Unbounded,
+        }
+    }
+}
+/// 读取路径上把原始值解析成用户看到的值：读出 blob 里的大值，把合并操作数和更旧的版本合并。
+/// `get`、`multi_get` 和扫描都通过它在快照上查找。
+#[derive(Clone)]
+pub(crate) struct ValueResolver {
+    blob_store: Arc<BlobStore>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    _blob_files_pin: Arc<()>,
 }
-fn key_within(user_key: &[u8], table_begin: KeySlice, table_end: KeySlice) -> bool {
-    table_begin.raw_ref() <= user_key && user_key <= table_end.raw_ref()
+
+/// `multi_get` 里一个键的查找状态
+struct MultiGetLookup<'a> {
+    key: &'a [u8],
+    /// 从新到旧收集的合并操作数
+    operands: Vec<Bytes>,
+    /// 一共遇到的合并操作数个数
+    num_operands: usize,
+    /// 合并操作数下面的基础值的过期时间
+    expire_at: Option<u64>,
+    /// 找到最终结果后为 Some
+    result: Option<Option<Bytes>>,
+}
+
+/// 压缩时一个键在快照上合并的结果
+pub(crate) struct MergedValue {
+    /// 合并后的值，None 表示键不存在
+    pub(crate) value: Option<Bytes>,
+    /// 基础值的过期时间，合并后的值也在这时过期
+    pub(crate) expire_at: Option<u64>,
+    /// 是否找到了基础值（值、墓碑或过期的值），没有时只合并了操作数
+    pub(crate) has_base: bool,
+    /// 合并了几个操作数
+    pub(crate) num_operands: usize,
+}
+
+impl ValueResolver {
+    /// 读出 blob 文件里的值
+    pub(crate) fn read_blob(&self, ptr: BlobPointer) -> Result<Bytes> {
+        self.blob_store.read(ptr)
+    }
+
+    /// 在快照上读取一个键
+    pub(crate) fn get(
+        &self,
+        snapshot: &LsmStorageState,
+        key: &[u8],
+        now: u64,
+    ) -> Result<Option<Bytes>> {
+        Ok(self.multi_get(snapshot, &[key], now)?.pop().unwrap())
+    }
+
+    /// 在快照上读取排好序、不重复的 `keys`，从新到旧查找memtable、L0和其它层，结果和 `keys` 一一对应
+    pub(crate) fn multi_get(
+        &self,
+        snapshot: &LsmStorageState,
+        keys: &[&[u8]],
+        now: u64,
+    ) -> Result<Vec<Option<Bytes>>> {
+        self.lookup(snapshot, keys, now)?
+            .into_iter()
+            .map(|lookup| match lookup.result {
+                Some(result) => Ok(result),
+                None => self.full_merge(lookup.key, None, lookup.operands),
+            })
+            .collect()
+    }
+
+    /// 压缩时合并一个键：和 `get` 一样查找，同时返回基础值的过期时间和是否找到了基础值
+    pub(crate) fn merge_versions(
+        &self,
+        snapshot: &LsmStorageState,
+        key: &[u8],
+        now: u64,
+    ) -> Result<MergedValue> {
+        let lookup = self.lookup(snapshot, &[key], now)?.pop().unwrap();
+        let has_base = lookup.result.is_some();
+        let value = match lookup.result {
+            Some(result) => result,
+            None => self.full_merge(key, None, lookup.operands)?,
+        };
+        Ok(MergedValue {
+            value,
+            expire_at: lookup.expire_at,
+            has_base,
+            num_operands: lookup.num_operands,
+        })
+    }
+
+    fn lookup<'a>(
+        &self,
+        snapshot: &LsmStorageState,
+        keys: &[&'a [u8]],
+        now: u64,
+    ) -> Result<Vec<MultiGetLookup<'a>>> {
+        let mut lookups = keys
+            .iter()
+            .map(|key| MultiGetLookup {
+                key,
+                operands: Vec::new(),
+                num_operands: 0,
+                expire_at: None,
+                result: None,
+            })
+            .collect::<Vec<_>>();
+
+        for memtable in std::iter::once(&snapshot.memtable).chain(snapshot.imm_memtables.iter()) {
+            for lookup in lookups.iter_mut().filter(|lookup| lookup.result.is_none()) {
+                if let Some(value) = memtable.get(lookup.key) {
+                    self.resolve_value(lookup, value, now)?;
+                }
+            }
+        }
+        // L0从新到旧，其它层的SST互不重叠，每个SST只处理落在它范围内的键
+        for table_id in snapshot
+            .l0_sstables
+            .iter()
+            .chain(snapshot.levels.iter().flat_map(|(_, files)| files))
+        {
+            self.multi_get_from_table(&snapshot.sstables[table_id], &mut lookups, now)?;
+        }
+        Ok(lookups)
+    }
+
+    //在一个SST里查找还没有结果的键，键是有序的，同一个块里的键连续出现，块只读取一次
+    fn multi_get_from_table(
+        &self,
+        table: &SsTable,
+        lookups: &mut [MultiGetLookup],
+        now: u64,
+    ) -> Result<()> {
+        let first = lookups.partition_point(|lookup| lookup.key < table.first_key().raw_ref());
+        let last = lookups.partition_point(|lookup| lookup.key <= table.last_key().raw_ref());
+        if first >= last {
+            return Ok(());
+        }
+        let bloom = table.bloom()?;
+        let mut current_block: Option<(usize, Arc<Block>)> = None;
+        for lookup in &mut lookups[first..last] {
+            if lookup.result.is_some() {
+                continue;
+            }
+            if let Some(bloom) = &bloom {
+                if !bloom.may_contain(farmhash::fingerprint32(lookup.key)) {
+                    continue;
+                }
+            }
+            let key = KeySlice::from_slice(lookup.key);
+            let blk_idx = table.find_block_idx(key)?;
+            let block = match &current_block {
+                Some((idx, block)) if *idx == blk_idx => block.clone(),
+                _ => {
+                    let block = table.read_block_cached(blk_idx)?;
+                    current_block = Some((blk_idx, block.clone()));
+                    block
+                }
+            };
+            let iter = BlockIterator::create_and_seek_to_key(block.clone(), key);
+            if iter.is_valid() && iter.key() == key {
+                let value = block.data.slice_ref(iter.value());
+                self.resolve_value(lookup, value, now)?;
+            }
+        }
+        Ok(())
+    }
+
+    //处理键的一个版本：遇到值或墓碑时记下最终结果，遇到合并操作数时收集起来，继续找更旧的版本
+    fn resolve_value(&self, lookup: &mut MultiGetLookup, value: Bytes, now: u64) -> Result<()> {
+        let key = lookup.key;
+        let operands = std::mem::take(&mut lookup.operands);
+        let result = match decode_value(&value, now)? {
+            //墓碑或已过期，返回键不存在
+            ValueRef::Deleted => self.full_merge(key, None, operands)?,
+            ValueRef::Put(data) => {
+                lookup.expire_at = value_expire_at(&value);
+                if operands.is_empty() {
+                    Some(value.slice_ref(data))
+                } else {
+                    self.full_merge(key, Some(data), operands)?
+                }
+            }
+            ValueRef::Blob(ptr) => {
+                lookup.expire_at = value_expire_at(&value);
+                let data = self.blob_store.read(ptr)?;
+                if operands.is_empty() {
+                    Some(data)
+                } else {
+                    self.full_merge(key, Some(&data), operands)?
+                }
+            }
+            ValueRef::Merge(operand) => {
+                lookup.operands = operands;
+                lookup.operands.push(value.slice_ref(operand));
+                lookup.num_operands += 1;
+                return Ok(());
+            }
+        };
+        lookup.result = Some(result);
+        Ok(())
+    }
+
+    //把从新到旧收集的合并操作数和基础值合并，没有操作数时直接返回基础值
+    fn full_merge(
+        &self,
+        key: &[u8],
+        existing: Option<&[u8]>,
+        mut operands: Vec<Bytes>,
+    ) -> Result<Option<Bytes>> {
+        if operands.is_empty() {
+            return Ok(existing.map(Bytes::copy_from_slice));
+        }
+        let Some(merge_operator) = &self.merge_operator else {
+            bail!("merge operator is not configured");
+        };
+        operands.reverse();
+        let operands = operands.iter().map(|x| &x[..]).collect::<Vec<_>>();
+        let value = merge_operator.full_merge(key, existing, &operands)?;
+        Ok(Some(value.into()))
+    }
 }
 
 /// 遍历SSTable对象内容的迭代器。
@@ -471,6 +1360,8 @@ pub struct SsTableIterator {
     table: Arc<SsTable>,
     blk_iter: BlockIterator,
     blk_idx: usize,
+    /// 预读的后续块，第一个是 blk_idx + 1
+    readahead: VecDeque<Arc<Block>>,
 }
 impl StorageIterator for SsTableIterator {
     type KeyType<'a> = KeySlice<'a>;
@@ -492,22 +1383,55 @@ impl StorageIterator for SsTableIterator {
         if !self.blk_iter.is_valid() {
             self.blk_idx += 1;
             if self.blk_idx < self.table.num_of_blocks() {
-                self.blk_iter = BlockIterator::create_and_seek_to_first(
-                    self.table.read_block_cached(self.blk_idx)?,
-                );
+                self.blk_iter = BlockIterator::create_and_seek_to_first(self.next_block()?);
             }
         }
         Ok(())
     }
+
+    fn prev(&mut self) -> Result<()> {
+        self.blk_iter.prev();
+        if !self.blk_iter.is_valid() && self.blk_idx > 0 {
+            // 预读的块都在 blk_idx 之后，往回走时作废
+            self.readahead.clear();
+            self.blk_idx -= 1;
+            self.blk_iter =
+                BlockIterator::create_and_seek_to_last(self.table.read_block_cached(self.blk_idx)?);
+        }
+        Ok(())
+    }
+
+    fn seek(&mut self, key: KeySlice) -> Result<()> {
+        let (blk_idx, blk_iter) = Self::seek_to_key_inner(&self.table, key)?;
+        self.blk_idx = blk_idx;
+        self.blk_iter = blk_iter;
+        self.readahead.clear();
+        Ok(())
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        *self = Self::create_and_seek_to_last(self.table.clone())?;
+        Ok(())
+    }
+
+    fn seek_for_prev(&mut self, key: KeySlice) -> Result<()> {
+        *self = Self::create_and_seek_for_prev(self.table.clone(), key)?;
+        Ok(())
+    }
 }
 ///合并多个相同类型的迭代器。如果相同的键多次出现
 ///迭代器，首选索引较小的迭代器。
 pub struct MergeIterator<I: StorageIterator> {
     iters: BinaryHeap<HeapWrapper<I>>,
     current: Option<HeapWrapper<I>>,
+    /// 已经走到头的迭代器，换方向或重新定位时还要用
+    exhausted: Vec<HeapWrapper<I>>,
+    /// 是否在反向迭代
+    reverse: bool,
 }
 
-struct HeapWrapper<I: StorageIterator>(pub usize, pub Box<I>);
+/// 第三个字段为 true 时按反向迭代排序，键大的先出堆
+struct HeapWrapper<I: StorageIterator>(pub usize, pub Box<I>, pub bool);
 
 impl<I: StorageIterator> PartialEq for HeapWrapper<I> {
     fn eq(&self, other: &Self) -> bool {
@@ -518,59 +1442,260 @@ impl<I: StorageIterator> PartialEq for HeapWrapper<I> {
 impl<I: StorageIterator> Eq for HeapWrapper<I> {}
 
 impl<I: StorageIterator> PartialOrd for HeapWrapper<I> {
-    #[allow(clippy::non_canonical_partial_ord_impl)]
     fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
-        match self.1.key().cmp(&other.1.key()) {
-            cmp::Ordering::Greater => Some(cmp::Ordering::Greater),
-            cmp::Ordering::Less => Some(cmp::Ordering::Less),
-            cmp::Ordering::Equal => self.0.partial_cmp(&other.0),
-        }
-        .map(|x| x.reverse())
+        Some(self.cmp(other))
     }
 }
 
 impl<I: StorageIterator> Ord for HeapWrapper<I> {
     fn cmp(&self, other: &Self) -> cmp::Ordering {
-        self.partial_cmp(other).unwrap()
+        let key_order = self.1.key().cmp(&other.1.key());
+        // 键相同时不论方向，都是下标小的（更新的）先出堆
+        let key_order = if self.2 { key_order.reverse() } else { key_order };
+        key_order.then_with(|| self.0.cmp(&other.0)).reverse()
     }
 }
 
 impl<I: StorageIterator> MergeIterator<I> {
     pub fn create(iters: Vec<Box<I>>) -> Self {
-        if iters.is_empty() {
-            return Self {
-                iters: BinaryHeap::new(),
-                current: None,
-            };
+        let mut iter = Self {
+            iters: BinaryHeap::new(),
+            current: None,
+            exhausted: Vec::new(),
+            reverse: false,
+        };
+        iter.install(
+            iters
+                .into_iter()
+                .enumerate()
+                .map(|(idx, iter)| HeapWrapper(idx, iter, false))
+                .collect(),
+        );
+        iter
+    }
+
+    /// 有效的迭代器放进堆里，堆顶作为当前迭代器。全都无效时随便选一个无效的作为当前迭代器。
+    fn install(&mut self, iters: Vec<HeapWrapper<I>>) {
+        for iter in iters {
+            if iter.1.is_valid() {
+                self.iters.push(iter);
+            } else {
+                self.exhausted.push(iter);
+            }
         }
+        self.current = self.iters.pop().or_else(|| self.exhausted.pop());
+    }
 
-        let mut heap = BinaryHeap::new();
+    /// 取出所有子迭代器，用 `reposition` 重新定位后按 `reverse` 的方向重建堆
+    fn rebuild(
+        &mut self,
+        reverse: bool,
+        mut reposition: impl FnMut(&mut I) -> Result<()>,
+    ) -> Result<()> {
+        let mut iters = std::mem::take(&mut self.iters).into_vec();
+        iters.append(&mut self.exhausted);
+        iters.extend(self.current.take());
+        for iter in iters.iter_mut() {
+            reposition(&mut iter.1)?;
+            iter.2 = reverse;
+        }
+        self.reverse = reverse;
+        self.install(iters);
+        Ok(())
+    }
 
-        if iters.iter().all(|x| !x.is_valid()) {
-            // All invalid, select the last one as the current.
-            let mut iters = iters;
-            return Self {
-                iters: heap,
-                current: Some(HeapWrapper(0, iters.pop().unwrap())),
-            };
+    /// 沿当前方向前进一步
+    fn step(&mut self) -> Result<()> {
+        let reverse = self.reverse;
+        let step = |iter: &mut I| if reverse { iter.prev() } else { iter.next() };
+        let current = self.current.as_mut().unwrap();
+        // 其它迭代器上相同的键是更旧的版本，一起跳过
+        while let Some(mut inner_iter) = self.iters.peek_mut() {
+            if inner_iter.1.key() == current.1.key() {
+                if let e @ Err(_) = step(&mut inner_iter.1) {
+                    PeekMut::pop(inner_iter);
+                    return e;
+                }
+                if !inner_iter.1.is_valid() {
+                    self.exhausted.push(PeekMut::pop(inner_iter));
+                }
+            } else {
+                break;
+            }
         }
 
-        for (idx, iter) in iters.into_iter().enumerate() {
-            if iter.is_valid() {
-                heap.push(HeapWrapper(idx, iter));
+        step(&mut current.1)?;
+
+        // 当前迭代器用完了，换成堆顶的迭代器
+        if !current.1.is_valid() {
+            if let Some(iter) = self.iters.pop() {
+                self.exhausted.push(std::mem::replace(current, iter));
             }
+            return Ok(());
         }
 
-        let current = heap.pop().unwrap();
-        Self {
-            iters: heap,
-            current: Some(current),
+        // 否则和堆顶比较，堆顶更先出堆时交换
+        if let Some(mut inner_iter) = self.iters.peek_mut() {
+            if *current < *inner_iter {
+                std::mem::swap(&mut *inner_iter, current);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<I: 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>> MergeIterator<I> {
+    /// 换方向：当前迭代器走一步，其它迭代器定位到当前键另一侧的第一个键
+    fn switch_direction(&mut self, reverse: bool) -> Result<()> {
+        let Some(mut current) = self.current.take() else {
+            return Ok(());
+        };
+        let mut iters = std::mem::take(&mut self.iters).into_vec();
+        iters.append(&mut self.exhausted);
+        let key = current.1.key();
+        for iter in iters.iter_mut() {
+            if reverse {
+                iter.1.seek_for_prev(key)?;
+            } else {
+                iter.1.seek(key)?;
+            }
+            if iter.1.is_valid() && iter.1.key() == key {
+                if reverse {
+                    iter.1.prev()?;
+                } else {
+                    iter.1.next()?;
+                }
+            }
+        }
+        if reverse {
+            current.1.prev()?;
+        } else {
+            current.1.next()?;
         }
+        iters.push(current);
+        for iter in iters.iter_mut() {
+            iter.2 = reverse;
+        }
+        self.reverse = reverse;
+        self.install(iters);
+        Ok(())
+    }
+}
+
+impl<I: 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>> StorageIterator
+    for MergeIterator<I>
+{
+    type KeyType<'a> = KeySlice<'a>;
+
+    fn key(&self) -> KeySlice<'_> {
+        self.current.as_ref().unwrap().1.key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.current.as_ref().unwrap().1.value()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.current
+            .as_ref()
+            .map(|x| x.1.is_valid())
+            .unwrap_or(false)
+    }
+
+    fn next(&mut self) -> Result<()> {
+        if self.reverse {
+            return self.switch_direction(false);
+        }
+        self.step()
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        if !self.reverse {
+            return self.switch_direction(true);
+        }
+        self.step()
+    }
+
+    fn seek(&mut self, key: KeySlice) -> Result<()> {
+        self.rebuild(false, |iter| iter.seek(key))
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.rebuild(true, |iter| iter.seek_to_last())
+    }
+
+    fn seek_for_prev(&mut self, key: KeySlice) -> Result<()> {
+        self.rebuild(true, |iter| iter.seek_for_prev(key))
+    }
+
+    fn num_active_iterators(&self) -> usize {
+        self.iters
+            .iter()
+            .map(|x| x.1.num_active_iterators())
+            .sum::<usize>()
+            + self
+                .current
+                .as_ref()
+                .map(|x| x.1.num_active_iterators())
+                .unwrap_or(0)
     }
 }
 
 
 impl SsTableIterator {
+    /// 创建一个新的迭代器并定位到第一个键值对。
+    pub fn create_and_seek_to_first(table: Arc<SsTable>) -> Result<Self> {
+        let blk_iter = BlockIterator::create_and_seek_to_first(table.read_block_cached(0)?);
+        Ok(Self {
+            blk_iter,
+            table,
+            blk_idx: 0,
+            readahead: VecDeque::new(),
+        })
+    }
+
+    /// 读取 blk_idx 指向的块。预读的块用完后，一次并行读取接下来的多个块
+    fn next_block(&mut self) -> Result<Arc<Block>> {
+        if let Some(block) = self.readahead.pop_front() {
+            return Ok(block);
+        }
+        let readahead_blocks = self.table.readahead_blocks();
+        if readahead_blocks <= 1 {
+            return self.table.read_block_cached(self.blk_idx);
+        }
+        self.readahead = self
+            .table
+            .read_blocks(self.blk_idx, readahead_blocks)?
+            .into();
+        Ok(self.readahead.pop_front().unwrap())
+    }
+
+    /// 创建一个新的迭代器并定位到最后一个键值对。
+    pub fn create_and_seek_to_last(table: Arc<SsTable>) -> Result<Self> {
+        let blk_idx = table.num_of_blocks() - 1;
+        let blk_iter = BlockIterator::create_and_seek_to_last(table.read_block_cached(blk_idx)?);
+        Ok(Self {
+            blk_iter,
+            table,
+            blk_idx,
+            readahead: VecDeque::new(),
+        })
+    }
+
+    /// 创建一个新的迭代器并定位到 <= `key` 的最后一个键值对。
+    pub fn create_and_seek_for_prev(table: Arc<SsTable>, key: KeySlice) -> Result<Self> {
+        let blk_idx = table.find_block_idx(key)?;
+        let blk_iter =
+            BlockIterator::create_and_seek_for_prev(table.read_block_cached(blk_idx)?, key);
+        Ok(Self {
+            blk_iter,
+            table,
+            blk_idx,
+            readahead: VecDeque::new(),
+        })
+    }
+
     /// 创建一个新的迭代器并查找>= ' key '的第一个键值对。
     pub fn create_and_seek_to_key(table: Arc<SsTable>, key: KeySlice) -> Result<Self> {
         let (blk_idx, blk_iter) = Self::seek_to_key_inner(&table, key)?;
@@ -578,11 +1703,12 @@ impl SsTableIterator {
             blk_iter,
             table,
             blk_idx,
+            readahead: VecDeque::new(),
         };
         Ok(iter)
     }
     fn seek_to_key_inner(table: &Arc<SsTable>, key: KeySlice) -> Result<(usize, BlockIterator)> {
-        let mut blk_idx = table.find_block_idx(key);
+        let mut blk_idx = table.find_block_idx(key)?;
         let mut blk_iter =
             BlockIterator::create_and_seek_to_key(table.read_block_cached(blk_idx)?, key);
         if !blk_iter.is_valid() {
@@ -598,6 +1724,10 @@ impl SsTableIterator {
 //数据类型，是put还是删除Del
 pub enum WriteBatchRecord<T: AsRef<[u8]>> {
     Put(T, T),
+    //带过期时间的put
+    PutWithTtl(T, T, Duration),
+    //合并操作数
+    Merge(T, T),
     Del(T),
 }
 
@@ -621,9 +1751,9 @@ pub struct LsmStorageState {
 //实现 lsm结构数据
 impl LsmStorageState {
     //当创建时会初始化长度
-    fn create(options: &LsmStorageOptions) -> Self {
+    pub(crate) fn create(compaction_options: &CompactionOptions) -> Self {
         //长度等级是符合Leveled和Simple时创建的长度不同
-        let levels = match &options.compaction_options {
+        let levels = match compaction_options {
             CompactionOptions::Leveled(LeveledCompactionOptions { max_levels, .. })
             | CompactionOptions::Simple(SimpleLeveledCompactionOptions { max_levels, .. }) => (1
                 ..=*max_levels)
@@ -643,6 +1773,23 @@ impl LsmStorageState {
             sstables: Default::default(),
         }
     }
+
+    /// 刷新出的SST放在L0最前面，分层（tiered）压缩时作为最新的一层
+    pub(crate) fn add_flushed_sst(&mut self, sst_id: usize, flush_to_l0: bool) {
+        if flush_to_l0 {
+            self.l0_sstables.insert(0, sst_id);
+        } else {
+            self.levels.insert(0, (sst_id, vec![sst_id]));
+        }
+    }
+
+    /// 压缩的输出追加在层的最后，打开所有 SST 之后按第一个键重新排序，让每层的 SST 按键范围排好
+    pub(crate) fn sort_levels(&mut self) {
+        let sstables = &self.sstables;
+        for (_, files) in &mut self.levels {
+            files.sort_by(|a, b| sstables[a].first_key().cmp(sstables[b].first_key()));
+        }
+    }
 }
 //LSM树的存储接口。
 #[derive(Debug, Clone)]
@@ -659,6 +1806,44 @@ pub struct LsmStorageOptions {
     pub enable_wal: bool,
     //是否序列化
     pub serializable: bool,
+    //合并操作符，不设置时不能调用merge
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    //前缀提取器，设置后SST的布隆过滤器同时包含键的前缀
+    pub prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+    //新写入的SST数据块使用的压缩算法
+    pub block_compression: BlockCompression,
+    //不小于这个大小的值单独写进blob文件，不设置时所有值都写在memtable和SST里
+    pub large_value_threshold: Option<usize>,
+    //blob文件里失效数据的比例达到这个值时，后台GC重写其中有效的值并删除它
+    pub blob_gc_garbage_ratio: f64,
+    //块缓存的容量，以字节为单位，设置了block_cache时不使用
+    pub block_cache_capacity_bytes: u64,
+    //和其他引擎共享的块缓存，不设置时按block_cache_capacity_bytes新建一个
+    pub block_cache: Option<Arc<BlockCache>>,
+    //SST的索引块和过滤块放进块缓存，受块缓存容量限制，而不是打开时常驻内存
+    pub cache_index_and_filter_blocks: bool,
+    //开启cache_index_and_filter_blocks时，L0的索引块和过滤块仍然常驻内存，读取L0不用等它们被淘汰后重新加载
+    pub pin_l0_filter_and_index_blocks_in_cache: bool,
+    //分区索引，每个分区包含的数据块个数，只在cache_index_and_filter_blocks时生效
+    pub index_partition_size: Option<usize>,
+    //同时打开的SST文件数上限，超过时关闭最久没用的文件，读取时再重新打开；不设置时所有SST一直打开
+    pub max_open_files: Option<usize>,
+    //用mmap只读映射SST文件，读取未压缩的块时直接引用映射的内存，不拷贝；否则用pread读取
+    pub use_mmap_reads: bool,
+    //L0文件数达到这个值时每次写入都减速，不设置时不减速
+    pub level0_slowdown_writes_trigger: Option<usize>,
+    //L0文件数达到这个值时阻塞写入，直到压缩把它降下来。不压缩的列族和没有压缩线程时不阻塞
+    pub level0_stop_writes_trigger: Option<usize>,
+    //估计的待压缩字节数达到这个值时阻塞写入
+    pub max_pending_compaction_bytes: Option<u64>,
+    //一次压缩最多按输入SST的数据块边界拆成几个子压缩，在各自的线程上并行合并
+    pub max_subcompactions: usize,
+    //刷新、压缩和blob GC每秒最多写入的字节数，刷新优先；不设置或为0时不限速
+    pub background_io_bytes_per_sec: Option<u64>,
+    //Linux上用O_DIRECT读取SST，块只缓存在BlockCache里，不再经过页缓存；其他系统上不生效
+    pub use_direct_io_reads: bool,
+    //扫描SST时一次预读的块数，Linux上用io_uring并行读取；不大于1时不预读
+    pub scan_readahead_blocks: usize,
 }
 
 //实现LsmStorageOptions
@@ -677,12 +1862,41 @@ impl LsmStorageOptions {
             num_memtable_limit: 50,
             //不序列化
             serializable: false,
+            merge_operator: None,
+            prefix_extractor: None,
+            block_compression: BlockCompression::None,
+            large_value_threshold: None,
+            blob_gc_garbage_ratio: 0.5,
+            block_cache_capacity_bytes: 64 << 20,
+            block_cache: None,
+            cache_index_and_filter_blocks: false,
+            pin_l0_filter_and_index_blocks_in_cache: false,
+            index_partition_size: None,
+            max_open_files: None,
+            use_mmap_reads: false,
+            use_direct_io_reads: false,
+            scan_readahead_blocks: 0,
+            level0_slowdown_writes_trigger: None,
+            level0_stop_writes_trigger: None,
+            max_pending_compaction_bytes: None,
+            background_io_bytes_per_sec: None,
+            max_subcompactions: 1,
+        }
+    }
+
+    /// 打开一个SST时索引块和过滤块的加载方式
+    pub(crate) fn table_open_options(&self, is_l0: bool) -> TableOpenOptions {
+        TableOpenOptions {
+            cache_index_and_filter_blocks: self.cache_index_and_filter_blocks
+                && !(is_l0 && self.pin_l0_filter_and_index_blocks_in_cache),
+            index_partition_size: self.index_partition_size,
+            readahead_blocks: self.scan_readahead_blocks,
         }
     }
 }
 
 //数据在内存中的压缩等级
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum CompactionOptions {
     //水平压实与部分压实+动态水平支持(= RocksDB的水平 压实)
     Leveled(LeveledCompactionOptions),
@@ -694,3 +1908,704 @@ pub enum CompactionOptions {
     NoCompaction,
 }
 
+#[cfg(test)]
+mod tests {
+    use std::{ops::Bound, time::Duration};
+
+    use tempfile::tempdir;
+
+    use std::sync::Arc;
+
+    use anyhow::Result;
+    use bytes::Bytes;
+
+    use super::{LsmStorageInner, LsmStorageOptions, LsmStorageState, WriteBatchRecord};
+    use crate::{
+        column_family::{ColumnFamilyOptions, DEFAULT_CF_ID},
+        compact::LeveledCompactionOptions,
+        compression::BlockCompression,
+        fault_injection::FaultInjectionDir,
+        iterators::StorageIterator,
+        lsm_storage::CompactionOptions,
+        merge_operator::MergeOperator,
+        prefix_extractor::FixedPrefix,
+        sstable::{FileObject, SsTable},
+        value::encode_value,
+    };
+
+    #[test]
+    fn test_put_with_ttl() {
+        let dir = tempdir().unwrap();
+        let storage =
+            LsmStorageInner::open(&dir, LsmStorageOptions::default_for_week1_test()).unwrap();
+        storage.put(b"1", b"v1").unwrap();
+        storage
+            .put_with_ttl(b"1", b"v2", Duration::from_millis(50))
+            .unwrap();
+        storage
+            .put_with_ttl(b"2", b"v3", Duration::from_secs(3600))
+            .unwrap();
+        assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"v2");
+        std::thread::sleep(Duration::from_millis(100));
+        // 过期的值会遮住更旧的版本
+        assert_eq!(storage.get(b"1").unwrap(), None);
+        assert_eq!(&storage.get(b"2").unwrap().unwrap()[..], b"v3");
+    }
+
+    /// 用逗号把操作数追加到旧值后面
+    #[derive(Debug)]
+    struct AppendOperator;
+
+    impl MergeOperator for AppendOperator {
+        fn full_merge(
+            &self,
+            _key: &[u8],
+            existing: Option<&[u8]>,
+            operands: &[&[u8]],
+        ) -> Result<Vec<u8>> {
+            let mut parts = existing.into_iter().collect::<Vec<_>>();
+            parts.extend_from_slice(operands);
+            Ok(parts.join(&b","[..]))
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.merge_operator = Some(Arc::new(AppendOperator));
+        let storage = LsmStorageInner::open(&dir, options).unwrap();
+        storage.put(b"1", b"a").unwrap();
+        storage.merge(b"1", b"b").unwrap();
+        storage.merge(b"1", b"c").unwrap();
+        assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"a,b,c");
+        storage.delete(b"1").unwrap();
+        storage.merge(b"1", b"d").unwrap();
+        assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"d");
+        storage.merge(b"2", b"e").unwrap();
+        assert_eq!(&storage.get(b"2").unwrap().unwrap()[..], b"e");
+    }
+
+    #[test]
+    fn test_column_families_share_wal() {
+        let dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.enable_wal = true;
+        let storage = LsmStorageInner::open(&dir, options.clone()).unwrap();
+        let cf_options = ColumnFamilyOptions {
+            block_size: 1024,
+            compaction_options: CompactionOptions::NoCompaction,
+        };
+        let index = storage.create_cf("index", cf_options.clone()).unwrap();
+        let dropped = storage.create_cf("dropped", cf_options).unwrap();
+        let default_cf = storage.default_cf();
+        storage
+            .write_batch_cf(&[
+                (&*default_cf, &WriteBatchRecord::Put(&b"1"[..], &b"row"[..])),
+                (&*index, &WriteBatchRecord::Put(&b"1"[..], &b"idx"[..])),
+                (&*dropped, &WriteBatchRecord::Put(&b"1"[..], &b"gone"[..])),
+            ])
+            .unwrap();
+        storage.drop_cf("dropped").unwrap();
+        assert!(storage
+            .write_batch_cf(&[(&*dropped, &WriteBatchRecord::Del(b"1"))])
+            .is_err());
+        drop(storage);
+
+        let storage = LsmStorageInner::open(&dir, options).unwrap();
+        assert!(storage.cf_handle("dropped").is_none());
+        let index = storage.cf_handle("index").unwrap();
+        assert_eq!(index.options().block_size, 1024);
+        assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"row");
+        assert_eq!(&storage.get_cf(&index, b"1").unwrap().unwrap()[..], b"idx");
+    }
+
+    #[test]
+    fn test_recover_after_crash() {
+        let dir = tempdir().unwrap();
+        let fault_injection = FaultInjectionDir::new(dir.path());
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.enable_wal = true;
+        let storage = LsmStorageInner::open(&dir, options.clone()).unwrap();
+        storage.put(b"1", b"1").unwrap();
+        storage.put(b"2", b"2").unwrap();
+        // 写进WAL但没有fsync，掉电后应该丢掉
+        storage
+            .write_records(&[(DEFAULT_CF_ID, &b"3"[..], encode_value(b"3", None))])
+            .unwrap();
+        drop(storage);
+
+        fault_injection.crash();
+        let storage = LsmStorageInner::open(&dir, options.clone()).unwrap();
+        assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"1");
+        assert_eq!(&storage.get(b"2").unwrap().unwrap()[..], b"2");
+        assert_eq!(storage.get(b"3").unwrap(), None);
+        drop(storage);
+
+        // 恢复过程中新建的WAL和MANIFEST记录也要经得起再次掉电
+        fault_injection.crash();
+        let storage = LsmStorageInner::open(&dir, options).unwrap();
+        assert_eq!(&storage.get(b"2").unwrap().unwrap()[..], b"2");
+    }
+
+    #[test]
+    fn test_recover_corrupted_wal_tail() {
+        let dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.enable_wal = true;
+        let storage = LsmStorageInner::open(&dir, options.clone()).unwrap();
+        // 两个批次写进同一个WAL
+        let wal = storage.path_of_wal(storage.state.read().memtable.id());
+        storage
+            .write_records(&[(DEFAULT_CF_ID, &b"1"[..], encode_value(b"1", None))])
+            .unwrap();
+        storage.sync().unwrap();
+        let first_len = std::fs::metadata(&wal).unwrap().len();
+        storage
+            .write_records(&[(DEFAULT_CF_ID, &b"2"[..], encode_value(b"2", None))])
+            .unwrap();
+        storage.sync().unwrap();
+        drop(storage);
+
+        // 最后一个批次的长度写完了，内容只写了一半
+        let mut data = std::fs::read(&wal).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        std::fs::write(&wal, &data).unwrap();
+        let storage = LsmStorageInner::open(&dir, options.clone()).unwrap();
+        assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"1");
+        assert_eq!(storage.get(b"2").unwrap(), None);
+        assert_eq!(std::fs::metadata(&wal).unwrap().len(), first_len);
+        drop(storage);
+
+        // 中间的批次损坏不是半截写入，打开失败
+        let mut data = std::fs::read(&wal).unwrap();
+        data[first_len as usize - 1] ^= 0xff;
+        data.extend_from_slice(&data.clone());
+        std::fs::write(&wal, &data).unwrap();
+        assert!(LsmStorageInner::open(&dir, options).is_err());
+    }
+
+    #[test]
+    fn test_scan_prefix() {
+        let dir = tempdir().unwrap();
+        let storage =
+            LsmStorageInner::open(&dir, LsmStorageOptions::default_for_week1_test()).unwrap();
+        storage.put(b"t1/a", b"1").unwrap();
+        storage.put(b"t1/b", b"2").unwrap();
+        storage.put(b"t1/c", b"3").unwrap();
+        storage.put(b"t2/a", b"4").unwrap();
+        storage.put(b"t1/b", b"5").unwrap();
+        storage.delete(b"t1/c").unwrap();
+        let mut iter = storage.scan_prefix(b"t1/").unwrap();
+        let mut result = Vec::new();
+        while iter.is_valid() {
+            result.push((iter.key().to_vec(), iter.value().to_vec()));
+            iter.next().unwrap();
+        }
+        assert_eq!(
+            result,
+            vec![
+                (b"t1/a".to_vec(), b"1".to_vec()),
+                (b"t1/b".to_vec(), b"5".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_prefix_bloom() {
+        let dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.prefix_extractor = Some(Arc::new(FixedPrefix(3)));
+        let storage = LsmStorageInner::open(&dir, options).unwrap();
+        storage
+            .write_batch(&[
+                WriteBatchRecord::Put(&b"t1/a"[..], &b"1"[..]),
+                WriteBatchRecord::Put(&b"t1/b"[..], &b"2"[..]),
+                WriteBatchRecord::Put(&b"t3/a"[..], &b"3"[..]),
+            ])
+            .unwrap();
+        storage.flush_all_memtables().unwrap();
+        let state = storage.state.read().clone();
+        let table = &state.sstables[&state.l0_sstables[0]];
+        assert!(storage.prefix_may_match(table, b"t1/").unwrap());
+        assert!(storage.prefix_may_match(table, b"t3/").unwrap());
+        // 键的范围覆盖了t2/，但过滤器里没有这个前缀
+        assert!(!storage.prefix_may_match(table, b"t2/").unwrap());
+        // 不是提取出来的完整前缀时不能用过滤器
+        assert!(storage.prefix_may_match(table, b"t2").unwrap());
+
+        let mut iter = storage.scan_prefix(b"t3/").unwrap();
+        assert_eq!((iter.key(), iter.value()), (&b"t3/a"[..], &b"3"[..]));
+        iter.next().unwrap();
+        assert!(!iter.is_valid());
+        assert!(!storage.scan_prefix(b"t2/").unwrap().is_valid());
+    }
+
+    #[test]
+    fn test_scan_prefix_merge() {
+        let dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.merge_operator = Some(Arc::new(AppendOperator));
+        let storage = LsmStorageInner::open(&dir, options).unwrap();
+        storage.put(b"t1/a", b"1").unwrap();
+        storage.merge(b"t1/a", b"2").unwrap();
+        storage.merge(b"t1/b", b"3").unwrap();
+        storage.put(b"t1/c", b"4").unwrap();
+        storage.delete(b"t1/c").unwrap();
+        storage.merge(b"t1/c", b"5").unwrap();
+        let expected = vec![
+            (b"t1/a".to_vec(), b"1,2".to_vec()),
+            (b"t1/b".to_vec(), b"3".to_vec()),
+            (b"t1/c".to_vec(), b"5".to_vec()),
+        ];
+        let mut iter = storage.scan_prefix(b"t1/").unwrap();
+        let mut result = Vec::new();
+        while iter.is_valid() {
+            result.push((iter.key().to_vec(), iter.value().to_vec()));
+            iter.next().unwrap();
+        }
+        assert_eq!(result, expected);
+        let mut iter = storage.scan_prefix_rev(b"t1/").unwrap();
+        let mut result = Vec::new();
+        while iter.is_valid() {
+            result.push((iter.key().to_vec(), iter.value().to_vec()));
+            iter.prev().unwrap();
+        }
+        result.reverse();
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_scan_prefix_rev() {
+        let dir = tempdir().unwrap();
+        let storage =
+            LsmStorageInner::open(&dir, LsmStorageOptions::default_for_week1_test()).unwrap();
+        for key in ["t1/a", "t1/b", "t1/c", "t1/d", "t2/a"] {
+            storage.put(key.as_bytes(), b"old").unwrap();
+        }
+        storage
+            .force_freeze_memtable(&storage.state_lock.lock())
+            .unwrap();
+        storage.put(b"t1/b", b"new").unwrap();
+        storage.delete(b"t1/c").unwrap();
+        storage.put(b"t0/z", b"x").unwrap();
+
+        let collect = |iter: &mut crate::lsm_iterator::LsmIterator| {
+            let mut result = Vec::new();
+            while iter.is_valid() {
+                result.push((iter.key().to_vec(), iter.value().to_vec()));
+                iter.prev().unwrap();
+            }
+            result
+        };
+        let mut iter = storage.scan_prefix_rev(b"t1/").unwrap();
+        assert_eq!(
+            collect(&mut iter),
+            vec![
+                (b"t1/d".to_vec(), b"old".to_vec()),
+                (b"t1/b".to_vec(), b"new".to_vec()),
+                (b"t1/a".to_vec(), b"old".to_vec()),
+            ]
+        );
+
+        // 正向走到一半再反向
+        let mut iter = storage.scan_prefix(b"t1/").unwrap();
+        iter.next().unwrap();
+        iter.next().unwrap();
+        assert_eq!(iter.key(), b"t1/d");
+        iter.prev().unwrap();
+        assert_eq!(
+            collect(&mut iter),
+            vec![
+                (b"t1/b".to_vec(), b"new".to_vec()),
+                (b"t1/a".to_vec(), b"old".to_vec()),
+            ]
+        );
+
+        let mut iter = storage.scan_prefix(b"t1/").unwrap();
+        iter.seek_for_prev(b"t1/c").unwrap();
+        assert_eq!(iter.key(), b"t1/b");
+        iter.seek_for_prev(b"t1/").unwrap();
+        assert!(!iter.is_valid());
+    }
+
+    #[test]
+    fn test_scan_range() {
+        let dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.compaction_options = CompactionOptions::Leveled(LeveledCompactionOptions {
+            level_size_multiplier: 10,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+            base_level_size_mb: 1,
+        });
+        let storage = LsmStorageInner::open(&dir, options).unwrap();
+        let mut expected = std::collections::BTreeMap::new();
+        let mut write = |filter: fn(usize) -> bool, value: &str| {
+            let keys = (0..30)
+                .filter(|i| filter(*i))
+                .map(|i| format!("k{:02}", i))
+                .collect::<Vec<_>>();
+            let batch = keys
+                .iter()
+                .map(|key| WriteBatchRecord::Put(key.as_bytes(), value.as_bytes()))
+                .collect::<Vec<_>>();
+            storage.write_batch(&batch).unwrap();
+            for key in keys {
+                expected.insert(key.into_bytes(), value.as_bytes().to_vec());
+            }
+        };
+        // 两次刷新压缩到L1，再刷新一个L0，最新的写入留在memtable里
+        write(|_| true, "l1");
+        storage.flush_all_memtables().unwrap();
+        write(|i| i % 3 == 0, "l1b");
+        storage.flush_all_memtables().unwrap();
+        storage.trigger_compaction().unwrap();
+        write(|i| i % 5 == 0, "l0");
+        storage.flush_all_memtables().unwrap();
+        write(|i| i % 7 == 0, "mem");
+        storage.delete(b"k10").unwrap();
+        storage.delete(b"k11").unwrap();
+        expected.remove(&b"k10"[..]);
+        expected.remove(&b"k11"[..]);
+        {
+            let snapshot = storage.state.read();
+            assert_eq!(snapshot.l0_sstables.len(), 1);
+            assert!(!snapshot.levels[0].1.is_empty());
+        }
+
+        let cases = [
+            (Bound::Unbounded, Bound::Unbounded),
+            (Bound::Included(&b"k05"[..]), Bound::Excluded(&b"k20"[..])),
+            (Bound::Excluded(&b"k05"[..]), Bound::Included(&b"k20"[..])),
+            (Bound::Excluded(&b"k09"[..]), Bound::Excluded(&b"k12"[..])),
+            (Bound::Included(&b"k255"[..]), Bound::Unbounded),
+            (Bound::Unbounded, Bound::Included(&b"k03"[..])),
+            (Bound::Included(&b"a"[..]), Bound::Excluded(&b"b"[..])),
+        ];
+        for (lower, upper) in cases {
+            let range = expected
+                .range::<[u8], _>((lower, upper))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect::<Vec<_>>();
+            let mut iter = storage.scan(lower, upper).unwrap();
+            let mut result = Vec::new();
+            while iter.is_valid() {
+                result.push((iter.key().to_vec(), iter.value().to_vec()));
+                iter.next().unwrap();
+            }
+            assert_eq!(result, range, "{:?}", (lower, upper));
+
+            let mut iter = storage.scan_rev(lower, upper).unwrap();
+            let mut result = Vec::new();
+            while iter.is_valid() {
+                result.push((iter.key().to_vec(), iter.value().to_vec()));
+                iter.prev().unwrap();
+            }
+            result.reverse();
+            assert_eq!(result, range, "{:?}", (lower, upper));
+        }
+
+        // 定位不会越过范围
+        let mut iter = storage
+            .scan(Bound::Excluded(b"k05"), Bound::Excluded(b"k20"))
+            .unwrap();
+        iter.seek(b"k00").unwrap();
+        assert_eq!(iter.key(), b"k06");
+        iter.seek_for_prev(b"k29").unwrap();
+        assert_eq!(iter.key(), b"k19");
+        iter.seek_for_prev(b"k11").unwrap();
+        assert_eq!(iter.key(), b"k09");
+        iter.seek_for_prev(b"k05").unwrap();
+        assert!(!iter.is_valid());
+        iter.seek(b"k20").unwrap();
+        assert!(!iter.is_valid());
+    }
+
+    #[test]
+    fn test_iterator_seek() {
+        let dir = tempdir().unwrap();
+        let storage =
+            LsmStorageInner::open(&dir, LsmStorageOptions::default_for_week1_test()).unwrap();
+        for i in 0..10 {
+            storage.put(format!("t/{}", i * 2).as_bytes(), b"old").unwrap();
+        }
+        storage
+            .force_freeze_memtable(&storage.state_lock.lock())
+            .unwrap();
+        storage.put(b"t/3", b"new").unwrap();
+        storage.delete(b"t/4").unwrap();
+
+        // 同一个迭代器反复定位，不用重建
+        let mut iter = storage.scan_prefix(b"t/").unwrap();
+        iter.seek(b"t/4").unwrap();
+        assert_eq!(iter.key(), b"t/6");
+        iter.seek(b"t/2").unwrap();
+        assert_eq!(iter.key(), b"t/2");
+        iter.next().unwrap();
+        assert_eq!((iter.key(), iter.value()), (&b"t/3"[..], &b"new"[..]));
+        iter.seek(b"a").unwrap();
+        assert_eq!(iter.key(), b"t/0");
+        iter.seek(b"t/9").unwrap();
+        assert!(!iter.is_valid());
+
+        // 反向走之后再正向
+        iter.seek_for_prev(b"t/6").unwrap();
+        assert_eq!(iter.key(), b"t/6");
+        iter.prev().unwrap();
+        assert_eq!(iter.key(), b"t/3");
+        iter.next().unwrap();
+        assert_eq!(iter.key(), b"t/6");
+        iter.next().unwrap();
+        assert_eq!(iter.key(), b"t/8");
+    }
+
+    #[test]
+    fn test_large_values() {
+        let dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.enable_wal = true;
+        options.large_value_threshold = Some(100_000);
+        let inline = vec![b'a'; 70_000];
+        let blob = vec![b'b'; 300_000];
+        {
+            let storage = LsmStorageInner::open(&dir, options.clone()).unwrap();
+            storage.put(b"inline", &inline).unwrap();
+            storage.put(b"blob", &blob).unwrap();
+            assert_eq!(&storage.get(b"inline").unwrap().unwrap()[..], &inline[..]);
+            assert_eq!(&storage.get(b"blob").unwrap().unwrap()[..], &blob[..]);
+        }
+        let storage = LsmStorageInner::open(&dir, options).unwrap();
+        assert_eq!(&storage.get(b"inline").unwrap().unwrap()[..], &inline[..]);
+        assert_eq!(&storage.get(b"blob").unwrap().unwrap()[..], &blob[..]);
+        storage.put(b"blob2", &blob).unwrap();
+        let mut iter = storage.scan_prefix(b"blob").unwrap();
+        let mut result = Vec::new();
+        while iter.is_valid() {
+            result.push((iter.key().to_vec(), iter.value().len()));
+            iter.next().unwrap();
+        }
+        assert_eq!(
+            result,
+            vec![(b"blob".to_vec(), 300_000), (b"blob2".to_vec(), 300_000)]
+        );
+    }
+
+    #[test]
+    fn test_blob_gc() {
+        let dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.enable_wal = true;
+        options.large_value_threshold = Some(10);
+        // 每个文件放两条记录
+        options.target_sst_size = 200;
+        let storage = LsmStorageInner::open(&dir, options).unwrap();
+        storage.put(b"a", &[b'1'; 100]).unwrap();
+        storage.put(b"b", &[b'2'; 100]).unwrap();
+        storage.put(b"a", &[b'3'; 100]).unwrap();
+        storage.put(b"c", &[b'4'; 100]).unwrap();
+        storage.put(b"d", &[b'5'; 100]).unwrap();
+        let files = storage.blob_store.sealed_file_ids().unwrap();
+        assert_eq!(files.len(), 2);
+        // 第一个文件一半是垃圾，第二个文件全部有效
+        assert_eq!(storage.gc_blob_files().unwrap(), 1);
+        assert_eq!(storage.blob_store.sealed_file_ids().unwrap(), vec![files[1]]);
+        assert_eq!(&storage.get(b"a").unwrap().unwrap()[..], &[b'3'; 100]);
+        assert_eq!(&storage.get(b"b").unwrap().unwrap()[..], &[b'2'; 100]);
+        assert_eq!(&storage.get(b"c").unwrap().unwrap()[..], &[b'4'; 100]);
+        assert_eq!(&storage.get(b"d").unwrap().unwrap()[..], &[b'5'; 100]);
+    }
+
+    #[test]
+    fn test_blob_gc_flushed_values() {
+        let dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.large_value_threshold = Some(10);
+        options.target_sst_size = 200;
+        let storage = LsmStorageInner::open(&dir, options).unwrap();
+        storage.put(b"a", &[b'1'; 100]).unwrap();
+        storage.put(b"b", &[b'2'; 100]).unwrap();
+        storage.put(b"a", &[b'3'; 100]).unwrap();
+        storage.put(b"c", &[b'4'; 100]).unwrap();
+        storage.put(b"d", &[b'5'; 100]).unwrap();
+        // 指针都在SST里
+        storage.flush_all_memtables().unwrap();
+        let files = storage.blob_store.sealed_file_ids().unwrap();
+        let reader = storage.scan_prefix(b"").unwrap();
+
+        assert_eq!(storage.gc_blob_files().unwrap(), 0);
+        assert_eq!(storage.blob_store.sealed_file_ids().unwrap(), vec![files[1]]);
+        // GC之前开始的读者还可能读到旧文件
+        let path = LsmStorageInner::path_of_blob_static(&dir, files[0]);
+        assert!(path.exists());
+        let mut values = Vec::new();
+        let mut iter = reader;
+        while iter.is_valid() {
+            values.push(iter.value()[0]);
+            iter.next().unwrap();
+        }
+        assert_eq!(values, b"3245");
+        drop(iter);
+        // 没有WAL，新指针刷新到SST之前旧文件不能删除
+        assert_eq!(storage.delete_obsolete_files().unwrap(), 0);
+        storage.flush_all_memtables().unwrap();
+        assert_eq!(storage.delete_obsolete_files().unwrap(), 1);
+        assert!(!path.exists());
+        assert_eq!(&storage.get(b"b").unwrap().unwrap()[..], &[b'2'; 100]);
+        assert_eq!(&storage.get(b"c").unwrap().unwrap()[..], &[b'4'; 100]);
+    }
+
+    #[test]
+    fn test_blob_gc_after_tombstone_compacted() {
+        let dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.enable_wal = true;
+        options.large_value_threshold = Some(10);
+        options.target_sst_size = 200;
+        options.compaction_options = CompactionOptions::Leveled(LeveledCompactionOptions {
+            level_size_multiplier: 10,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+            base_level_size_mb: 1,
+        });
+        let storage = LsmStorageInner::open(&dir, options).unwrap();
+        storage.put(b"a", &[b'1'; 100]).unwrap();
+        storage.put(b"b", &[b'2'; 100]).unwrap();
+        storage.put(b"c", &[b'3'; 100]).unwrap();
+        storage.delete(b"a").unwrap();
+        storage.delete(b"b").unwrap();
+        storage.flush_all_memtables().unwrap();
+        let files = storage.blob_store.sealed_file_ids().unwrap();
+        assert_eq!(files.len(), 1);
+        // 压缩到最底层，墓碑和被删除的值都不再写出
+        storage.trigger_compaction().unwrap();
+        assert!(storage.state.read().l0_sstables.is_empty());
+
+        assert_eq!(storage.gc_blob_files().unwrap(), 1);
+        assert!(!LsmStorageInner::path_of_blob_static(&dir, files[0]).exists());
+        assert!(storage.get(b"a").unwrap().is_none());
+        assert_eq!(&storage.get(b"c").unwrap().unwrap()[..], &[b'3'; 100]);
+    }
+
+    #[test]
+    fn test_multi_get() {
+        let dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.merge_operator = Some(Arc::new(AppendOperator));
+        options.large_value_threshold = Some(100);
+        let storage = LsmStorageInner::open(&dir, options).unwrap();
+        storage.put(b"a", b"1").unwrap();
+        storage.merge(b"a", b"2").unwrap();
+        storage.put(b"b", &[b'x'; 200]).unwrap();
+        storage.put(b"c", b"3").unwrap();
+        storage.delete(b"c").unwrap();
+        storage.merge(b"d", b"4").unwrap();
+        let result = storage
+            .multi_get(&[b"d", b"a", b"missing", b"b", b"c", b"a"])
+            .unwrap();
+        assert_eq!(
+            result,
+            vec![
+                Some(Bytes::from_static(b"4")),
+                Some(Bytes::from_static(b"1,2")),
+                None,
+                Some(Bytes::from(vec![b'x'; 200])),
+                None,
+                Some(Bytes::from_static(b"1,2")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_flush_with_compression() {
+        let dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.block_compression = BlockCompression::Lz4;
+        let storage = LsmStorageInner::open(&dir, options.clone()).unwrap();
+        let cf = storage
+            .create_cf(
+                "cf",
+                ColumnFamilyOptions {
+                    block_size: 1024,
+                    compaction_options: CompactionOptions::NoCompaction,
+                },
+            )
+            .unwrap();
+        let value = br#"{"tenant":"t1","table":"users","name":"alice"}"#;
+        for i in 0..10 {
+            storage.put(format!("k{}", i).as_bytes(), value).unwrap();
+        }
+        storage.delete(b"k0").unwrap();
+        storage
+            .write_batch_cf(&[(&*cf, &WriteBatchRecord::Put(&b"k"[..], &value[..]))])
+            .unwrap();
+        storage.flush_all_memtables().unwrap();
+
+        // 每个列族的SST都按配置压缩
+        for cf in storage.column_families.read().values() {
+            let state = cf.state.read().clone();
+            assert!(state.imm_memtables.is_empty());
+            assert!(!state.l0_sstables.is_empty());
+            for table in state.sstables.values() {
+                let raw =
+                    std::fs::read(LsmStorageInner::path_of_sst_static(&dir, table.sst_id()))
+                        .unwrap();
+                assert_eq!(
+                    raw[table.block_meta_offset - 5],
+                    BlockCompression::Lz4.codec_id()
+                );
+            }
+        }
+        drop(storage);
+
+        let storage = LsmStorageInner::open(&dir, options).unwrap();
+        assert!(storage.state.read().imm_memtables.is_empty());
+        assert_eq!(storage.get(b"k0").unwrap(), None);
+        for i in 1..10 {
+            let key = format!("k{}", i);
+            assert_eq!(&storage.get(key.as_bytes()).unwrap().unwrap()[..], value);
+        }
+        let cf = storage.cf_handle("cf").unwrap();
+        assert_eq!(&storage.get_cf(&cf, b"k").unwrap().unwrap()[..], value);
+        assert_eq!(storage.get_cf(&cf, b"k1").unwrap(), None);
+    }
+
+    #[test]
+    fn test_get_from_sst() {
+        let dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.merge_operator = Some(Arc::new(AppendOperator));
+        let storage = LsmStorageInner::open(&dir, options).unwrap();
+        // 假装刷新出了一个SST
+        let id = storage.next_sst_id();
+        let path = LsmStorageInner::path_of_sst_static(&dir, id);
+        let (a, b, c) = (
+            encode_value(b"1", None),
+            encode_value(b"2", None),
+            encode_value(b"3", None),
+        );
+        SsTable::write_for_testing(&path, &[(b"a", &a), (b"b", &b), (b"c", &c)]).unwrap();
+        let table = SsTable::open(id, None, FileObject::open(&path).unwrap()).unwrap();
+        {
+            let mut guard = storage.state.write();
+            let mut snapshot = LsmStorageState::clone(&guard);
+            snapshot.l0_sstables.insert(0, id);
+            snapshot.sstables.insert(id, Arc::new(table));
+            *guard = Arc::new(snapshot);
+        }
+        storage.delete(b"b").unwrap();
+        storage.merge(b"c", b"4").unwrap();
+        assert_eq!(&storage.get(b"a").unwrap().unwrap()[..], b"1");
+        assert_eq!(storage.get(b"b").unwrap(), None);
+        assert_eq!(&storage.get(b"c").unwrap().unwrap()[..], b"3,4");
+        assert_eq!(storage.get(b"d").unwrap(), None);
+        assert_eq!(
+            storage.multi_get(&[b"c", b"a"]).unwrap(),
+            vec![
+                Some(Bytes::from_static(b"3,4")),
+                Some(Bytes::from_static(b"1"))
+            ]
+        );
+    }
+}
@@ -1,13 +1,35 @@
+pub mod blob;
 pub mod block;
+pub mod block_cache;
+pub mod bloom;
+pub mod checkpoint;
+pub mod column_family;
 pub mod compact;
+pub mod compression;
+pub mod db_lock;
+#[cfg(test)]
+mod fault_injection;
+pub mod fs_util;
+#[cfg(target_os = "linux")]
+pub mod direct_io;
 pub mod iterators;
 pub mod key;
+pub mod lsm_iterator;
+pub mod live_files;
 pub mod lsm_storage;
 pub mod sstable;
+pub mod table_cache;
 pub mod two_merge_iterator;
 pub mod minilsm;
 pub mod memtable;
+pub mod prefix_extractor;
+pub mod rate_limiter;
+pub mod secondary;
+pub mod merge_operator;
 pub mod sql;
+pub mod value;
+pub mod varint;
+pub mod write_stall;
 
 use anyhow::{Context, Result};
 use bytes::{BufMut, Bytes};
@@ -4,10 +4,9 @@ use crossbeam_skiplist::map::Entry;
 use crossbeam_skiplist::SkipMap;
 use ouroboros::self_referencing;
 use std::fs::{File, OpenOptions};
-use std::hash::Hasher;
-use std::io::{BufWriter, Read, Write};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
 use std::ops::Bound;
-use std::path::{self, Path};
+use std::path::{self, Path, PathBuf};
 use std::sync::Arc;
 
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt};
@@ -18,8 +17,12 @@ use parking_lot::Mutex;
 use std::sync::atomic::AtomicUsize;
 use tempfile::tempdir;
 
+use crate::column_family::DEFAULT_CF_ID;
+use crate::fs_util::{sync_file, sync_parent_dir};
 use crate::iterators::StorageIterator;
 use crate::key::KeySlice;
+use crate::sstable::SsTableBuilder;
+use crate::varint::{get_varint, put_varint};
 
 pub struct MemTable {
     map: Arc<SkipMap<Bytes, Bytes>>,
@@ -49,9 +52,21 @@ impl MemTable {
             approximate_size: Arc::new(AtomicUsize::new(0)),
         })
     }
+    /// 创建一个和 `other` 共用同一个 WAL 的内存表，所有列族的记录都写进这一个 WAL。
+    pub fn create_sharing_wal(id: usize, other: &MemTable) -> Self {
+        Self {
+            id,
+            map: Arc::new(SkipMap::new()),
+            wal: other.wal.clone(),
+            approximate_size: Arc::new(AtomicUsize::new(0)),
+        }
+    }
     pub fn id(&self) -> usize {
         self.id
     }
+    pub(crate) fn wal(&self) -> Option<&Wal> {
+        self.wal.as_ref()
+    }
     /// 创建一个新的 mem-table.
     pub fn create_wal(id: usize, path: impl AsRef<Path>) -> Result<Self> {
         print!("进入创建{:?}", id);
@@ -69,30 +84,60 @@ impl MemTable {
         }
         Ok(())
     }
-   ///创建一个内存表
-    pub fn recover_from_wal(id: usize, path: impl AsRef<Path>) -> Result<Self> {
-        let map = Arc::new(SkipMap::new());
-        Ok(Self {
-            id,
-            wal: Some(Wal::recover(path.as_ref(), &map)?),
-            map,
-            approximate_size: Arc::new(AtomicUsize::new(0)),
-        })
+   ///从共用的WAL恢复每个列族的内存表，返回的顺序和 `cf_ids` 相同，不认识的列族（已删除）被跳过
+    pub fn recover_from_wal(id: usize, path: impl AsRef<Path>, cf_ids: &[usize]) -> Result<Vec<Self>> {
+        Self::load_wal(id, cf_ids, |apply| Wal::recover(path.as_ref(), apply).map(Some))
+    }
+    /// 和 `recover_from_wal` 一样，但只读地重放WAL，不打开写句柄，恢复出的内存表不带WAL
+    pub fn replay_wal(id: usize, path: impl AsRef<Path>, cf_ids: &[usize]) -> Result<Vec<Self>> {
+        Self::load_wal(id, cf_ids, |apply| Wal::replay(path.as_ref(), apply).map(|()| None))
+    }
+    fn load_wal(
+        id: usize,
+        cf_ids: &[usize],
+        read: impl FnOnce(&mut dyn FnMut(usize, Bytes, Bytes)) -> Result<Option<Wal>>,
+    ) -> Result<Vec<Self>> {
+        let maps = cf_ids
+            .iter()
+            .map(|_| Arc::new(SkipMap::new()))
+            .collect::<Vec<_>>();
+        let wal = read(&mut |cf_id, key, value| {
+            if let Some(idx) = cf_ids.iter().position(|x| *x == cf_id) {
+                maps[idx].insert(key, value);
+            }
+        })?;
+        Ok(maps
+            .into_iter()
+            .map(|map| Self {
+                id,
+                wal: wal.clone(),
+                map,
+                approximate_size: Arc::new(AtomicUsize::new(0)),
+            })
+            .collect())
     }
 
     pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        if let Some(ref wal) = self.wal {
+            print!("进入put区02");
+            wal.put(key, value)?;
+        }
+        self.put_without_wal(key, value);
+        Ok(())
+    }
+    /// 只写入内存，WAL 由调用者通过 `Wal::put_batch` 统一写入。
+    pub(crate) fn put_without_wal(&self, key: &[u8], value: &[u8]) {
         let estimated_size = key.len() + value.len();
         self.map
             .insert(Bytes::copy_from_slice(key), Bytes::copy_from_slice(value));
         // print!("获取的key值为{:?}", &key);
         self.approximate_size
             .fetch_add(estimated_size, std::sync::atomic::Ordering::Relaxed);
-
-        if let Some(ref wal) = self.wal {
-            print!("进入put区02");
-            wal.put(key, value)?;
-        }
-        Ok(())
+    }
+    /// 获取键的范围在 `lower` 和 `upper` 之间的迭代器
+    pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> MemTableIterator {
+        let bounds = (map_bound(lower), map_bound(upper));
+        MemTableIterator::build(self.map.clone(), bounds.clone(), bounds, false)
     }
     pub fn for_testing_get_slice(&self, key: &[u8]) -> Option<Bytes> {
         self.get(key)
@@ -100,6 +145,13 @@ impl MemTable {
     pub fn get(&self, key: &[u8]) -> Option<Bytes> {
         self.map.get(key).map(|e| e.value().clone())
     }
+    /// 按键的顺序把所有条目写进 `builder`，刷新到 SST 时使用
+    pub fn flush(&self, builder: &mut SsTableBuilder) -> Result<()> {
+        for entry in self.map.iter() {
+            builder.add(KeySlice::from_slice(entry.key()), entry.value());
+        }
+        Ok(())
+    }
     ///仅在关闭数据库时使用此函数
     pub fn is_empty(&self) -> bool {
         self.map.is_empty()
@@ -112,42 +164,59 @@ impl MemTable {
 }
 
 
+/// 所有列族共用的WAL。每次写入一个批次，恢复时批次要么全部生效要么全部丢弃：
+/// | batch_len (u32) | checksum (u32) | cf_id (varint) | key_len (varint) | key | value_len (varint) | value | ... |
+#[derive(Clone)]
 pub struct Wal {
     file: Arc<Mutex<BufWriter<File>>>,
+    path: Arc<PathBuf>,
 }
 impl Wal {
     pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.put_batch(&[(DEFAULT_CF_ID, key, value)])
+    }
+
+    /// 把 `(列族id, key, value)` 作为一个批次写入
+    pub fn put_batch(&self, records: &[(usize, &[u8], &[u8])]) -> Result<()> {
         let mut file = self.file.lock();
         print!("file数据为{:?}", file);
-        let mut buf: Vec<u8> =
-            Vec::with_capacity(key.len() + value.len() + std::mem::size_of::<u16>());
-        let mut hasher = crc32fast::Hasher::new();
-        hasher.write_u16(key.len() as u16);
-        buf.put_u16(key.len() as u16);
-        hasher.write(key);
-        buf.put_slice(key);
-        hasher.write_u16(value.len() as u16);
-        buf.put_u16(value.len() as u16);
-        buf.put_slice(value);
-        hasher.write(value);
-        buf.put_u32(hasher.finalize());
+        let mut body: Vec<u8> = Vec::new();
+        for (cf_id, key, value) in records {
+            put_varint(&mut body, *cf_id as u64);
+            put_varint(&mut body, key.len() as u64);
+            body.put_slice(key);
+            put_varint(&mut body, value.len() as u64);
+            body.put_slice(value);
+        }
+        let mut buf: Vec<u8> = Vec::with_capacity(body.len() + std::mem::size_of::<u32>() * 2);
+        buf.put_u32(body.len() as u32);
+        buf.put_u32(crc32fast::hash(&body));
+        buf.put_slice(&body);
         file.write_all(&buf)?;
         Ok(())
     }
 
     pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .read(true)
+            .create_new(true)
+            .write(true)
+            .open(path)
+            .context("failed to create WAL")?;
+        // 目录项落盘之后才能在MANIFEST里引用它
+        sync_parent_dir(path)?;
         Ok(Self {
-            file: Arc::new(Mutex::new(BufWriter::new(
-                OpenOptions::new()
-                    .read(true)
-                    .create_new(true)
-                    .write(true)
-                    .open(path)
-                    .context("failed to create WAL")?,
-            ))),
+            file: Arc::new(Mutex::new(BufWriter::new(file))),
+            path: Arc::new(path.to_path_buf()),
         })
     }
-    pub fn recover(path: impl AsRef<Path>, skiplist: &SkipMap<Bytes, Bytes>) -> Result<Self> {
+    /// 按批次重放WAL，`apply` 收到每条记录的列族id、key和value。
+    /// 文件末尾没写完或校验失败的批次（崩溃时的半截写入）会被整体丢弃，并从文件里截掉。
+    pub fn recover(
+        path: impl AsRef<Path>,
+        apply: impl FnMut(usize, Bytes, Bytes),
+    ) -> Result<Self> {
         let path = path.as_ref();
         let mut file = OpenOptions::new()
             .read(true)
@@ -156,34 +225,95 @@ impl Wal {
             .context("failed to recover from WAL")?;
         let mut buf = Vec::new();
         file.read_to_end(&mut buf)?;
-        let mut rbuf: &[u8] = buf.as_slice();
-        while rbuf.has_remaining() {
-            let mut hasher = crc32fast::Hasher::new();
-            let key_len = rbuf.get_u16() as usize;
-            hasher.write_u16(key_len as u16);
-            let key = Bytes::copy_from_slice(&rbuf[..key_len]);
-            hasher.write(&key);
-            rbuf.advance(key_len);
-            let value_len = rbuf.get_u16() as usize;
-            hasher.write_u16(value_len as u16);
-            let value = Bytes::copy_from_slice(&rbuf[..value_len]);
-            hasher.write(&value);
-            rbuf.advance(value_len);
-            let checksum = rbuf.get_u32();
-            if hasher.finalize() != checksum {
-                bail!("checksum mismatch");
-            }
-            skiplist.insert(key, value);
+        let valid_len = Self::replay_buf(&buf, apply)?;
+        // 截掉半截的批次，之后追加的批次才能被读到
+        if valid_len < buf.len() {
+            file.set_len(valid_len as u64)?;
+            sync_file(&file, path)?;
         }
         Ok(Self {
             file: Arc::new(Mutex::new(BufWriter::new(file))),
+            path: Arc::new(path.to_path_buf()),
         })
     }
+    /// 只读地重放WAL，不修改文件。另一个实例可能正在追加，末尾没写完的批次同样被丢弃。
+    pub fn replay(path: impl AsRef<Path>, apply: impl FnMut(usize, Bytes, Bytes)) -> Result<()> {
+        let buf = std::fs::read(path.as_ref()).context("failed to replay WAL")?;
+        Self::replay_buf(&buf, apply)?;
+        Ok(())
+    }
+    /// 只读地从 `offset` 开始重放WAL，`apply` 每次收到一个批次的全部记录，返回 false 时不应用它并停在它之前。
+    /// 返回下次继续重放的位置，末尾没写完的批次留到下次。从实例用它增量地跟上主实例的写入。
+    pub fn replay_from(
+        path: impl AsRef<Path>,
+        offset: u64,
+        apply: impl FnMut(Vec<(usize, Bytes, Bytes)>) -> bool,
+    ) -> Result<u64> {
+        let mut file = File::open(path.as_ref()).context("failed to replay WAL")?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        let len = Self::replay_batches(&buf, apply)?;
+        Ok(offset + len as u64)
+    }
+    /// 重放完整的批次，返回它们占用的长度。校验失败的批次在文件末尾时当作没写完，在中间时报错
+    fn replay_buf(buf: &[u8], mut apply: impl FnMut(usize, Bytes, Bytes)) -> Result<usize> {
+        Self::replay_batches(buf, |batch| {
+            for (cf_id, key, value) in batch {
+                apply(cf_id, key, value);
+            }
+            true
+        })
+    }
+    fn replay_batches(
+        buf: &[u8],
+        mut apply: impl FnMut(Vec<(usize, Bytes, Bytes)>) -> bool,
+    ) -> Result<usize> {
+        let mut rbuf: &[u8] = buf;
+        while rbuf.remaining() >= std::mem::size_of::<u32>() * 2 {
+            let mut header = rbuf;
+            let batch_len = header.get_u32() as usize;
+            let checksum = header.get_u32();
+            if header.remaining() < batch_len {
+                break;
+            }
+            let mut body = &header[..batch_len];
+            if crc32fast::hash(body) != checksum {
+                if header.remaining() == batch_len {
+                    break;
+                }
+                bail!("checksum mismatch");
+            }
+            let mut batch = Vec::new();
+            while body.has_remaining() {
+                let cf_id = get_varint(&mut body) as usize;
+                let key_len = get_varint(&mut body) as usize;
+                let key = Bytes::copy_from_slice(&body[..key_len]);
+                body.advance(key_len);
+                let value_len = get_varint(&mut body) as usize;
+                let value = Bytes::copy_from_slice(&body[..value_len]);
+                body.advance(value_len);
+                batch.push((cf_id, key, value));
+            }
+            if !apply(batch) {
+                break;
+            }
+            rbuf = &header[batch_len..];
+        }
+        Ok(buf.len() - rbuf.remaining())
+    }
     pub fn sync(&self) -> Result<()> {
         let mut file = self.file.lock();
         file.flush()?;
-        file.get_mut().sync_all()?;
-        Ok(())
+        sync_file(file.get_ref(), &self.path)
+    }
+}
+
+pub(crate) fn map_bound(bound: Bound<&[u8]>) -> Bound<Bytes> {
+    match bound {
+        Bound::Included(x) => Bound::Included(Bytes::copy_from_slice(x)),
+        Bound::Excluded(x) => Bound::Excluded(Bytes::copy_from_slice(x)),
+        Bound::Unbounded => Bound::Unbounded,
     }
 }
 
@@ -201,9 +331,57 @@ pub struct MemTableIterator {
     iter: SkipMapRangeIter<'this>,
     /// 存储当前的键值对。
     item: (Bytes, Bytes),
+    /// `scan` 时给的范围，重新定位时不能超出
+    bounds: (Bound<Bytes>, Bound<Bytes>),
+    /// 是否在反向迭代
+    reverse: bool,
 }
 
 impl MemTableIterator {
+    /// 在 `range` 上创建迭代器并读出第一个键值对，`reverse` 时从后往前读
+    fn build(
+        map: Arc<SkipMap<Bytes, Bytes>>,
+        bounds: (Bound<Bytes>, Bound<Bytes>),
+        range: (Bound<Bytes>, Bound<Bytes>),
+        reverse: bool,
+    ) -> Self {
+        let mut iter = MemTableIteratorBuilder {
+            map,
+            iter_builder: |map| map.range(range),
+            item: (Bytes::new(), Bytes::new()),
+            bounds,
+            reverse,
+        }
+        .build();
+        iter.advance();
+        iter
+    }
+
+    /// 沿当前方向读出下一个键值对
+    fn advance(&mut self) {
+        let reverse = *self.borrow_reverse();
+        let entry = self.with_iter_mut(|iter| {
+            MemTableIterator::entry_to_item(if reverse { iter.next_back() } else { iter.next() })
+        });
+        self.with_mut(|x| *x.item = entry);
+    }
+
+    /// 在 `range` 上按 `reverse` 的方向重新定位
+    fn reposition(&mut self, range: (Bound<Bytes>, Bound<Bytes>), reverse: bool) {
+        let map = self.borrow_map().clone();
+        let bounds = self.borrow_bounds().clone();
+        *self = Self::build(map, bounds, range, reverse);
+    }
+
+    /// `key` 是否在 `scan` 的下界之下
+    fn below_lower(&self, key: &[u8]) -> bool {
+        match &self.borrow_bounds().0 {
+            Bound::Included(lower) => key < &lower[..],
+            Bound::Excluded(lower) => key <= &lower[..],
+            Bound::Unbounded => false,
+        }
+    }
+
     fn entry_to_item(entry: Option<Entry<'_, Bytes, Bytes>>) -> (Bytes, Bytes) {
         entry
             .map(|x| (x.key().clone(), x.value().clone()))
@@ -227,8 +405,59 @@ impl StorageIterator for MemTableIterator {
     }
 
     fn next(&mut self) -> Result<()> {
-        let entry = self.with_iter_mut(|iter| MemTableIterator::entry_to_item(iter.next()));
-        self.with_mut(|x| *x.item = entry);
+        if *self.borrow_reverse() {
+            let key = self.borrow_item().0.clone();
+            let upper = self.borrow_bounds().1.clone();
+            self.reposition((Bound::Excluded(key), upper), false);
+        } else {
+            self.advance();
+        }
+        Ok(())
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        if *self.borrow_reverse() {
+            self.advance();
+        } else {
+            let key = self.borrow_item().0.clone();
+            let lower = self.borrow_bounds().0.clone();
+            self.reposition((lower, Bound::Excluded(key)), true);
+        }
+        Ok(())
+    }
+
+    fn seek(&mut self, key: KeySlice) -> Result<()> {
+        let key = key.raw_ref();
+        let (lower, upper) = self.borrow_bounds().clone();
+        let lower = if self.below_lower(key) {
+            lower
+        } else {
+            Bound::Included(Bytes::copy_from_slice(key))
+        };
+        self.reposition((lower, upper), false);
+        Ok(())
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        let bounds = self.borrow_bounds().clone();
+        self.reposition(bounds, true);
+        Ok(())
+    }
+
+    fn seek_for_prev(&mut self, key: KeySlice) -> Result<()> {
+        let key = key.raw_ref();
+        if self.below_lower(key) {
+            // 范围是空的，定位成无效
+            self.reposition((Bound::Excluded(Bytes::new()), Bound::Excluded(Bytes::new())), true);
+            return Ok(());
+        }
+        let (lower, upper) = self.borrow_bounds().clone();
+        let upper = match upper {
+            Bound::Included(upper) if &upper[..] <= key => Bound::Included(upper),
+            Bound::Excluded(upper) if &upper[..] <= key => Bound::Excluded(upper),
+            _ => Bound::Included(Bytes::copy_from_slice(key)),
+        };
+        self.reposition((lower, upper), true);
         Ok(())
     }
 }
@@ -0,0 +1,13 @@
+use std::fmt::Debug;
+
+use anyhow::Result;
+
+/// 合并操作符，在 `LsmStorageOptions::merge_operator` 中注册。
+/// `LsmStorageInner::merge` 只写入操作数，读取时才把操作数和更旧的值合并，
+/// 这样计数器、追加列表之类的更新不需要先读再写。
+pub trait MergeOperator: Send + Sync + Debug {
+    /// 把 `existing`（最近的基础值，不存在或已删除时为 `None`）和
+    /// 按从旧到新排列的 `operands` 合并成新的值。
+    fn full_merge(&self, key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]])
+        -> Result<Vec<u8>>;
+}
@@ -1,8 +1,17 @@
-use std::{path::Path, sync::Arc};
+use std::{
+    ops::Bound,
+    path::Path,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
 
 use parking_lot::Mutex;
-use anyhow::{bail, Context, Result};
-use crate::lsm_storage::{LsmStorageInner, LsmStorageOptions};
+use anyhow::{anyhow, bail, Result};
+use crate::block_cache::BlockCacheStats;
+use crate::column_family::{ColumnFamily, ColumnFamilyOptions};
+use crate::lsm_iterator::LsmIterator;
+use crate::lsm_storage::{LsmStorageInner, LsmStorageOptions, OpenMode};
+use crate::write_stall::WriteStallStats;
 
 /// ' LsmStorageInner '的包装器和MiniLSM的用户界面。
 /// minilsm 在内存中是不是要刷新频繁一点，加大cpu和一级缓存的使用效率
@@ -16,6 +25,14 @@ pub struct MiniLsm {
     compaction_notifier: crossbeam_channel::Sender<()>,
     /// 压缩线程的句柄
     compaction_thread: Mutex<Option<std::thread::JoinHandle<()>>>,
+    /// 通知blob GC线程停止工作。
+    blob_gc_notifier: crossbeam_channel::Sender<()>,
+    /// blob GC线程的句柄，没有开启键值分离时为空
+    blob_gc_thread: Mutex<Option<std::thread::JoinHandle<()>>>,
+    /// 通知从实例跟上主实例的线程停止工作。
+    catch_up_notifier: crossbeam_channel::Sender<()>,
+    /// 跟上主实例的线程，不是从实例时为空
+    catch_up_thread: Mutex<Option<std::thread::JoinHandle<()>>>,
 }
 
 // trait Drop 是一个特殊的trait，用于定义当某个类型的值离开其作用域（即不再被使用）时应该执行的清理操作
@@ -24,9 +41,9 @@ pub struct MiniLsm {
 // drop 方法并不直接暴露给开发者，而是通过实现 Drop trait 的 drop 函数来定义的。
 impl Drop for MiniLsm {
     //停止工作
+    // 后台线程持有 `inner`，等它们退出后目录锁才会随 `inner` 释放
     fn drop(&mut self) {
-        self.compaction_notifier.send(()).ok();
-        self.flush_notifier.send(()).ok();
+        self.stop_threads().ok();
     }
 }
 
@@ -34,17 +51,384 @@ impl Drop for MiniLsm {
 impl MiniLsm {
     //通过加载现有目录或创建新目录启动存储引擎
     pub fn open(path: impl AsRef<Path>, options: LsmStorageOptions) -> Result<Arc<Self>> {
-        let inner = Arc::new(LsmStorageInner::open(path, options)?);
+        Self::start(Arc::new(LsmStorageInner::open(path, options)?))
+    }
+
+    /// 只读打开已有的数据库：重放MANIFEST和WAL，不写MANIFEST、不创建文件、不加锁，
+    /// 可以和正在写入的实例同时打开。打开后看不到写入者新写的数据，写入返回错误。
+    pub fn open_read_only(path: impl AsRef<Path>, options: LsmStorageOptions) -> Result<Arc<Self>> {
+        Self::start(Arc::new(LsmStorageInner::open_with_mode(
+            path,
+            options,
+            OpenMode::ReadOnly,
+        )?))
+    }
+
+    /// 作为从实例只读打开 `primary_path`，后台线程定期读取主实例的MANIFEST和WAL，跟上新刷新的SST和新写入的数据。
+    /// `secondary_path` 是从实例自己的目录，只放它的LOCK文件，每个从实例要用不同的目录。
+    pub fn open_as_secondary(
+        primary_path: impl AsRef<Path>,
+        secondary_path: impl AsRef<Path>,
+        options: LsmStorageOptions,
+    ) -> Result<Arc<Self>> {
+        Self::start(Arc::new(LsmStorageInner::open_with_mode(
+            primary_path,
+            options,
+            OpenMode::Secondary(secondary_path.as_ref().to_path_buf()),
+        )?))
+    }
+
+    // 启动后台线程，只读实例不启动刷新、压缩和blob GC
+    fn start(inner: Arc<LsmStorageInner>) -> Result<Arc<Self>> {
         let (tx1, rx) = crossbeam_channel::unbounded();
         let compaction_thread = inner.spawn_compaction_thread(rx)?;
         let (tx2, rx) = crossbeam_channel::unbounded();
         let flush_thread = inner.spawn_flush_thread(rx)?;
+        let (tx3, rx) = crossbeam_channel::unbounded();
+        let blob_gc_thread = inner.spawn_blob_gc_thread(rx)?;
+        let (tx4, rx) = crossbeam_channel::unbounded();
+        let catch_up_thread = inner.spawn_catch_up_thread(rx)?;
         Ok(Arc::new(Self {
             inner,
             flush_notifier: tx2,
             flush_thread: Mutex::new(flush_thread),
             compaction_notifier: tx1,
             compaction_thread: Mutex::new(compaction_thread),
+            blob_gc_notifier: tx3,
+            blob_gc_thread: Mutex::new(blob_gc_thread),
+            catch_up_notifier: tx4,
+            catch_up_thread: Mutex::new(catch_up_thread),
         }))
     }
+
+    /// 停止后台线程并等它们退出，再把 WAL、blob 文件和目录同步到磁盘，释放目录锁。
+    /// 之后这个实例还能读取，但所有写入都返回错误。
+    /// 没有开启 WAL 时先把所有 memtable 刷新到 SST。
+    pub fn close(&self) -> Result<()> {
+        let stopped = self.stop_threads();
+        let flushed = if self.inner.mode == OpenMode::ReadWrite && !self.inner.options.enable_wal {
+            self.inner.flush_all_memtables()
+        } else {
+            Ok(())
+        };
+        {
+            // 等正在进行的写入和列族修改结束
+            let _write_guard = self.inner.blob_gc_lock.write();
+            let _state_lock = self.inner.state_lock.lock();
+            self.inner.closed.store(true, Ordering::SeqCst);
+        }
+        // 被停写阻塞的写入者不会再等到压缩，唤醒它们返回错误
+        self.inner.write_controller.wake_stalled_writers();
+        // 只读实例没有要落盘的数据
+        let synced = if self.inner.mode == OpenMode::ReadWrite {
+            self.inner.sync().and_then(|_| self.inner.sync_dir())
+        } else {
+            Ok(())
+        };
+        // 释放目录锁，之后其他实例可以打开这个数据库。出错时也要释放，再报告最先出现的错误
+        self.inner.db_lock.lock().take();
+        stopped?;
+        flushed?;
+        synced?;
+        // 刷新之后、关闭之前还有写入
+        let unpersisted = self.inner.unpersisted_memtables();
+        if unpersisted > 0 {
+            bail!(
+                "{} memtables are lost on close: the WAL is disabled",
+                unpersisted
+            );
+        }
+        Ok(())
+    }
+
+    /// 通知所有后台线程停止并等它们全部退出，有线程 panic 时等全部退出后再返回错误
+    fn stop_threads(&self) -> Result<()> {
+        self.compaction_notifier.send(()).ok();
+        self.flush_notifier.send(()).ok();
+        self.blob_gc_notifier.send(()).ok();
+        self.catch_up_notifier.send(()).ok();
+        let threads = [
+            ("flush", &self.flush_thread),
+            ("compaction", &self.compaction_thread),
+            ("blob gc", &self.blob_gc_thread),
+            ("catch up", &self.catch_up_thread),
+        ];
+        let mut result = Ok(());
+        for (name, thread) in threads {
+            if let Some(handle) = thread.lock().take() {
+                if handle.join().is_err() && result.is_ok() {
+                    result = Err(anyhow!("{} thread panicked", name));
+                }
+            }
+        }
+        result
+    }
+
+    /// 写入一个带过期时间的键值对，过期后读取时视为已删除
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.inner.put_with_ttl(key, value, ttl)
+    }
+
+    /// 写入一个合并操作数，读取或压缩时由 `merge_operator` 和更旧的版本合并
+    pub fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        self.inner.merge(key, operand)
+    }
+
+    /// 扫描默认列族里 `lower` 到 `upper` 之间的键
+    pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<LsmIterator> {
+        self.inner.scan(lower, upper)
+    }
+
+    /// 扫描默认列族里以 `prefix` 开头的键
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<LsmIterator> {
+        self.inner.scan_prefix(prefix)
+    }
+
+    /// 创建列族，所有列族共用一个WAL和MANIFEST
+    pub fn create_cf(&self, name: &str, options: ColumnFamilyOptions) -> Result<Arc<ColumnFamily>> {
+        self.inner.create_cf(name, options)
+    }
+
+    /// 删除列族
+    pub fn drop_cf(&self, name: &str) -> Result<()> {
+        self.inner.drop_cf(name)
+    }
+
+    /// 立即回收一次blob文件，返回删除的文件数
+    pub fn gc_blob_files(&self) -> Result<usize> {
+        self.inner.gc_blob_files()
+    }
+
+    /// 块缓存的命中、未命中和淘汰次数
+    pub fn block_cache_stats(&self) -> BlockCacheStats {
+        self.inner.block_cache().stats()
+    }
+
+    /// 写入因为刷新或压缩落后而停顿的累计时间和次数
+    pub fn write_stall_stats(&self) -> WriteStallStats {
+        self.inner.write_stall_stats()
+    }
+
+    /// 运行时调整刷新、压缩和 blob GC 的写入速率，`None` 或 0 表示不限速
+    pub fn set_background_io_bytes_per_sec(&self, bytes_per_sec: Option<u64>) {
+        self.inner.rate_limiter.set_bytes_per_sec(bytes_per_sec);
+    }
+
+    /// 当前刷新、压缩和 blob GC 的写入速率上限
+    pub fn background_io_bytes_per_sec(&self) -> Option<u64> {
+        self.inner.rate_limiter.bytes_per_sec()
+    }
+
+    /// 从实例立即跟上主实例一次，不用等后台线程
+    pub fn try_catch_up_with_primary(&self) -> Result<()> {
+        self.inner.try_catch_up_with_primary()
+    }
+
+    /// 在 `dir` 创建一个可以直接打开的检查点，SST 用硬链接，WAL 复制
+    pub fn create_checkpoint(&self, dir: impl AsRef<Path>) -> Result<()> {
+        self.inner.create_checkpoint(dir)
+    }
+
+    /// 按名字获取列族句柄
+    pub fn cf_handle(&self, name: &str) -> Option<Arc<ColumnFamily>> {
+        self.inner.cf_handle(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::MiniLsm;
+    use crate::{
+        column_family::ColumnFamilyOptions,
+        db_lock::DatabaseInUseError,
+        lsm_storage::{CompactionOptions, LsmStorageInner, LsmStorageOptions, WriteBatchRecord},
+        memtable::Wal,
+        value::encode_value,
+    };
+
+    #[test]
+    fn test_close() {
+        let dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.enable_wal = true;
+        let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+        storage.inner.put(b"1", b"1").unwrap();
+        storage.close().unwrap();
+        assert!(storage.flush_thread.lock().is_none());
+        // 关闭后还能读，但不能再写入或修改MANIFEST
+        assert_eq!(&storage.inner.get(b"1").unwrap().unwrap()[..], b"1");
+        let manifest_len = std::fs::metadata(dir.path().join("MANIFEST"))
+            .unwrap()
+            .len();
+        assert!(storage.inner.put(b"2", b"2").is_err());
+        assert!(storage
+            .create_cf(
+                "cf",
+                ColumnFamilyOptions {
+                    block_size: 4096,
+                    compaction_options: CompactionOptions::NoCompaction,
+                },
+            )
+            .is_err());
+        assert_eq!(
+            std::fs::metadata(dir.path().join("MANIFEST")).unwrap().len(),
+            manifest_len
+        );
+        // 关闭后释放目录锁，即使 `storage` 还没有 drop
+        let storage = LsmStorageInner::open(&dir, options).unwrap();
+        assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"1");
+
+        // 没有WAL时关闭前刷新到SST
+        let dir = tempdir().unwrap();
+        let storage = MiniLsm::open(&dir, LsmStorageOptions::default_for_week1_test()).unwrap();
+        storage.close().unwrap();
+        let storage = MiniLsm::open(&dir, LsmStorageOptions::default_for_week1_test()).unwrap();
+        storage.inner.put(b"1", b"1").unwrap();
+        storage.close().unwrap();
+        storage.close().unwrap();
+        let storage = MiniLsm::open(&dir, LsmStorageOptions::default_for_week1_test()).unwrap();
+        assert_eq!(&storage.inner.get(b"1").unwrap().unwrap()[..], b"1");
+    }
+
+    #[test]
+    fn test_database_in_use() {
+        let dir = tempdir().unwrap();
+        let options = LsmStorageOptions::default_for_week1_test();
+        let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+        let err = MiniLsm::open(&dir, options.clone()).err().unwrap();
+        assert!(err.downcast_ref::<DatabaseInUseError>().is_some());
+        // drop 时等后台线程退出，目录锁随之释放
+        drop(storage);
+        MiniLsm::open(&dir, options).unwrap();
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        let dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.enable_wal = true;
+        let primary = MiniLsm::open(&dir, options.clone()).unwrap();
+        primary.inner.put(b"1", b"1").unwrap();
+        let files = std::fs::read_dir(&dir).unwrap().count();
+        let manifest_len = std::fs::metadata(dir.path().join("MANIFEST"))
+            .unwrap()
+            .len();
+
+        // 写入者还开着的时候也能只读打开
+        let reader = MiniLsm::open_read_only(&dir, options.clone()).unwrap();
+        assert_eq!(&reader.inner.get(b"1").unwrap().unwrap()[..], b"1");
+        assert!(reader.inner.put(b"2", b"2").is_err());
+        assert!(reader.create_cf("cf", ColumnFamilyOptions {
+            block_size: 4096,
+            compaction_options: CompactionOptions::NoCompaction,
+        })
+        .is_err());
+        reader.close().unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), files);
+        assert_eq!(
+            std::fs::metadata(dir.path().join("MANIFEST")).unwrap().len(),
+            manifest_len
+        );
+
+        let missing = dir.path().join("missing");
+        assert!(MiniLsm::open_read_only(&missing, options).is_err());
+        assert!(!missing.exists());
+    }
+
+    #[test]
+    fn test_open_as_secondary() {
+        let dir = tempdir().unwrap();
+        let secondary_dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.enable_wal = true;
+        let primary = MiniLsm::open(&dir, options.clone()).unwrap();
+        primary.inner.put(b"1", b"1").unwrap();
+
+        let secondary =
+            MiniLsm::open_as_secondary(&dir, &secondary_dir, options.clone()).unwrap();
+        assert_eq!(&secondary.inner.get(b"1").unwrap().unwrap()[..], b"1");
+        assert!(secondary.inner.put(b"2", b"2").is_err());
+        // 同一个从实例目录不能打开两次
+        assert!(MiniLsm::open_as_secondary(&dir, &secondary_dir, options).is_err());
+
+        primary.inner.put(b"2", b"2").unwrap();
+        let cf = primary
+            .create_cf("cf", ColumnFamilyOptions {
+                block_size: 4096,
+                compaction_options: CompactionOptions::NoCompaction,
+            })
+            .unwrap();
+        primary
+            .inner
+            .write_batch_cf(&[(&*cf, &WriteBatchRecord::Put(b"3", b"3"))])
+            .unwrap();
+        assert!(secondary.inner.get(b"2").unwrap().is_none());
+        secondary.try_catch_up_with_primary().unwrap();
+        assert_eq!(&secondary.inner.get(b"2").unwrap().unwrap()[..], b"2");
+        let secondary_cf = secondary.cf_handle("cf").unwrap();
+        assert_eq!(
+            &secondary.inner.get_cf(&secondary_cf, b"3").unwrap().unwrap()[..],
+            b"3"
+        );
+
+        // 刷新出的SST装到对应的列族里
+        primary.inner.flush_all_memtables().unwrap();
+        secondary.try_catch_up_with_primary().unwrap();
+        let cf_state = secondary_cf.state.read().clone();
+        assert_eq!(cf_state.l0_sstables.len(), 1);
+        assert_eq!(
+            &secondary.inner.get_cf(&secondary_cf, b"3").unwrap().unwrap()[..],
+            b"3"
+        );
+        assert_eq!(secondary.inner.state.read().l0_sstables.len(), 2);
+        assert_eq!(&secondary.inner.get(b"1").unwrap().unwrap()[..], b"1");
+
+        // 后台线程也会跟上
+        primary.inner.put(b"4", b"4").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        assert_eq!(&secondary.inner.get(b"4").unwrap().unwrap()[..], b"4");
+        secondary.close().unwrap();
+    }
+
+    #[test]
+    fn test_secondary_replays_wal_incrementally() {
+        let dir = tempdir().unwrap();
+        let secondary_dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.enable_wal = true;
+        let primary = MiniLsm::open(&dir, options.clone()).unwrap();
+        let secondary =
+            MiniLsm::open_as_secondary(&dir, &secondary_dir, options.clone()).unwrap();
+
+        // 直接往主实例正在使用的WAL里追加批次
+        let wal_id = primary.inner.state.read().memtable.id();
+        let wal = Wal::recover(primary.inner.path_of_wal(wal_id), |_, _, _| {}).unwrap();
+        wal.put_batch(&[(0, b"a", &encode_value(b"1", None))])
+            .unwrap();
+        wal.sync().unwrap();
+        secondary.try_catch_up_with_primary().unwrap();
+        assert_eq!(&secondary.inner.get(b"a").unwrap().unwrap()[..], b"1");
+        let memtable = secondary.inner.state.read().imm_memtables[0].clone();
+        assert_eq!(memtable.id(), wal_id);
+
+        // 只重放新追加的批次；还没创建的列族的批次留到下次
+        wal.put_batch(&[(0, b"a", &encode_value(b"2", None))])
+            .unwrap();
+        wal.put_batch(&[
+            (99, b"x", &encode_value(b"1", None)),
+            (0, b"b", &encode_value(b"1", None)),
+        ])
+        .unwrap();
+        wal.sync().unwrap();
+        secondary.try_catch_up_with_primary().unwrap();
+        assert_eq!(&secondary.inner.get(b"a").unwrap().unwrap()[..], b"2");
+        assert!(secondary.inner.get(b"b").unwrap().is_none());
+        assert!(std::sync::Arc::ptr_eq(
+            &memtable,
+            &secondary.inner.state.read().imm_memtables[0]
+        ));
+        secondary.close().unwrap();
+    }
 }
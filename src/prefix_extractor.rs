@@ -0,0 +1,29 @@
+use std::fmt::Debug;
+
+/// 从键中取出前缀，在 `LsmStorageOptions::prefix_extractor` 中注册。
+/// SST 的布隆过滤器会同时包含完整的键和它的前缀，`scan_prefix` 用它跳过整个 SST。
+pub trait PrefixExtractor: Send + Sync + Debug {
+    /// 返回键的前缀，键不在前缀的定义域内时返回 `None`。
+    fn prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]>;
+}
+
+/// 固定长度的前缀，比这个长度短的键没有前缀。
+#[derive(Debug, Clone)]
+pub struct FixedPrefix(pub usize);
+
+impl PrefixExtractor for FixedPrefix {
+    fn prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        key.get(..self.0)
+    }
+}
+
+/// 到第一个分隔符为止（包括分隔符）的前缀，比如 `tenant/table/pk` 的前缀是 `tenant/`。
+#[derive(Debug, Clone)]
+pub struct DelimiterPrefix(pub u8);
+
+impl PrefixExtractor for DelimiterPrefix {
+    fn prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        let pos = key.iter().position(|x| *x == self.0)?;
+        Some(&key[..=pos])
+    }
+}
@@ -0,0 +1,218 @@
+use std::time::{Duration, Instant};
+
+use parking_lot::{Condvar, Mutex};
+
+/// 后台 I/O 的优先级。刷新阻塞着写入，优先于压缩拿到令牌。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPriority {
+    /// 刷新
+    High,
+    /// 压缩和 blob GC
+    Low,
+}
+
+/// 令牌桶最多攒下多长时间的令牌
+const REFILL_PERIOD_SECS: f64 = 0.1;
+
+struct Bucket {
+    /// 不设置时不限速
+    bytes_per_sec: Option<u64>,
+    available: f64,
+    last_refill: Instant,
+    /// 正在等待令牌的高优先级请求数，大于 0 时低优先级请求不能拿令牌
+    high_pri_waiting: usize,
+    /// 各优先级一共申请过的字节数，下标是 `IoPriority as usize`
+    bytes_through: [u64; 2],
+}
+
+impl Bucket {
+    /// 按 `now` 和上次补充之间经过的时间补充令牌，测试时可以传入任意时刻
+    fn refill(&mut self, rate: u64, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.available = (self.available + elapsed * rate as f64).min(burst_bytes(rate));
+        self.last_refill = now;
+    }
+}
+
+fn burst_bytes(rate: u64) -> f64 {
+    (rate as f64 * REFILL_PERIOD_SECS).max(1.0)
+}
+
+/// 刷新、压缩和 blob GC 共用的令牌桶限速器，每写一段数据前先申请同样字节数的令牌。
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+    condvar: Condvar,
+}
+
+impl RateLimiter {
+    /// `bytes_per_sec` 为空或为 0 时不限速
+    pub fn new(bytes_per_sec: Option<u64>) -> Self {
+        let bytes_per_sec = bytes_per_sec.filter(|rate| *rate > 0);
+        Self {
+            bucket: Mutex::new(Bucket {
+                bytes_per_sec,
+                available: bytes_per_sec.map(burst_bytes).unwrap_or_default(),
+                last_refill: Instant::now(),
+                high_pri_waiting: 0,
+                bytes_through: [0; 2],
+            }),
+            condvar: Condvar::new(),
+        }
+    }
+
+    pub fn bytes_per_sec(&self) -> Option<u64> {
+        self.bucket.lock().bytes_per_sec
+    }
+
+    /// 运行时调整速率，正在等待的请求按新的速率继续。为空或为 0 时不限速
+    pub fn set_bytes_per_sec(&self, bytes_per_sec: Option<u64>) {
+        let bytes_per_sec = bytes_per_sec.filter(|rate| *rate > 0);
+        let mut bucket = self.bucket.lock();
+        if let Some(rate) = bucket.bytes_per_sec {
+            bucket.refill(rate, Instant::now());
+        }
+        bucket.bytes_per_sec = bytes_per_sec;
+        bucket.last_refill = Instant::now();
+        if let Some(rate) = bytes_per_sec {
+            bucket.available = bucket.available.min(burst_bytes(rate));
+        }
+        self.condvar.notify_all();
+    }
+
+    /// 这个优先级一共申请过的字节数，包括不限速时的请求
+    pub fn total_bytes_through(&self, priority: IoPriority) -> u64 {
+        self.bucket.lock().bytes_through[priority as usize]
+    }
+
+    /// 阻塞直到拿到 `bytes` 字节的令牌。大请求按令牌桶的容量分段拿。
+    pub fn request(&self, bytes: usize, priority: IoPriority) {
+        let high = priority == IoPriority::High;
+        let mut bucket = self.bucket.lock();
+        if high {
+            bucket.high_pri_waiting += 1;
+        }
+        let mut remaining = bytes as f64;
+        while remaining > 0.0 {
+            let Some(rate) = bucket.bytes_per_sec else {
+                break;
+            };
+            bucket.refill(rate, Instant::now());
+            let chunk = remaining.min(burst_bytes(rate));
+            if (high || bucket.high_pri_waiting == 0) && bucket.available >= chunk {
+                bucket.available -= chunk;
+                remaining -= chunk;
+                continue;
+            }
+            let wait = ((chunk - bucket.available).max(0.0) / rate as f64).max(0.001);
+            self.condvar
+                .wait_for(&mut bucket, Duration::from_secs_f64(wait));
+        }
+        bucket.bytes_through[priority as usize] += bytes as u64;
+        if high {
+            bucket.high_pri_waiting -= 1;
+            // 让等着的低优先级请求继续
+            self.condvar.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use super::{Bucket, IoPriority, RateLimiter};
+    use crate::lsm_storage::{LsmStorageInner, LsmStorageOptions, WriteBatchRecord};
+
+    #[test]
+    fn test_refill() {
+        let start = Instant::now();
+        let mut bucket = Bucket {
+            bytes_per_sec: Some(1000),
+            available: 0.0,
+            last_refill: start,
+            high_pri_waiting: 0,
+            bytes_through: [0; 2],
+        };
+        bucket.refill(1000, start + Duration::from_millis(50));
+        assert_eq!(bucket.available, 50.0);
+        // 最多攒下 0.1 秒的令牌
+        bucket.refill(1000, start + Duration::from_secs(10));
+        assert_eq!(bucket.available, 100.0);
+        bucket.available = 0.0;
+        bucket.refill(1000, start + Duration::from_secs(10));
+        assert_eq!(bucket.available, 0.0);
+        // 速率很低时至少能攒下一个字节
+        bucket.refill(1, start + Duration::from_secs(20));
+        assert_eq!(bucket.available, 1.0);
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        // 令牌桶容量是 100KB，大请求分段拿令牌
+        let limiter = Arc::new(RateLimiter::new(Some(1 << 20)));
+        limiter.request(300 << 10, IoPriority::High);
+        assert_eq!(limiter.total_bytes_through(IoPriority::High), 300 << 10);
+        assert!(limiter.bucket.lock().available < (100 << 10) as f64);
+
+        // 低优先级的请求先到，高优先级的请求先完成
+        let low = {
+            let limiter = limiter.clone();
+            std::thread::spawn(move || {
+                limiter.request(200 << 10, IoPriority::Low);
+                Instant::now()
+            })
+        };
+        std::thread::sleep(Duration::from_millis(20));
+        limiter.request(200 << 10, IoPriority::High);
+        let high_done = Instant::now();
+        assert!(high_done < low.join().unwrap());
+        assert_eq!(limiter.total_bytes_through(IoPriority::High), 500 << 10);
+        assert_eq!(limiter.total_bytes_through(IoPriority::Low), 200 << 10);
+
+        // 运行时取消限速会唤醒正在等待的请求
+        limiter.set_bytes_per_sec(Some(1000));
+        let blocked = {
+            let limiter = limiter.clone();
+            std::thread::spawn(move || limiter.request(1 << 20, IoPriority::Low))
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!blocked.is_finished());
+        limiter.set_bytes_per_sec(None);
+        blocked.join().unwrap();
+        assert_eq!(limiter.bytes_per_sec(), None);
+
+        // 速率为 0 也是不限速
+        limiter.set_bytes_per_sec(Some(0));
+        assert_eq!(limiter.bytes_per_sec(), None);
+        limiter.request(1 << 20, IoPriority::Low);
+        let limiter = RateLimiter::new(Some(0));
+        assert_eq!(limiter.bytes_per_sec(), None);
+        limiter.request(1 << 20, IoPriority::High);
+    }
+
+    #[test]
+    fn test_flush_is_rate_limited() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.background_io_bytes_per_sec = Some(100 << 10);
+        let storage = LsmStorageInner::open(&dir, options).unwrap();
+        let value = vec![b'x'; 1000];
+        let keys = (0..50).map(|i| format!("{:02}", i)).collect::<Vec<_>>();
+        let batch = keys
+            .iter()
+            .map(|key| WriteBatchRecord::Put(key.as_bytes(), &value[..]))
+            .collect::<Vec<_>>();
+        storage.write_batch(&batch).unwrap();
+        storage.flush_all_memtables().unwrap();
+        // 刷新按高优先级为写出的每个字节申请令牌
+        let snapshot = storage.state.read().clone();
+        assert_eq!(snapshot.l0_sstables.len(), 1);
+        let table_size = snapshot.sstables[&snapshot.l0_sstables[0]].table_size();
+        let limiter = &storage.rate_limiter;
+        assert_eq!(limiter.total_bytes_through(IoPriority::High), table_size);
+        assert_eq!(limiter.total_bytes_through(IoPriority::Low), 0);
+    }
+}
@@ -0,0 +1,314 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use parking_lot::RwLock;
+
+use crate::{
+    column_family::ColumnFamily,
+    compact::CompactionController,
+    lsm_storage::{
+        LsmStorageInner, LsmStorageState, Manifest, ManifestRecord, ManifestTail, ReplayedWal,
+    },
+    memtable::Wal,
+    sstable::SsTable,
+    MemTable,
+};
+
+impl LsmStorageInner {
+    /// 从实例读取主实例MANIFEST新追加的记录：创建或删除列族，打开各列族新刷新或压缩出的SST，
+    /// 再从上次停下的位置继续重放还没刷新的WAL，更新各列族的不可变memtable。
+    pub fn try_catch_up_with_primary(&self) -> Result<()> {
+        let Some(tail) = &self.manifest_tail else {
+            bail!("database {:?} is not opened as a secondary", self.path);
+        };
+        let _state_lock = self.state_lock.lock();
+        let mut tail = tail.lock();
+        let (records, offset) =
+            Manifest::read_records_from(self.path.join("MANIFEST"), tail.offset)?;
+        self.apply_primary_records(&mut tail, records, offset)
+    }
+
+    /// 应用读到 `offset` 为止的记录。主实例先写MANIFEST再删除文件，读完记录之后SST或WAL被删除，
+    /// 说明MANIFEST后面又追加了换掉它的刷新或压缩记录，读出这些记录接着应用，再重新打开
+    fn apply_primary_records(
+        &self,
+        tail: &mut ManifestTail,
+        mut records: Vec<ManifestRecord>,
+        mut offset: u64,
+    ) -> Result<()> {
+        let manifest_path = self.path.join("MANIFEST");
+        // 先在副本上修改，全部成功后再生效。记录按顺序应用到各列族状态的副本上，
+        // 压缩记录引用的是之前刷新或压缩出的SST
+        let mut wals = tail.wals.clone();
+        let mut column_families = self.column_families.read().clone();
+        let mut states = column_families
+            .iter()
+            .map(|(id, cf)| (*id, cf.state.read().as_ref().clone()))
+            .collect::<BTreeMap<_, _>>();
+        loop {
+            for record in records {
+                Self::apply_manifest_record(record, &mut wals, &mut column_families, &mut states);
+            }
+            match self.open_primary_files(&mut wals, &column_families, &mut states) {
+                Err(e) if is_not_found(&e) => {
+                    let (more, new_offset) = Manifest::read_records_from(&manifest_path, offset)?;
+                    if more.is_empty() {
+                        return Err(e);
+                    }
+                    records = more;
+                    offset = new_offset;
+                }
+                result => break result?,
+            }
+        }
+
+        for (id, state) in states {
+            *column_families[&id].state.write() = Arc::new(state);
+        }
+        if let Some(max_id) = column_families.keys().max() {
+            self.next_cf_id
+                .fetch_max(max_id + 1, std::sync::atomic::Ordering::SeqCst);
+        }
+        *self.column_families.write() = column_families;
+        tail.offset = offset;
+        tail.wals = wals;
+        Ok(())
+    }
+
+    /// 把主实例MANIFEST的一条记录应用到各列族状态的副本上
+    fn apply_manifest_record(
+        record: ManifestRecord,
+        wals: &mut BTreeMap<usize, ReplayedWal>,
+        column_families: &mut BTreeMap<usize, Arc<ColumnFamily>>,
+        states: &mut BTreeMap<usize, LsmStorageState>,
+    ) {
+        match record {
+            ManifestRecord::Flush(memtable_id, tables) => {
+                wals.remove(&memtable_id);
+                for (cf_id, sst_id) in tables {
+                    if let (Some(cf), Some(state)) =
+                        (column_families.get(&cf_id), states.get_mut(&cf_id))
+                    {
+                        let controller = CompactionController::new(&cf.options.compaction_options);
+                        state.add_flushed_sst(sst_id, controller.flush_to_l0());
+                    }
+                }
+            }
+            ManifestRecord::NewMemtable(id) => {
+                wals.insert(id, ReplayedWal::default());
+            }
+            ManifestRecord::CreateColumnFamily(id, name, cf_options) => {
+                let cf_state = LsmStorageState::create(&cf_options.compaction_options);
+                states.insert(id, cf_state.clone());
+                column_families.insert(
+                    id,
+                    Arc::new(ColumnFamily::new(
+                        id,
+                        name,
+                        cf_options,
+                        Arc::new(RwLock::new(Arc::new(cf_state))),
+                    )),
+                );
+            }
+            ManifestRecord::DropColumnFamily(id) => {
+                column_families.remove(&id);
+                states.remove(&id);
+                for wal in wals.values_mut() {
+                    wal.memtables.remove(&id);
+                }
+            }
+            ManifestRecord::Compaction(cf_id, task, output) => {
+                if let (Some(cf), Some(state)) =
+                    (column_families.get(&cf_id), states.get_mut(&cf_id))
+                {
+                    let controller = CompactionController::new(&cf.options.compaction_options);
+                    let (mut new_state, removed) =
+                        controller.apply_compaction_result(state, &task, &output);
+                    for id in removed {
+                        new_state.sstables.remove(&id);
+                    }
+                    *state = new_state;
+                }
+            }
+        }
+    }
+
+    /// 打开各列族新引用的SST，再从上次停下的位置继续重放还没刷新的WAL
+    fn open_primary_files(
+        &self,
+        wals: &mut BTreeMap<usize, ReplayedWal>,
+        column_families: &BTreeMap<usize, Arc<ColumnFamily>>,
+        states: &mut BTreeMap<usize, LsmStorageState>,
+    ) -> Result<()> {
+        // 之后又被压缩掉的SST已经不在状态里，不用打开
+        for state in states.values_mut() {
+            let new_ssts = state
+                .l0_sstables
+                .iter()
+                .chain(state.levels.iter().flat_map(|(_, files)| files))
+                .filter(|id| !state.sstables.contains_key(id))
+                .copied()
+                .collect::<Vec<_>>();
+            for table_id in new_ssts {
+                let sst = SsTable::open_with_options(
+                    table_id,
+                    Some(self.block_cache.clone()),
+                    Self::open_sst_file(
+                        &self.path,
+                        table_id,
+                        &self.options,
+                        self.table_cache.as_ref(),
+                    )
+                    .with_context(|| format!("failed to open SST: {}", table_id))?,
+                    self.options
+                        .table_open_options(state.l0_sstables.contains(&table_id)),
+                )?;
+                state.sstables.insert(table_id, Arc::new(sst));
+            }
+            state.sort_levels();
+        }
+
+        // 主实例还在往最新的WAL里追加。重放出的记录直接插入已经发布的memtable，
+        // 重复插入同样的记录不影响结果
+        let next_cf_id = column_families
+            .keys()
+            .max()
+            .map_or(0, |id| id + 1)
+            .max(self.next_cf_id.load(std::sync::atomic::Ordering::SeqCst));
+        for state in states.values_mut() {
+            state.imm_memtables.clear();
+        }
+        if !self.options.enable_wal {
+            return Ok(());
+        }
+        for (id, wal) in wals.iter_mut() {
+            for cf_id in states.keys() {
+                wal.memtables
+                    .entry(*cf_id)
+                    .or_insert_with(|| Arc::new(MemTable::create(*id)));
+            }
+            let memtables = &wal.memtables;
+            wal.offset = Wal::replay_from(self.path_of_wal(*id), wal.offset, |batch| {
+                // 还没读到创建记录的列族，停在这个批次之前，下次再重放
+                if batch.iter().any(|(cf_id, _, _)| *cf_id >= next_cf_id) {
+                    return false;
+                }
+                // 已经删除的列族的记录丢掉
+                for (cf_id, key, value) in batch {
+                    if let Some(memtable) = memtables.get(&cf_id) {
+                        memtable.put_without_wal(&key, &value);
+                    }
+                }
+                true
+            })?;
+        }
+        for (cf_id, state) in states.iter_mut() {
+            state.imm_memtables = wals
+                .values()
+                .rev()
+                .filter_map(|wal| wal.memtables.get(cf_id).cloned())
+                .collect();
+        }
+        Ok(())
+    }
+
+    /// 启动定期跟上主实例的线程，不是从实例时不启动
+    pub(crate) fn spawn_catch_up_thread(
+        self: &Arc<Self>,
+        rx: crossbeam_channel::Receiver<()>,
+    ) -> Result<Option<std::thread::JoinHandle<()>>> {
+        if self.manifest_tail.is_none() {
+            return Ok(None);
+        }
+        let this = self.clone();
+        let handle = std::thread::spawn(move || {
+            let ticker = crossbeam_channel::tick(Duration::from_millis(100));
+            loop {
+                crossbeam_channel::select! {
+                    recv(ticker) -> _ => if let Err(e) = this.try_catch_up_with_primary() {
+                        eprintln!("catch up with primary failed: {}", e);
+                    },
+                    recv(rx) -> _ => return
+                }
+            }
+        });
+        Ok(Some(handle))
+    }
+}
+
+/// 错误是不是文件不存在
+fn is_not_found(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::{
+        compact::LeveledCompactionOptions,
+        lsm_storage::{CompactionOptions, LsmStorageInner, LsmStorageOptions, Manifest, OpenMode},
+    };
+
+    #[test]
+    fn test_catch_up_after_primary_deletes_files() {
+        let dir = tempdir().unwrap();
+        let secondary_dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.enable_wal = true;
+        options.compaction_options = CompactionOptions::Leveled(LeveledCompactionOptions {
+            level_size_multiplier: 10,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+            base_level_size_mb: 1,
+        });
+        let primary = LsmStorageInner::open(&dir, options.clone()).unwrap();
+        let secondary = LsmStorageInner::open_with_mode(
+            &dir,
+            options,
+            OpenMode::Secondary(secondary_dir.path().to_path_buf()),
+        )
+        .unwrap();
+        let manifest_path = dir.path().join("MANIFEST");
+        let staged_path = secondary_dir.path().join("MANIFEST.staged");
+        // 模拟从实例读完 `staged` 之后，主实例才追加后面的记录并删除文件
+        let catch_up_from = |staged: &[u8]| {
+            std::fs::write(&staged_path, staged).unwrap();
+            let mut tail = secondary.manifest_tail.as_ref().unwrap().lock();
+            let (records, offset) = Manifest::read_records_from(&staged_path, tail.offset).unwrap();
+            secondary
+                .apply_primary_records(&mut tail, records, offset)
+                .unwrap();
+        };
+
+        primary.put(b"a", b"1").unwrap();
+        primary.flush_all_memtables().unwrap();
+        primary.put(b"b", b"2").unwrap();
+        let staged = std::fs::read(&manifest_path).unwrap();
+        // b 所在的 WAL 刷新后被删除
+        primary.flush_all_memtables().unwrap();
+        assert!(primary.delete_obsolete_files().unwrap() > 0);
+        catch_up_from(&staged);
+        assert_eq!(secondary.state.read().l0_sstables.len(), 2);
+        assert_eq!(&secondary.get(b"a").unwrap().unwrap()[..], b"1");
+        assert_eq!(&secondary.get(b"b").unwrap().unwrap()[..], b"2");
+
+        primary.put(b"c", b"3").unwrap();
+        primary.flush_all_memtables().unwrap();
+        let staged = std::fs::read(&manifest_path).unwrap();
+        // c 的 SST 压缩后被删除
+        primary.trigger_compaction().unwrap();
+        assert!(primary.delete_obsolete_files().unwrap() > 0);
+        catch_up_from(&staged);
+        let state = secondary.state.read().clone();
+        assert!(state.l0_sstables.is_empty());
+        assert_eq!(state.levels[0], primary.state.read().levels[0]);
+        for (key, value) in [(b"a", b"1"), (b"b", b"2"), (b"c", b"3")] {
+            assert_eq!(&secondary.get(key).unwrap().unwrap()[..], value);
+        }
+    }
+}
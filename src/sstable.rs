@@ -1,53 +1,151 @@
-use std::{fs::File, path::Path, sync::Arc};
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
+#[cfg(target_os = "linux")]
+use crate::direct_io;
 use crate::{
-    block::Block, key::{KeyBytes, KeySlice}, lsm_storage::BlockCache
+    block::{Block, BlockBuilder, BlockIterator, DEFAULT_RESTART_INTERVAL}, bloom::Bloom, compression::BlockCompression, key::{KeyBytes, KeySlice, KeyVec}, block_cache::BlockCacheHandle, table_cache::TableCache,
+    fs_util::{sync_file, sync_parent_dir},
+    prefix_extractor::PrefixExtractor,
+    rate_limiter::{IoPriority, RateLimiter},
+    varint::{get_varint, put_varint, varint_len},
 };
-use anyhow::{anyhow, bail, Result};
-use bytes::{Buf, BufMut};
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut, Bytes};
+
+/// 打开 SST 时索引块和过滤块的加载方式
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TableOpenOptions {
+    /// 索引块和过滤块放在块缓存里，用到时再读，而不是常驻内存。没有块缓存时不生效。
+    pub cache_index_and_filter_blocks: bool,
+    /// 分区索引，每个分区包含的数据块个数。只有第一层索引（每个分区的第一个键）常驻内存，
+    /// 分区放在块缓存里。只在 `cache_index_and_filter_blocks` 时生效。
+    pub index_partition_size: Option<usize>,
+    /// 扫描时一次预读的块数，不大于 1 时不预读
+    pub readahead_blocks: usize,
+}
+
+/// 索引块（`BlockMeta` 列表）的加载方式
+enum IndexBlock {
+    /// 常驻内存
+    Pinned(Arc<Vec<BlockMeta>>),
+    /// 放在块缓存里，记录的是它在文件中的位置
+    Cached { offset: u64, len: u64 },
+    /// 分区索引，第一层常驻内存
+    Partitioned(Vec<IndexPartition>),
+}
+
+/// 分区索引的一个分区
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct IndexPartition {
+    /// 分区里第一个数据块的第一个键
+    first_key: KeyBytes,
+    /// 分区里第一个数据块的序号
+    first_block_idx: usize,
+    /// 分区里第一个数据块的偏移量，用来确定上一个分区最后一个块的结束位置
+    first_offset: usize,
+    /// 分区在文件中的位置
+    offset: u64,
+    len: u64,
+    /// 分区包含的数据块个数
+    num: usize,
+    /// 打开时算出的分区校验和，完整索引块的校验和只能在打开时检查
+    checksum: u32,
+}
+
+/// 过滤块的加载方式
+enum FilterBlock {
+    Pinned(Arc<Bloom>),
+    Cached { offset: u64, len: u64 },
+}
 
 //创建sst表数据 ,用于刷新数据到磁盘，要判断数据
 pub struct SsTable {
     /// SsTable的实际存储单元，格式如上。
     pub(crate) file: FileObject,
     /// 保存数据块信息的元块。
-    pub(crate) block_meta: Vec<BlockMeta>,
+    index: IndexBlock,
+    /// 数据块的数量
+    num_blocks: usize,
     /// 指示' file '中元块起始点的偏移量。
     pub(crate) block_meta_offset: usize,
     //唯一id
     id: usize,
     //缓存的数据
-    block_cache: Option<Arc<BlockCache>>,
+    block_cache: Option<BlockCacheHandle>,
     //开始数据
     first_key: KeyBytes,
     //介绍key
     last_key: KeyBytes,
-    // pub(crate) bloom: Option<Bloom>,
+    //布隆过滤器，包含完整的键，配置了prefix_extractor时还包含键的前缀
+    filter: Option<FilterBlock>,
     //ts最大设置
     max_ts: u64,
+    //扫描时一次预读的块数
+    readahead_blocks: usize,
 }
 impl SsTable {
-    /// 打开sstable文件
-    pub fn open(id: usize, block_cache: Option<Arc<BlockCache>>, file: FileObject) -> Result<Self> {
+    /// 打开sstable文件，索引块和过滤块常驻内存
+    pub fn open(id: usize, block_cache: Option<BlockCacheHandle>, file: FileObject) -> Result<Self> {
+        Self::open_with_options(id, block_cache, file, TableOpenOptions::default())
+    }
+
+    /// 打开sstable文件，按 `options` 决定索引块和过滤块是否放进块缓存
+    pub fn open_with_options(
+        id: usize,
+        block_cache: Option<BlockCacheHandle>,
+        file: FileObject,
+        options: TableOpenOptions,
+    ) -> Result<Self> {
+        let cached = options.cache_index_and_filter_blocks && block_cache.is_some();
         let len = file.size();
         let raw_bloom_offset = file.read(len - 4, 4)?;
         let bloom_offset = (&raw_bloom_offset[..]).get_u32() as u64;
-        let raw_bloom = file.read(bloom_offset, len - 4 - bloom_offset)?;
-        // let bloom_filter = Bloom::decode(&raw_bloom)?;
+        let bloom_len = len - 4 - bloom_offset;
+        let filter = if cached {
+            FilterBlock::Cached {
+                offset: bloom_offset,
+                len: bloom_len,
+            }
+        } else {
+            let raw_bloom = file.read(bloom_offset, bloom_len)?;
+            FilterBlock::Pinned(Arc::new(Bloom::decode(&raw_bloom)?))
+        };
         let raw_meta_offset = file.read(bloom_offset - 4, 4)?;
         let block_meta_offset = (&raw_meta_offset[..]).get_u32() as u64;
-        let raw_meta = file.read(block_meta_offset, bloom_offset - 4 - block_meta_offset)?;
+        let meta_len = bloom_offset - 4 - block_meta_offset;
+        let raw_meta = file.read(block_meta_offset, meta_len)?;
+        // 打开时总要完整解码一次，检查校验和并取出首尾键
         let block_meta = BlockMeta::decode_block_meta(&raw_meta[..])?;
+        let first_key = block_meta.first().unwrap().first_key.clone();
+        let last_key = block_meta.last().unwrap().last_key.clone();
+        let num_blocks = block_meta.len();
+        let index = match (cached, options.index_partition_size) {
+            (false, _) => IndexBlock::Pinned(Arc::new(block_meta)),
+            (true, None) => IndexBlock::Cached {
+                offset: block_meta_offset,
+                len: meta_len,
+            },
+            (true, Some(partition_size)) => IndexBlock::Partitioned(
+                BlockMeta::partition_block_meta(&raw_meta, block_meta_offset, partition_size),
+            ),
+        };
         Ok(Self {
             file,
-            first_key: block_meta.first().unwrap().first_key.clone(),
-            last_key: block_meta.last().unwrap().last_key.clone(),
-            block_meta,
+            first_key,
+            last_key,
+            index,
+            num_blocks,
             block_meta_offset: block_meta_offset as usize,
             id,
             block_cache,
-            // bloom: Some(bloom_filter),
+            filter: Some(filter),
             max_ts: 0,
+            readahead_blocks: options.readahead_blocks,
         })
     }
     pub fn first_key(&self) -> &KeyBytes {
@@ -69,48 +167,330 @@ impl SsTable {
     pub fn max_ts(&self) -> u64 {
         self.max_ts
     }
+
+    /// 布隆过滤器，放在块缓存里时可能需要从磁盘读取
+    pub(crate) fn bloom(&self) -> Result<Option<Arc<Bloom>>> {
+        match &self.filter {
+            None => Ok(None),
+            Some(FilterBlock::Pinned(bloom)) => Ok(Some(bloom.clone())),
+            Some(FilterBlock::Cached { offset, len }) => {
+                let block_cache = self.block_cache.as_ref().unwrap();
+                let bloom = block_cache.get_filter_block(self.id, || {
+                    Bloom::decode(&self.file.read(*offset, *len)?)
+                })?;
+                Ok(Some(bloom))
+            }
+        }
+    }
+
+    /// 读取完整的索引块（不分区时）
+    fn read_index(&self) -> Result<Arc<Vec<BlockMeta>>> {
+        match &self.index {
+            IndexBlock::Pinned(block_meta) => Ok(block_meta.clone()),
+            IndexBlock::Cached { offset, len } => {
+                let block_cache = self.block_cache.as_ref().unwrap();
+                block_cache.get_index_block(self.id, 0, || {
+                    BlockMeta::decode_block_meta(&self.file.read(*offset, *len)?)
+                })
+            }
+            IndexBlock::Partitioned(_) => unreachable!(),
+        }
+    }
+
+    /// 所有数据块的首键，按顺序排列
+    pub(crate) fn block_first_keys(&self) -> Result<Vec<KeyBytes>> {
+        let block_meta = match &self.index {
+            IndexBlock::Partitioned(partitions) => {
+                let mut block_meta = Vec::with_capacity(self.num_blocks);
+                for idx in 0..partitions.len() {
+                    let partition = self.read_index_partition(partitions, idx)?;
+                    block_meta.extend(partition.iter().map(|meta| meta.first_key.clone()));
+                }
+                return Ok(block_meta);
+            }
+            _ => self.read_index()?,
+        };
+        Ok(block_meta.iter().map(|meta| meta.first_key.clone()).collect())
+    }
+
+    /// 读取分区索引的第 idx 个分区
+    fn read_index_partition(
+        &self,
+        partitions: &[IndexPartition],
+        idx: usize,
+    ) -> Result<Arc<Vec<BlockMeta>>> {
+        let partition = &partitions[idx];
+        let block_cache = self.block_cache.as_ref().unwrap();
+        block_cache.get_index_block(self.id, idx, || {
+            let raw = self.file.read(partition.offset, partition.len)?;
+            if crc32fast::hash(&raw) != partition.checksum {
+                bail!("index partition checksum mismatched");
+            }
+            let mut buf = &raw[..];
+            Ok(BlockMeta::decode_block_meta_entries(&mut buf, partition.num))
+        })
+    }
+
+    /// 点查一个键，返回它在这个 SST 里的原始值。先用键范围和布隆过滤器排除
+    pub(crate) fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        if key < self.first_key().raw_ref() || key > self.last_key().raw_ref() {
+            return Ok(None);
+        }
+        if let Some(bloom) = self.bloom()? {
+            if !bloom.may_contain(farmhash::fingerprint32(key)) {
+                return Ok(None);
+            }
+        }
+        let key = KeySlice::from_slice(key);
+        let block = self.read_block_cached(self.find_block_idx(key)?)?;
+        let iter = BlockIterator::create_and_seek_to_key(block.clone(), key);
+        if iter.is_valid() && iter.key() == key {
+            return Ok(Some(block.data.slice_ref(iter.value())));
+        }
+        Ok(None)
+    }
+
     ///查找可能包含' key '的块。
-    pub fn find_block_idx(&self, key: KeySlice) -> usize {
-        self.block_meta
-            .partition_point(|meta| meta.first_key.as_key_slice() <= key)
-            .saturating_sub(1)
+    pub fn find_block_idx(&self, key: KeySlice) -> Result<usize> {
+        let find = |block_meta: &[BlockMeta]| {
+            block_meta
+                .partition_point(|meta| meta.first_key.as_key_slice() <= key)
+                .saturating_sub(1)
+        };
+        match &self.index {
+            IndexBlock::Partitioned(partitions) => {
+                let idx = partitions
+                    .partition_point(|partition| partition.first_key.as_key_slice() <= key)
+                    .saturating_sub(1);
+                let block_meta = self.read_index_partition(partitions, idx)?;
+                Ok(partitions[idx].first_block_idx + find(&block_meta))
+            }
+            _ => Ok(find(&self.read_index()?)),
+        }
+    }
+
+    /// 数据块在文件中的起止位置
+    fn block_range(&self, block_idx: usize) -> Result<(usize, usize)> {
+        match &self.index {
+            IndexBlock::Partitioned(partitions) => {
+                let idx = partitions
+                    .partition_point(|partition| partition.first_block_idx <= block_idx)
+                    - 1;
+                let block_meta = self.read_index_partition(partitions, idx)?;
+                let local_idx = block_idx - partitions[idx].first_block_idx;
+                let offset_end = block_meta
+                    .get(local_idx + 1)
+                    .map(|x| x.offset)
+                    .or_else(|| partitions.get(idx + 1).map(|x| x.first_offset))
+                    .unwrap_or(self.block_meta_offset);
+                Ok((block_meta[local_idx].offset, offset_end))
+            }
+            _ => {
+                let block_meta = self.read_index()?;
+                let offset_end = block_meta
+                    .get(block_idx + 1)
+                    .map_or(self.block_meta_offset, |x| x.offset);
+                Ok((block_meta[block_idx].offset, offset_end))
+            }
+        }
+    }
+
+    /// 预读从 `start_idx` 开始的 `count` 个块，不在块缓存里的块一次性并行读取后放进块缓存
+    pub fn read_blocks(&self, start_idx: usize, count: usize) -> Result<Vec<Arc<Block>>> {
+        let end_idx = (start_idx + count).min(self.num_blocks);
+        let mut blocks = (start_idx..end_idx)
+            .map(|idx| {
+                self.block_cache
+                    .as_ref()
+                    .and_then(|block_cache| block_cache.get_cached_data_block(self.id, idx))
+            })
+            .collect::<Vec<_>>();
+        let missing = (start_idx..end_idx)
+            .filter(|idx| blocks[idx - start_idx].is_none())
+            .collect::<Vec<_>>();
+        let mut ranges = Vec::with_capacity(missing.len());
+        for idx in &missing {
+            let (offset, offset_end) = self.block_range(*idx)?;
+            ranges.push((offset as u64, (offset_end - offset) as u64));
+        }
+        for (idx, raw) in missing.into_iter().zip(self.file.read_many(&ranges)?) {
+            let block = Arc::new(decode_block(raw)?);
+            if let Some(block_cache) = &self.block_cache {
+                block_cache.insert_data_block(self.id, idx, block.clone());
+            }
+            blocks[idx - start_idx] = Some(block);
+        }
+        Ok(blocks.into_iter().map(Option::unwrap).collect())
+    }
+
+    /// 扫描时每次预读的块数
+    pub(crate) fn readahead_blocks(&self) -> usize {
+        self.readahead_blocks
     }
 
     /// 用块缓存从磁盘读取一个块。
     pub fn read_block_cached(&self, block_idx: usize) -> Result<Arc<Block>> {
         if let Some(ref block_cache) = self.block_cache {
-            let blk = block_cache
-                .try_get_with((self.id, block_idx), || self.read_block(block_idx))
-                .map_err(|e| anyhow!("{}", e))?;
-            Ok(blk)
+            block_cache.get_data_block(self.id, block_idx, || self.read_block(block_idx))
         } else {
             self.read_block(block_idx)
         }
     }
      ///从磁盘读取一个块。
      pub fn read_block(&self, block_idx: usize) -> Result<Arc<Block>> {
-        let offset = self.block_meta[block_idx].offset;
-        let offset_end = self
-            .block_meta
-            .get(block_idx + 1)
-            .map_or(self.block_meta_offset, |x| x.offset);
-        let block_len = offset_end - offset - 4;
-        let block_data_with_chksum: Vec<u8> = self
+        let (offset, offset_end) = self.block_range(block_idx)?;
+        let block_data_with_chksum = self
             .file
-            .read(offset as u64, (offset_end - offset) as u64)?;
-        let block_data = &block_data_with_chksum[..block_len];
-        let checksum = (&block_data_with_chksum[block_len..]).get_u32();
-        if checksum != crc32fast::hash(block_data) {
-            bail!("block checksum mismatched");
-        }
-        Ok(Arc::new(Block::decode(block_data)))
+            .read_bytes(offset as u64, (offset_end - offset) as u64)?;
+        Ok(Arc::new(decode_block(block_data_with_chksum)?))
     }
     
     ///获取数据块的数量。
     pub fn num_of_blocks(&self) -> usize {
-        self.block_meta.len()
+        self.num_blocks
     }
-    
+
+    /// 测试用：把排好序的键值对写成不压缩的SST文件
+    #[cfg(test)]
+    pub(crate) fn write_for_testing(path: &Path, entries: &[(&[u8], &[u8])]) -> Result<()> {
+        let mut builder = SsTableBuilder::new(4096, BlockCompression::None, None);
+        for (key, value) in entries {
+            builder.add(KeySlice::from_slice(key), value);
+        }
+        std::fs::write(path, builder.finish())?;
+        Ok(())
+    }
+}
+
+/// 按键的顺序添加键值对，生成 SST 文件的内容：
+/// | 数据块 ... | 块元 | 块元偏移 (u32) | 布隆过滤器 | 过滤器偏移 (u32) |
+pub struct SsTableBuilder {
+    builder: BlockBuilder,
+    /// 当前数据块的首尾键
+    first_key: KeyVec,
+    last_key: KeyVec,
+    /// 已经写完的数据块
+    data: Vec<u8>,
+    meta: Vec<BlockMeta>,
+    block_size: usize,
+    /// 数据块的压缩算法
+    compression: BlockCompression,
+    /// 布隆过滤器里的哈希
+    key_hashes: Vec<u32>,
+    /// 设置后每个键的前缀也加入布隆过滤器
+    prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+    /// 上一个加入过滤器的前缀的哈希，相邻的键前缀多半相同，只加一次
+    last_prefix_hash: Option<u32>,
+}
+
+impl SsTableBuilder {
+    pub fn new(
+        block_size: usize,
+        compression: BlockCompression,
+        prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+    ) -> Self {
+        Self {
+            builder: BlockBuilder::new(block_size, DEFAULT_RESTART_INTERVAL),
+            first_key: KeyVec::new(),
+            last_key: KeyVec::new(),
+            data: Vec::new(),
+            meta: Vec::new(),
+            block_size,
+            compression,
+            key_hashes: Vec::new(),
+            prefix_extractor,
+            last_prefix_hash: None,
+        }
+    }
+
+    /// 添加一个键值对，键要比之前添加的都大。当前数据块满了就压缩写出，开始一个新块。
+    pub fn add(&mut self, key: KeySlice, value: &[u8]) {
+        self.key_hashes.push(farmhash::fingerprint32(key.raw_ref()));
+        if let Some(prefix) = self
+            .prefix_extractor
+            .as_ref()
+            .and_then(|extractor| extractor.prefix(key.raw_ref()))
+        {
+            let hash = farmhash::fingerprint32(prefix);
+            if self.last_prefix_hash != Some(hash) {
+                self.key_hashes.push(hash);
+                self.last_prefix_hash = Some(hash);
+            }
+        }
+        if !self.builder.add(key, value) {
+            self.finish_block();
+            assert!(self.builder.add(key, value));
+        }
+        if self.first_key.is_empty() {
+            self.first_key.set_from_slice(key);
+        }
+        self.last_key.set_from_slice(key);
+    }
+
+    /// 已经写出的数据块的大小
+    pub fn estimated_size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.meta.is_empty() && self.builder.is_empty()
+    }
+
+    fn finish_block(&mut self) {
+        let builder = std::mem::replace(
+            &mut self.builder,
+            BlockBuilder::new(self.block_size, DEFAULT_RESTART_INTERVAL),
+        );
+        self.meta.push(BlockMeta {
+            offset: self.data.len(),
+            first_key: std::mem::take(&mut self.first_key).into_key_bytes(),
+            last_key: std::mem::take(&mut self.last_key).into_key_bytes(),
+        });
+        encode_block(&builder.build(), self.compression, &mut self.data);
+    }
+
+    /// 写出最后一个数据块，加上块元和布隆过滤器，返回整个文件的内容
+    pub fn finish(mut self) -> Vec<u8> {
+        assert!(!self.is_empty(), "SST should not be empty");
+        if !self.builder.is_empty() {
+            self.finish_block();
+        }
+        let mut buf = self.data;
+        let meta_offset = buf.len();
+        BlockMeta::encode_block_meta(&self.meta, &mut buf);
+        buf.put_u32(meta_offset as u32);
+        let bloom_offset = buf.len();
+        let bits_per_key = Bloom::bloom_bits_per_key(self.key_hashes.len(), 0.01);
+        Bloom::build_from_key_hashes(&self.key_hashes, bits_per_key).encode(&mut buf);
+        buf.put_u32(bloom_offset as u32);
+        buf
+    }
+}
+
+/// 把块压缩后追加到 `buf`：| 压缩后的块 | codec id (u8) | checksum (u32) |，校验和覆盖 codec id。
+pub(crate) fn encode_block(block: &Block, compression: BlockCompression, buf: &mut Vec<u8>) {
+    let offset = buf.len();
+    buf.extend(compression.compress(&block.encode()));
+    buf.put_u8(compression.codec_id());
+    buf.put_u32(crc32fast::hash(&buf[offset..]));
+}
+
+/// `encode_block` 的逆过程，先校验再按 codec id 解压。没有压缩时块直接引用 `raw`。
+pub(crate) fn decode_block(raw: Bytes) -> Result<Block> {
+    if raw.len() < 5 {
+        bail!("block too short");
+    }
+    let block_len = raw.len() - 4;
+    let checksum = (&raw[block_len..]).get_u32();
+    if checksum != crc32fast::hash(&raw[..block_len]) {
+        bail!("block checksum mismatched");
+    }
+    let compression = BlockCompression::from_codec_id(raw[block_len - 1])?;
+    if compression == BlockCompression::None {
+        return Ok(Block::decode_bytes(raw.slice(..block_len - 1)));
+    }
+    let block_data = compression.decompress(&raw[..block_len - 1])?;
+    Ok(Block::decode_bytes(block_data.into()))
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -130,11 +510,11 @@ impl BlockMeta {
             // 偏移量的大小
             estimated_size += std::mem::size_of::<u32>();
             //键长度的大小
-            estimated_size += std::mem::size_of::<u16>();
+            estimated_size += varint_len(meta.first_key.len() as u64);
             // 实际键的大小
             estimated_size += meta.first_key.len();
             // 键长度的大小
-            estimated_size += std::mem::size_of::<u16>();
+            estimated_size += varint_len(meta.last_key.len() as u64);
             // 实际键的大小
             estimated_size += meta.last_key.len();
         }
@@ -145,9 +525,9 @@ impl BlockMeta {
         buf.put_u32(block_meta.len() as u32);
         for meta in block_meta {
             buf.put_u32(meta.offset as u32);
-            buf.put_u16(meta.first_key.len() as u16);
+            put_varint(buf, meta.first_key.len() as u64);
             buf.put_slice(meta.first_key.raw_ref());
-            buf.put_u16(meta.last_key.len() as u16);
+            put_varint(buf, meta.last_key.len() as u64);
             buf.put_slice(meta.last_key.raw_ref());
         }
         buf.put_u32(crc32fast::hash(&buf[original_len + 4..]));
@@ -155,14 +535,24 @@ impl BlockMeta {
     }
     /// 从缓冲区解码块元。
     pub fn decode_block_meta(mut buf: &[u8]) -> Result<Vec<BlockMeta>> {
-        let mut block_meta = Vec::new();
         let num = buf.get_u32() as usize;
         let checksum = crc32fast::hash(&buf[..buf.remaining() - 4]);
+        let block_meta = Self::decode_block_meta_entries(&mut buf, num);
+        if buf.get_u32() != checksum {
+            bail!("meta checksum mismatched");
+        }
+
+        Ok(block_meta)
+    }
+
+    /// 解码 `num` 个块元，不带块元数量和校验和
+    pub(crate) fn decode_block_meta_entries(buf: &mut &[u8], num: usize) -> Vec<BlockMeta> {
+        let mut block_meta = Vec::with_capacity(num);
         for _ in 0..num {
             let offset = buf.get_u32() as usize;
-            let first_key_len = buf.get_u16() as usize;
+            let first_key_len = get_varint(buf) as usize;
             let first_key = KeyBytes::from_bytes(buf.copy_to_bytes(first_key_len));
-            let last_key_len: usize = buf.get_u16() as usize;
+            let last_key_len: usize = get_varint(buf) as usize;
             let last_key = KeyBytes::from_bytes(buf.copy_to_bytes(last_key_len));
             block_meta.push(BlockMeta {
                 offset,
@@ -170,27 +560,123 @@ impl BlockMeta {
                 last_key,
             });
         }
-        if buf.get_u32() != checksum {
-            bail!("meta checksum mismatched");
-        }
+        block_meta
+    }
 
-        Ok(block_meta)
+    /// 把已经检查过校验和的块元按每 `partition_size` 个分成一个分区，`base_offset` 是块元在文件中的起始位置
+    pub(crate) fn partition_block_meta(
+        raw: &[u8],
+        base_offset: u64,
+        partition_size: usize,
+    ) -> Vec<IndexPartition> {
+        let partition_size = partition_size.max(1);
+        let mut buf = raw;
+        let num = buf.get_u32() as usize;
+        let mut partitions = Vec::with_capacity(num.div_ceil(partition_size));
+        let mut first_block_idx = 0;
+        while first_block_idx < num {
+            let partition_num = partition_size.min(num - first_block_idx);
+            let start = raw.len() - buf.len();
+            let block_meta = Self::decode_block_meta_entries(&mut buf, partition_num);
+            let end = raw.len() - buf.len();
+            partitions.push(IndexPartition {
+                first_key: block_meta[0].first_key.clone(),
+                first_block_idx,
+                first_offset: block_meta[0].offset,
+                offset: base_offset + start as u64,
+                len: (end - start) as u64,
+                num: partition_num,
+                checksum: crc32fast::hash(&raw[start..end]),
+            });
+            first_block_idx += partition_num;
+        }
+        partitions
     }
 }
 
 ///一个文件对象。
-pub struct FileObject(Option<File>, u64);
+pub struct FileObject(Option<FileHandle>, u64);
+
+/// 文件对象背后的文件
+enum FileHandle {
+    /// 一直打开的文件
+    Open(File),
+    /// 只读映射的整个文件
+    Mmap(Bytes),
+    /// 用 `O_DIRECT` 打开的文件，绕过页缓存
+    #[cfg(target_os = "linux")]
+    Direct(File),
+    /// 通过表缓存按需打开，被淘汰时关闭
+    Cached {
+        id: usize,
+        path: PathBuf,
+        table_cache: Arc<TableCache>,
+    },
+}
 
 impl FileObject {
     pub fn read(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
         let mut data = vec![0; len as usize];
-        // self.0
-        //     .as_ref()
-        //     .unwrap()
-        //     .read_exact_at(&mut data[..], offset)?;
+        match &self.0 {
+            Some(FileHandle::Open(file)) => read_exact_at(file, &mut data, offset)?,
+            Some(FileHandle::Mmap(map)) => data.copy_from_slice(&Self::slice(map, offset, len)?),
+            #[cfg(target_os = "linux")]
+            Some(FileHandle::Direct(file)) => {
+                data.copy_from_slice(&direct_io::pread_direct(file, offset, len)?)
+            }
+            Some(FileHandle::Cached {
+                id,
+                path,
+                table_cache,
+            }) => {
+                let file = table_cache.get_or_open(*id, path)?;
+                read_exact_at(&file, &mut data, offset)?
+            }
+            None => bail!("file object has no file"),
+        }
         Ok(data)
     }
 
+    /// 读取一段数据，映射的文件直接切片，不拷贝
+    pub fn read_bytes(&self, offset: u64, len: u64) -> Result<Bytes> {
+        match &self.0 {
+            Some(FileHandle::Mmap(map)) => Self::slice(map, offset, len),
+            #[cfg(target_os = "linux")]
+            Some(FileHandle::Direct(file)) => direct_io::pread_direct(file, offset, len),
+            _ => Ok(self.read(offset, len)?.into()),
+        }
+    }
+
+    /// 一次读取多段数据。Linux 上用 io_uring 并行读取，其他情况逐段读取
+    pub fn read_many(&self, ranges: &[(u64, u64)]) -> Result<Vec<Bytes>> {
+        #[cfg(target_os = "linux")]
+        match &self.0 {
+            Some(FileHandle::Open(file)) => return direct_io::read_many(file, ranges, false),
+            Some(FileHandle::Direct(file)) => return direct_io::read_many(file, ranges, true),
+            Some(FileHandle::Cached {
+                id,
+                path,
+                table_cache,
+            }) => {
+                let file = table_cache.get_or_open(*id, path)?;
+                return direct_io::read_many(&file, ranges, false);
+            }
+            _ => {}
+        }
+        ranges
+            .iter()
+            .map(|&(offset, len)| self.read_bytes(offset, len))
+            .collect()
+    }
+
+    fn slice(map: &Bytes, offset: u64, len: u64) -> Result<Bytes> {
+        let end = offset + len;
+        if end > map.len() as u64 {
+            bail!("read out of range: {}..{} of {}", offset, end, map.len());
+        }
+        Ok(map.slice(offset as usize..end as usize))
+    }
+
     pub fn size(&self) -> u64 {
         self.1
     }
@@ -198,9 +684,35 @@ impl FileObject {
     /// Create a new file object (day 2) and write the file to the disk (day 4).
     pub fn create(path: &Path, data: Vec<u8>) -> Result<Self> {
         std::fs::write(path, &data)?;
-        File::open(path)?.sync_all()?;
+        sync_file(&File::open(path)?, path)?;
+        sync_parent_dir(path)?;
+        Ok(FileObject(
+            Some(FileHandle::Open(
+                File::options().read(true).write(false).open(path)?,
+            )),
+            data.len() as u64,
+        ))
+    }
+
+    /// 和 `create` 一样，但每写一段之前先向限速器申请令牌。刷新和压缩写 SST 时用它。
+    pub fn create_with_rate_limiter(
+        path: &Path,
+        data: Vec<u8>,
+        rate_limiter: &RateLimiter,
+        priority: IoPriority,
+    ) -> Result<Self> {
+        const WRITE_CHUNK_SIZE: usize = 64 << 10;
+        let mut file = File::create(path)?;
+        for chunk in data.chunks(WRITE_CHUNK_SIZE) {
+            rate_limiter.request(chunk.len(), priority);
+            file.write_all(chunk)?;
+        }
+        sync_file(&file, path)?;
+        sync_parent_dir(path)?;
         Ok(FileObject(
-            Some(File::options().read(true).write(false).open(path)?),
+            Some(FileHandle::Open(
+                File::options().read(true).write(false).open(path)?,
+            )),
             data.len() as u64,
         ))
     }
@@ -208,6 +720,142 @@ impl FileObject {
     pub fn open(path: &Path) -> Result<Self> {
         let file = File::options().read(true).write(false).open(path)?;
         let size = file.metadata()?.len();
-        Ok(FileObject(Some(file), size))
+        Ok(FileObject(Some(FileHandle::Open(file)), size))
+    }
+
+    /// 只读映射整个文件，映射建立后文件句柄就可以关闭
+    pub fn open_mmap(path: &Path) -> Result<Self> {
+        let file = File::options().read(true).write(false).open(path)?;
+        // SAFETY: SST 文件写完后不再修改，也不会在映射期间被截断
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        let size = map.len() as u64;
+        Ok(FileObject(Some(FileHandle::Mmap(Bytes::from_owner(map))), size))
+    }
+
+    /// 用 `O_DIRECT` 打开文件，读取时不经过页缓存。只在 Linux 上生效，
+    /// 其他系统或文件系统不支持时按普通文件打开
+    pub fn open_direct(path: &Path) -> Result<Self> {
+        #[cfg(target_os = "linux")]
+        if let Some(file) = direct_io::open_direct(path)? {
+            let size = file.metadata()?.len();
+            return Ok(FileObject(Some(FileHandle::Direct(file)), size));
+        }
+        Self::open(path)
+    }
+
+    /// 打开一个通过表缓存读取的文件，读取时才真正打开
+    pub fn open_cached(id: usize, path: &Path, table_cache: Arc<TableCache>) -> Result<Self> {
+        let size = std::fs::metadata(path)?.len();
+        Ok(FileObject(
+            Some(FileHandle::Cached {
+                id,
+                path: path.to_path_buf(),
+                table_cache,
+            }),
+            size,
+        ))
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)?;
+    Ok(())
+}
+
+#[cfg(windows)]
+pub(crate) fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        let n = file.seek_read(buf, offset)?;
+        if n == 0 {
+            bail!("failed to fill whole buffer");
+        }
+        buf = &mut buf[n..];
+        offset += n as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::{decode_block, encode_block, BlockMeta, FileObject};
+    use crate::{
+        block::{BlockBuilder, DEFAULT_RESTART_INTERVAL},
+        compression::BlockCompression,
+        key::{KeyBytes, KeySlice},
+    };
+
+    #[test]
+    fn test_block_compression() {
+        let value = br#"{"tenant":"t1","table":"users","name":"alice"}"#;
+        let mut builder = BlockBuilder::new(4096, DEFAULT_RESTART_INTERVAL);
+        for i in 0..20u8 {
+            assert!(builder.add(KeySlice::from_slice(&[b'a' + i]), value));
+        }
+        let block = builder.build();
+        let mut plain = Vec::new();
+        encode_block(&block, BlockCompression::None, &mut plain);
+        let mut compressed = Vec::new();
+        encode_block(&block, BlockCompression::Lz4, &mut compressed);
+        assert!(compressed.len() < plain.len());
+        for raw in [plain, compressed] {
+            let decoded = decode_block(raw.into()).unwrap();
+            assert_eq!(decoded.data, block.data);
+            assert_eq!(decoded.restarts, block.restarts);
+        }
+    }
+
+    #[test]
+    fn test_partitioned_index() {
+        let block_meta = (0..10u8)
+            .map(|i| BlockMeta {
+                offset: i as usize * 100,
+                first_key: KeyBytes::from_bytes(Bytes::from(vec![b'a' + i; 2])),
+                last_key: KeyBytes::from_bytes(Bytes::from(vec![b'a' + i; 3])),
+            })
+            .collect::<Vec<_>>();
+        let mut raw = Vec::new();
+        BlockMeta::encode_block_meta(&block_meta, &mut raw);
+        let base_offset = 1000;
+        let partitions = BlockMeta::partition_block_meta(&raw, base_offset, 3);
+        assert_eq!(partitions.len(), 4);
+        let mut decoded = Vec::new();
+        for partition in &partitions {
+            let start = (partition.offset - base_offset) as usize;
+            let mut buf = &raw[start..start + partition.len as usize];
+            assert_eq!(crc32fast::hash(buf), partition.checksum);
+            let metas = BlockMeta::decode_block_meta_entries(&mut buf, partition.num);
+            assert!(buf.is_empty());
+            assert_eq!(metas[0].first_key, partition.first_key);
+            assert_eq!(metas[0].offset, partition.first_offset);
+            assert_eq!(partition.first_block_idx, decoded.len());
+            decoded.extend(metas);
+        }
+        assert_eq!(decoded, block_meta);
+    }
+
+    #[test]
+    fn test_mmap_read_is_zero_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("00001.sst");
+        let mut builder = BlockBuilder::new(4096, DEFAULT_RESTART_INTERVAL);
+        assert!(builder.add(KeySlice::from_slice(b"a"), b"1"));
+        let block = builder.build();
+        let mut data = b"header".to_vec();
+        encode_block(&block, BlockCompression::None, &mut data);
+        std::fs::write(&path, &data).unwrap();
+        let file = FileObject::open_mmap(&path).unwrap();
+        let len = data.len() as u64 - 6;
+        let raw = file.read_bytes(6, len).unwrap();
+        assert_eq!(raw.as_ptr(), file.read_bytes(6, len).unwrap().as_ptr());
+        let decoded = decode_block(raw.clone()).unwrap();
+        assert_eq!(decoded.data.as_ptr(), raw.as_ptr());
+        assert_eq!(decoded.data, block.data);
+        assert_eq!(file.read(6, len).unwrap(), &raw[..]);
+        assert!(file.read_bytes(6, len + 1).is_err());
     }
 }
@@ -0,0 +1,71 @@
+use std::{fs::File, path::Path, sync::Arc};
+
+use anyhow::{anyhow, Context, Result};
+
+/// 限制同时打开的 SST 文件数的表缓存。SST 的元数据一直保存在 `SsTable` 里，
+/// 这里只缓存文件句柄，被淘汰的句柄在最后一个读者用完后关闭，下次读取时重新打开。
+pub struct TableCache {
+    /// 键是 SST id
+    cache: moka::sync::Cache<usize, Arc<File>>,
+}
+
+impl TableCache {
+    /// 最多同时打开 `max_open_files` 个 SST 文件（近似值）
+    pub fn new(max_open_files: usize) -> Self {
+        Self {
+            cache: moka::sync::Cache::new(max_open_files as u64),
+        }
+    }
+
+    /// 取出已经打开的文件，没有时打开它
+    pub(crate) fn get_or_open(&self, id: usize, path: &Path) -> Result<Arc<File>> {
+        self.cache
+            .try_get_with(id, || {
+                File::options()
+                    .read(true)
+                    .write(false)
+                    .open(path)
+                    .map(Arc::new)
+                    .with_context(|| format!("failed to open SST: {}", id))
+            })
+            .map_err(|e| anyhow!("{}", e))
+    }
+
+    /// 关闭已经删除的 SST 的文件句柄
+    pub(crate) fn evict(&self, id: usize) {
+        self.cache.invalidate(&id);
+    }
+
+    /// 当前打开的文件数
+    pub fn open_files(&self) -> u64 {
+        self.cache.entry_count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use moka::sync::ConcurrentCacheExt;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::sstable::FileObject;
+
+    #[test]
+    fn test_table_cache_reopens_files() {
+        let dir = tempdir().unwrap();
+        let table_cache = Arc::new(TableCache::new(1));
+        let mut files = Vec::new();
+        for id in 0..3usize {
+            let path = dir.path().join(format!("{:05}.sst", id));
+            std::fs::write(&path, vec![id as u8; 16]).unwrap();
+            files.push(FileObject::open_cached(id, &path, table_cache.clone()).unwrap());
+        }
+        for _ in 0..2 {
+            for (id, file) in files.iter().enumerate() {
+                assert_eq!(file.read(4, 8).unwrap(), vec![id as u8; 8]);
+            }
+        }
+        table_cache.cache.sync();
+        assert!(table_cache.open_files() <= 1);
+    }
+}
@@ -8,6 +8,8 @@ pub struct TwoMergeIterator<A: StorageIterator, B: StorageIterator> {
     a: A,
     b: B,
     choose_a: bool,
+    /// 是否在反向迭代
+    reverse: bool,
 }
 
 impl<
@@ -15,31 +17,47 @@ impl<
         B: 'static + for<'a> StorageIterator<KeyType<'a> = A::KeyType<'a>>,
     > TwoMergeIterator<A, B>
 {
-    fn choose_a(a: &A, b: &B) -> bool {
+    fn choose_a(a: &A, b: &B, reverse: bool) -> bool {
         if !a.is_valid() {
             return false;
         }
         if !b.is_valid() {
             return true;
         }
-        a.key() < b.key()
+        if reverse {
+            a.key() > b.key()
+        } else {
+            a.key() < b.key()
+        }
     }
 
     fn skip_b(&mut self) -> Result<()> {
         if self.a.is_valid() && self.b.is_valid() && self.b.key() == self.a.key() {
-            self.b.next()?;
+            if self.reverse {
+                self.b.prev()?;
+            } else {
+                self.b.next()?;
+            }
         }
         Ok(())
     }
 
+    /// 两边都重新定位之后，跳过 B 中重复的键并选出当前的迭代器
+    fn reset(&mut self, reverse: bool) -> Result<()> {
+        self.reverse = reverse;
+        self.skip_b()?;
+        self.choose_a = Self::choose_a(&self.a, &self.b, reverse);
+        Ok(())
+    }
+
     pub fn create(a: A, b: B) -> Result<Self> {
         let mut iter = Self {
             choose_a: false,
+            reverse: false,
             a,
             b,
         };
-        iter.skip_b()?;
-        iter.choose_a = Self::choose_a(&iter.a, &iter.b);
+        iter.reset(false)?;
         Ok(iter)
     }
 }
@@ -76,14 +94,71 @@ impl<
     }
 
     fn next(&mut self) -> Result<()> {
+        if self.reverse {
+            // 换方向：另一边定位到比当前键大的第一个键
+            if self.choose_a {
+                let key = self.a.key();
+                self.b.seek(key)?;
+                if self.b.is_valid() && self.b.key() == key {
+                    self.b.next()?;
+                }
+            } else {
+                let key = self.b.key();
+                self.a.seek(key)?;
+                if self.a.is_valid() && self.a.key() == key {
+                    self.a.next()?;
+                }
+            }
+        }
         if self.choose_a {
             self.a.next()?;
         } else {
             self.b.next()?;
         }
-        self.skip_b()?;
-        self.choose_a = Self::choose_a(&self.a, &self.b);
-        Ok(())
+        self.reset(false)
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        if !self.reverse {
+            // 换方向：另一边定位到比当前键小的最后一个键
+            if self.choose_a {
+                let key = self.a.key();
+                self.b.seek_for_prev(key)?;
+                if self.b.is_valid() && self.b.key() == key {
+                    self.b.prev()?;
+                }
+            } else {
+                let key = self.b.key();
+                self.a.seek_for_prev(key)?;
+                if self.a.is_valid() && self.a.key() == key {
+                    self.a.prev()?;
+                }
+            }
+        }
+        if self.choose_a {
+            self.a.prev()?;
+        } else {
+            self.b.prev()?;
+        }
+        self.reset(true)
+    }
+
+    fn seek(&mut self, key: Self::KeyType<'_>) -> Result<()> {
+        self.a.seek(key)?;
+        self.b.seek(key)?;
+        self.reset(false)
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.a.seek_to_last()?;
+        self.b.seek_to_last()?;
+        self.reset(true)
+    }
+
+    fn seek_for_prev(&mut self, key: Self::KeyType<'_>) -> Result<()> {
+        self.a.seek_for_prev(key)?;
+        self.b.seek_for_prev(key)?;
+        self.reset(true)
     }
 
     fn num_active_iterators(&self) -> usize {
@@ -0,0 +1,131 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut};
+
+use crate::blob::BlobPointer;
+
+/// 每个非空值的第一个字节是值头部的类型标记，空值仍然表示删除（墓碑）。
+/// 头部写在值里面，所以 `MemTable`、`Block` 和 WAL 的编码都不需要改变。
+pub(crate) const VALUE_PLAIN: u8 = 0;
+/// 带过期时间的值，标记后面是 8 字节的过期时间（unix 毫秒时间戳）。
+pub(crate) const VALUE_WITH_TTL: u8 = 1;
+/// 合并操作数，读取或压缩时由 `MergeOperator` 和更旧的版本合并。
+pub(crate) const VALUE_MERGE: u8 = 2;
+/// 保存在 blob 文件里的大值，标记后面是 8 字节的过期时间（0 表示不过期）和 `BlobPointer`。
+pub(crate) const VALUE_BLOB: u8 = 3;
+
+const SIZEOF_U64: usize = std::mem::size_of::<u64>();
+
+/// 解码后的值。
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ValueRef<'a> {
+    /// 墓碑，或者已经过期的值
+    Deleted,
+    /// 有效的值
+    Put(&'a [u8]),
+    /// 合并操作数
+    Merge(&'a [u8]),
+    /// 指向 blob 文件的指针
+    Blob(BlobPointer),
+}
+
+/// 当前时间，unix 毫秒时间戳。
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// 计算从现在起经过 `ttl` 后的过期时间。
+pub(crate) fn expire_at_ms(ttl: Duration) -> u64 {
+    now_ms().saturating_add(ttl.as_millis() as u64)
+}
+
+/// 给值加上头部。`expire_at` 为 `None` 时永不过期。
+pub(crate) fn encode_value(value: &[u8], expire_at: Option<u64>) -> Vec<u8> {
+    match expire_at {
+        Some(expire_at) => {
+            let mut buf = Vec::with_capacity(1 + SIZEOF_U64 + value.len());
+            buf.put_u8(VALUE_WITH_TTL);
+            buf.put_u64(expire_at);
+            buf.put_slice(value);
+            buf
+        }
+        None => {
+            let mut buf = Vec::with_capacity(1 + value.len());
+            buf.put_u8(VALUE_PLAIN);
+            buf.put_slice(value);
+            buf
+        }
+    }
+}
+
+/// 给合并操作数加上头部。
+pub(crate) fn encode_merge_operand(operand: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(1 + operand.len());
+    buf.put_u8(VALUE_MERGE);
+    buf.put_slice(operand);
+    buf
+}
+
+/// 给 blob 指针加上头部，`expire_at` 为 `None` 时永不过期。
+pub(crate) fn encode_blob_pointer(ptr: BlobPointer, expire_at: Option<u64>) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(1 + SIZEOF_U64 + 30);
+    buf.put_u8(VALUE_BLOB);
+    buf.put_u64(expire_at.unwrap_or(0));
+    ptr.encode(&mut buf);
+    buf
+}
+
+/// 把 blob 值改成指向 `ptr`，保留原来的过期时间，GC 重写值时使用。
+pub(crate) fn relocate_blob_pointer(raw: &[u8], ptr: BlobPointer) -> Vec<u8> {
+    let mut buf = raw[..1 + SIZEOF_U64].to_vec();
+    ptr.encode(&mut buf);
+    buf
+}
+
+/// 值头部里的过期时间，没有设置 TTL 时返回 `None`。
+pub(crate) fn value_expire_at(raw: &[u8]) -> Option<u64> {
+    match raw.first() {
+        Some(&VALUE_WITH_TTL) | Some(&VALUE_BLOB) if raw.len() > SIZEOF_U64 => {
+            Some((&raw[1..]).get_u64()).filter(|expire_at| *expire_at != 0)
+        }
+        _ => None,
+    }
+}
+
+/// 解析值头部。过期的值和墓碑一样返回 `Deleted`，这样它也会遮住更旧的版本。
+pub(crate) fn decode_value(raw: &[u8], now: u64) -> Result<ValueRef<'_>> {
+    if raw.is_empty() {
+        return Ok(ValueRef::Deleted);
+    }
+    let mut buf = &raw[1..];
+    match raw[0] {
+        VALUE_PLAIN => Ok(ValueRef::Put(buf)),
+        VALUE_WITH_TTL => {
+            if buf.len() < SIZEOF_U64 {
+                bail!("value header too short");
+            }
+            let expire_at = buf.get_u64();
+            if expire_at <= now {
+                Ok(ValueRef::Deleted)
+            } else {
+                Ok(ValueRef::Put(buf))
+            }
+        }
+        VALUE_MERGE => Ok(ValueRef::Merge(buf)),
+        VALUE_BLOB => {
+            if buf.len() < SIZEOF_U64 {
+                bail!("value header too short");
+            }
+            let expire_at = buf.get_u64();
+            if expire_at != 0 && expire_at <= now {
+                Ok(ValueRef::Deleted)
+            } else {
+                Ok(ValueRef::Blob(BlobPointer::decode(&mut buf)))
+            }
+        }
+        kind => bail!("unknown value kind: {}", kind),
+    }
+}
@@ -0,0 +1,36 @@
+use bytes::{Buf, BufMut};
+
+/// LEB128 变长整数：每个字节的低 7 位存数据，最高位为 1 表示后面还有字节。
+/// `Block`、`BlockMeta` 和 WAL 里的长度都用它编码，所以键和值不再受 u16 的限制。
+pub(crate) fn put_varint(buf: &mut Vec<u8>, mut x: u64) {
+    while x >= 0x80 {
+        buf.put_u8((x as u8) | 0x80);
+        x >>= 7;
+    }
+    buf.put_u8(x as u8);
+}
+
+/// 读取一个变长整数并前移 `buf`，和 `Buf::get_u16` 一样，数据不够时 panic。
+pub(crate) fn get_varint(buf: &mut &[u8]) -> u64 {
+    let mut x = 0;
+    let mut shift = 0;
+    loop {
+        let byte = buf.get_u8();
+        assert!(shift < 64, "varint too long");
+        x |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return x;
+        }
+        shift += 7;
+    }
+}
+
+/// 编码后的字节数
+pub(crate) fn varint_len(mut x: u64) -> usize {
+    let mut len = 1;
+    while x >= 0x80 {
+        x >>= 7;
+        len += 1;
+    }
+    len
+}
@@ -0,0 +1,240 @@
+use std::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use parking_lot::{Condvar, Mutex};
+
+use crate::lsm_storage::{CompactionOptions, LsmStorageInner};
+
+/// 进入减速状态时每次写入额外等待的时间
+const WRITE_SLOWDOWN_DELAY: Duration = Duration::from_millis(1);
+
+/// 停写时最长等多久重新检查一次，防止错过唤醒后一直阻塞
+const WRITE_STOP_RECHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// 写入停顿的统计信息
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteStallStats {
+    /// 写入者因为减速和停写一共等待的时间，以微秒为单位
+    pub stall_micros: u64,
+    /// 被减速的写入次数
+    pub slowdowns: u64,
+    /// 被阻塞直到后台线程赶上来的写入次数
+    pub stops: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum WriteStallCondition {
+    Normal,
+    Delayed,
+    Stopped,
+}
+
+/// 刷新或压缩跟不上写入时，让写入者在写入之前减速或者阻塞，
+/// 刷新线程和压缩线程有进展后唤醒它们，关闭时唤醒它们返回错误。
+#[derive(Default)]
+pub(crate) struct WriteController {
+    mutex: Mutex<()>,
+    condvar: Condvar,
+    /// 启动了压缩线程。没有压缩线程时 L0 不会变少，停写会一直阻塞，所以不停顿
+    has_compaction_thread: AtomicBool,
+    stall_micros: AtomicU64,
+    slowdowns: AtomicU64,
+    stops: AtomicU64,
+}
+
+impl WriteController {
+    /// 唤醒所有被阻塞的写入者，让它们重新检查是否还需要等待
+    pub fn wake_stalled_writers(&self) {
+        let _guard = self.mutex.lock();
+        self.condvar.notify_all();
+    }
+
+    pub(crate) fn set_has_compaction_thread(&self) {
+        self.has_compaction_thread.store(true, Ordering::SeqCst);
+    }
+
+    pub fn stats(&self) -> WriteStallStats {
+        WriteStallStats {
+            stall_micros: self.stall_micros.load(Ordering::Relaxed),
+            slowdowns: self.slowdowns.load(Ordering::Relaxed),
+            stops: self.stops.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl LsmStorageInner {
+    /// 所有会压缩的列族中最坏的情况：L0 文件数或待压缩的字节数超过阈值。
+    /// 不压缩的列族等不到 L0 变少，不参与判断
+    pub(crate) fn write_stall_condition(&self) -> WriteStallCondition {
+        let options = &self.options;
+        if !self
+            .write_controller
+            .has_compaction_thread
+            .load(Ordering::SeqCst)
+        {
+            return WriteStallCondition::Normal;
+        }
+        let mut l0_files = 0;
+        let mut pending_compaction_bytes = 0;
+        for cf in self.column_families.read().values() {
+            let state = cf.state.read();
+            if !matches!(
+                cf.options.compaction_options,
+                CompactionOptions::NoCompaction
+            ) {
+                l0_files = l0_files.max(state.l0_sstables.len());
+                // 粗略估计：L0 里的数据都还要往下压缩
+                pending_compaction_bytes += state
+                    .l0_sstables
+                    .iter()
+                    .filter_map(|id| state.sstables.get(id))
+                    .map(|table| table.table_size())
+                    .sum::<u64>();
+            }
+        }
+        let exceeds =
+            |trigger: Option<usize>| matches!(trigger, Some(trigger) if l0_files >= trigger);
+        if exceeds(options.level0_stop_writes_trigger)
+            || matches!(options.max_pending_compaction_bytes, Some(max) if pending_compaction_bytes >= max)
+        {
+            WriteStallCondition::Stopped
+        } else if exceeds(options.level0_slowdown_writes_trigger) {
+            WriteStallCondition::Delayed
+        } else {
+            WriteStallCondition::Normal
+        }
+    }
+
+    /// 写入之前按当前的停顿状态减速或阻塞写入者，不能持有任何锁时调用。阻塞期间关闭时返回错误
+    pub(crate) fn maybe_stall_writes(&self) -> Result<()> {
+        let controller = &self.write_controller;
+        let start = Instant::now();
+        match self.write_stall_condition() {
+            WriteStallCondition::Normal => return Ok(()),
+            WriteStallCondition::Delayed => {
+                controller.slowdowns.fetch_add(1, Ordering::Relaxed);
+                let mut guard = controller.mutex.lock();
+                controller
+                    .condvar
+                    .wait_for(&mut guard, WRITE_SLOWDOWN_DELAY);
+            }
+            WriteStallCondition::Stopped => {
+                controller.stops.fetch_add(1, Ordering::Relaxed);
+                let mut guard = controller.mutex.lock();
+                // 在锁内重新检查，唤醒者也要先拿锁，不会错过唤醒
+                while self.write_stall_condition() == WriteStallCondition::Stopped {
+                    self.check_writable()?;
+                    controller
+                        .condvar
+                        .wait_for(&mut guard, WRITE_STOP_RECHECK_INTERVAL);
+                }
+            }
+        }
+        controller
+            .stall_micros
+            .fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// 写入停顿的累计时间和次数
+    pub fn write_stall_stats(&self) -> WriteStallStats {
+        self.write_controller.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{atomic::Ordering, Arc},
+        time::Duration,
+    };
+
+    use tempfile::tempdir;
+
+    use crate::{
+        compact::LeveledCompactionOptions,
+        lsm_storage::{CompactionOptions, LsmStorageInner, LsmStorageOptions, LsmStorageState},
+    };
+
+    /// 假装刷新落后了，L0 里有 `num` 个文件
+    fn set_l0_files(storage: &LsmStorageInner, num: usize) {
+        let mut guard = storage.state.write();
+        let mut snapshot = LsmStorageState::clone(&guard);
+        snapshot.l0_sstables = (0..num).collect();
+        *guard = Arc::new(snapshot);
+    }
+
+    #[test]
+    fn test_write_stall() {
+        let dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.level0_slowdown_writes_trigger = Some(1);
+        options.level0_stop_writes_trigger = Some(2);
+        options.compaction_options = CompactionOptions::Leveled(LeveledCompactionOptions {
+            level_size_multiplier: 10,
+            level0_file_num_compaction_trigger: 4,
+            max_levels: 3,
+            base_level_size_mb: 1,
+        });
+        let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+        // 没有压缩线程时不停顿
+        set_l0_files(&storage, 2);
+        storage.put(b"1", b"1").unwrap();
+        assert_eq!(storage.write_stall_stats(), Default::default());
+
+        storage.write_controller.set_has_compaction_thread();
+        set_l0_files(&storage, 0);
+        storage.put(b"1", b"1").unwrap();
+        assert_eq!(storage.write_stall_stats().slowdowns, 0);
+
+        set_l0_files(&storage, 1);
+        storage.put(b"2", b"2").unwrap();
+        let stats = storage.write_stall_stats();
+        assert_eq!((stats.slowdowns, stats.stops), (1, 0));
+
+        set_l0_files(&storage, 2);
+        let writer = {
+            let storage = storage.clone();
+            std::thread::spawn(move || storage.put(b"3", b"3").unwrap())
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!writer.is_finished());
+        // 刷新线程赶上来之后唤醒写入者
+        set_l0_files(&storage, 0);
+        storage.write_controller.wake_stalled_writers();
+        writer.join().unwrap();
+        let stats = storage.write_stall_stats();
+        assert_eq!((stats.slowdowns, stats.stops), (1, 1));
+        assert!(stats.stall_micros >= 50_000);
+        assert_eq!(&storage.get(b"3").unwrap().unwrap()[..], b"3");
+
+        // 停写期间关闭，写入者返回错误，没有写进memtable
+        set_l0_files(&storage, 2);
+        let writer = {
+            let storage = storage.clone();
+            std::thread::spawn(move || storage.put(b"4", b"4"))
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!writer.is_finished());
+        storage.closed.store(true, Ordering::SeqCst);
+        storage.write_controller.wake_stalled_writers();
+        assert!(writer.join().unwrap().is_err());
+        assert!(storage.state.read().memtable.get(b"4").is_none());
+    }
+
+    #[test]
+    fn test_no_write_stall_without_compaction() {
+        let dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.level0_stop_writes_trigger = Some(1);
+        let storage = LsmStorageInner::open(&dir, options).unwrap();
+        storage.write_controller.set_has_compaction_thread();
+        // 不压缩的列族 L0 不会变少，不能一直阻塞
+        set_l0_files(&storage, 2);
+        storage.put(b"1", b"1").unwrap();
+        assert_eq!(storage.write_stall_stats().stops, 0);
+    }
+}